@@ -2,159 +2,167 @@
 // Logic for proposing new blocks in the VENIA blockchain
 
 use crate::{
-    stake_manager::StakeManager,
     transaction_verifier::TransactionVerifier,
     consensus_state::ConsensusState,
-    utilities::{time_utils, crypto_utils},
+    pos_algorithm::Commit,
+    validator_set::ValidatorSet,
+    utilities::{time_utils, crypto_utils::{self, Hash, VrfProof}},
 };
 
 use blockchain_types::{
     Block, Transaction, Validator, BlockHeader,
 };
 
+/// Builds blocks from pending transactions and evidence.
+/// It holds no chain state of its own: every block is built from the consensus state and
+/// validator set of the engine proposing it, as of the last finalized block.
 pub struct BlockProposal {
-    stake_manager: StakeManager,
     transaction_verifier: TransactionVerifier,
-    consensus_state: ConsensusState,
 }
 
 impl BlockProposal {
     /// Creates a new instance of the BlockProposal module.
-    pub fn new(stake_manager: StakeManager, transaction_verifier: TransactionVerifier, consensus_state: ConsensusState) -> Self {
-        BlockProposal {
-            stake_manager,
-            transaction_verifier,
-            consensus_state,
-        }
+    pub fn new(transaction_verifier: TransactionVerifier) -> Self {
+        BlockProposal { transaction_verifier }
     }
 
     /// Main function to propose a block. It orchestrates the block creation process.
-    pub fn propose_block(&self, transactions: Vec<Transaction>, validator: &Validator) -> Block {
-        let mut block = self.prepare_empty_block(validator);
+    /// The block extends the last finalized block of `consensus_state` and carries the commit that
+    /// finalized it, from which validator liveness is recorded. `leader_proof` is the
+    /// proposer's winning VRF ticket when proposers are elected by lottery.
+    /// Blocks carry no reward: the block provision is minted and distributed by stake when the
+    /// block is finalized.
+    pub fn propose_block(
+        &self,
+        consensus_state: &ConsensusState,
+        validator_set: &ValidatorSet,
+        transactions: Vec<Transaction>,
+        validator: &Validator,
+        leader_proof: Option<VrfProof>,
+    ) -> Block {
+        self.propose_block_on(consensus_state.get_last_commit(), consensus_state, validator_set, transactions, validator, leader_proof)
+    }
+
+    /// Proposes a block extending the block certified by `last_commit` rather than the last
+    /// finalized block, for engines that build on blocks which are certified but not yet final.
+    /// The state root and evidence are still taken from the last finalized state. Without a
+    /// commit, the block extends the last finalized block, which is then genesis.
+    pub fn propose_block_on(
+        &self,
+        last_commit: Option<&Commit>,
+        consensus_state: &ConsensusState,
+        validator_set: &ValidatorSet,
+        transactions: Vec<Transaction>,
+        validator: &Validator,
+        leader_proof: Option<VrfProof>,
+    ) -> Block {
+        let previous_hash = last_commit.map_or_else(|| consensus_state.get_latest_block_hash(), |commit| commit.block_hash);
+        let parent_timestamp = consensus_state.block_timestamp(&previous_hash);
+        let mut block = self.prepare_empty_block(validator, previous_hash, parent_timestamp, leader_proof);
+        if let Some(commit) = last_commit {
+            block.set_last_commit(commit.clone());
+        }
         for transaction in transactions {
-            if self.transaction_verifier.verify(&transaction) {
+            if self.transaction_verifier.verify_transaction(&transaction).is_ok() {
                 block.add_transaction(transaction);
             }
         }
-        self.finalize_block(&mut block);
+        for evidence in consensus_state.pending_evidence() {
+            block.add_evidence(evidence.clone());
+        }
+        self.finalize_block(&mut block, consensus_state, validator_set);
         block
     }
 
     /// Prepares an empty block with the current validator's signature and other metadata.
-    fn prepare_empty_block(&self, validator: &Validator) -> Block {
-        let timestamp = time_utils::current_timestamp();
-        let previous_hash = self.consensus_state.get_latest_block_hash();
-        let block_header = BlockHeader::new(timestamp, previous_hash, validator.public_key.clone());
-        
+    /// The block is timestamped with the current time, or just after its parent if the parent
+    /// is not older, since block times must increase. The header carries `leader_proof`, if any.
+    fn prepare_empty_block(
+        &self,
+        validator: &Validator,
+        previous_hash: Hash,
+        parent_timestamp: Option<u64>,
+        leader_proof: Option<VrfProof>,
+    ) -> Block {
+        let now = time_utils::current_timestamp();
+        let timestamp = parent_timestamp.map_or(now, |parent| now.max(parent + 1));
+        let mut block_header = BlockHeader::new(timestamp, previous_hash, validator.public_key.clone());
+        if let Some(proof) = leader_proof {
+            block_header.set_vrf_proof(proof);
+        }
+
         Block::new(block_header, Vec::new())
     }
 
     /// Finalizes the block by computing the consensus-related metadata.
-    fn finalize_block(&self, block: &mut Block) {
-        let state_root_hash = self.consensus_state.compute_state_root();
+    /// The hash is computed last, so it covers the state root.
+    fn finalize_block(&self, block: &mut Block, consensus_state: &ConsensusState, validator_set: &ValidatorSet) {
+        block.set_state_root(consensus_state.compute_state_root(validator_set));
+
         let block_hash = crypto_utils::hash_block(block);
-        block.set_state_root(state_root_hash);
         block.set_hash(block_hash);
-
-        // Calculate and assign the block's proof-of-stake-related attributes
-        let stake_snapshot = self.stake_manager.get_current_stake_snapshot();
-        let validator_reward = self.calculate_validator_reward(&stake_snapshot, block);
-        block.set_validator_reward(validator_reward);
-    }
-
-     /// Calculates the reward for the validator proposing the block.
-    fn calculate_validator_reward(&self, stake_snapshot: &StakeSnapshot, block: &Block) -> u64 {
-        // Base reward determined by the network's predefined parameters
-        let base_reward = self.consensus_state.get_base_reward();
-    
-        // Additional reward based on the number of transactions in the block
-        let tx_reward = self.calculate_transaction_reward(block.transactions.len());
-    
-        // The validator's stake proportion in the network
-        let validator_stake_proportion = self.calculate_stake_proportion(stake_snapshot, &block.validator_public_key);
-    
-        // Total reward considering base, transaction, and stake proportion
-        base_reward + tx_reward + (validator_stake_proportion * base_reward)
     }
-    
-    /// Calculates additional rewards based on the number of transactions in the block.
-    fn calculate_transaction_reward(&self, transaction_count: usize) -> u64 {
-        // Define a reward rate per transaction
-        let reward_per_transaction = self.consensus_state.get_reward_per_transaction();
-    
-        // Calculate the total transaction reward
-        (transaction_count as u64) * reward_per_transaction
-    }
-    
-    /// Calculates the proportion of stake the validator holds in the network.
-    fn calculate_stake_proportion(&self, stake_snapshot: &StakeSnapshot, validator_public_key: &PublicKey) -> u64 {
-        let validator_stake = stake_snapshot.get_stake(validator_public_key);
-        let total_network_stake = stake_snapshot.get_total_stake();
-    
-        // Calculate the stake proportion (as a percentage of the total network stake)
-        (validator_stake * 100) / total_network_stake
-    }
-
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use blockchain_types::{Transaction, PublicKey, BlockHeader};
-
-    /// Mocks necessary data for testing purposes
-    fn setup() -> (BlockProposal, Vec<Transaction>, Validator) {
-        let stake_manager = StakeManager::new(); // Assuming a new method for instantiation
-        let transaction_verifier = TransactionVerifier::new(); // Assuming a new method
-        let consensus_state = ConsensusState::new(); // Assuming a new method
-
-        let block_proposal = BlockProposal::new(stake_manager, transaction_verifier, consensus_state);
-
-        let transactions = vec![
-            Transaction::new(/* transaction data */),
-            // ... more transactions
-        ];
+    use crate::stake_manager::StakeManager;
+    use crate::state::BlockchainState;
+    use crate::validator_set::Commission;
 
-        let validator = Validator {
-            public_key: PublicKey::new(/* public key data */),
-            // ... other validator properties
-        };
+    fn block_proposal() -> BlockProposal {
+        BlockProposal::new(TransactionVerifier::new(BlockchainState::default()))
+    }
 
-        (block_proposal, transactions, validator)
+    fn validator_set() -> ValidatorSet {
+        let mut set = ValidatorSet::new(StakeManager::new());
+        set.add_validator("a".to_string(), 10, vec![1; 32], Commission::default()).unwrap();
+        set.update_validator_status("a", true);
+        set
     }
 
     #[test]
     fn test_prepare_empty_block() {
-        let (block_proposal, _, validator) = setup();
-        let block = block_proposal.prepare_empty_block(&validator);
+        let validator = Validator::new(vec![1; 32]);
+        let block = block_proposal().prepare_empty_block(&validator, [7; 32], None, None);
 
         assert_eq!(block.transactions.len(), 0, "Block should have no transactions.");
-        assert_eq!(block.header.validator_public_key, validator.public_key, "Block header should have the validator's public key.");
-        // ... more assertions
+        assert_eq!(block.header.previous_hash, [7; 32]);
+
+        let parent_timestamp = time_utils::current_timestamp() + 10;
+        let next = block_proposal().prepare_empty_block(&validator, [7; 32], Some(parent_timestamp), None);
+        assert_eq!(next.header.timestamp, parent_timestamp + 1);
     }
 
     #[test]
-    fn test_propose_block_with_valid_transactions() {
-        let (block_proposal, transactions, validator) = setup();
-        let block = block_proposal.propose_block(transactions, &validator);
-
-        // Assuming all transactions are valid
-        assert!(!block.transactions.is_empty(), "Block should contain transactions.");
-        // ... more assertions
+    fn test_proposed_block_commits_to_the_given_state() {
+        let mut validator_set = validator_set();
+        let consensus_state = ConsensusState::new(&validator_set);
+        let validator = Validator::new(vec![1; 32]);
+
+        let block = block_proposal().propose_block(&consensus_state, &validator_set, Vec::new(), &validator, None);
+        assert_eq!(block.header.previous_hash, consensus_state.get_latest_block_hash());
+        assert_eq!(block.header.state_root, consensus_state.compute_state_root(&validator_set));
+        assert!(block.evidence.is_empty());
+        assert_eq!(block.last_commit, None);
+
+        // The root is read from the validator set passed in, not from a copy taken earlier.
+        validator_set.add_validator("b".to_string(), 5, vec![2; 32], Commission::default()).unwrap();
+        let next = block_proposal().propose_block(&consensus_state, &validator_set, Vec::new(), &validator, None);
+        assert_ne!(next.header.state_root, block.header.state_root);
+        assert_eq!(next.header.state_root, consensus_state.compute_state_root(&validator_set));
     }
 
     #[test]
-    fn test_calculate_validator_reward() {
-        let (block_proposal, _, _) = setup();
-        let stake_snapshot = StakeSnapshot::new(/* snapshot data */);
-        let block = Block::new(BlockHeader::new(/* header data */), vec![]);
-
-        let reward = block_proposal.calculate_validator_reward(&stake_snapshot, &block);
-        
-        assert!(reward > 0, "Validator should receive a reward.");
-        // ... more assertions based on the expected reward calculation logic
+    fn test_propose_block_on_extends_the_given_block() {
+        let validator_set = validator_set();
+        let consensus_state = ConsensusState::new(&validator_set);
+        let validator = Validator::new(vec![1; 32]);
+
+        let commit = Commit::unsigned(1, 0, [3; 32]);
+        let block = block_proposal().propose_block_on(Some(&commit), &consensus_state, &validator_set, Vec::new(), &validator, None);
+        assert_eq!(block.header.previous_hash, [3; 32]);
+        assert_eq!(block.last_commit, Some(commit));
     }
-
-    // Additional tests can be added to cover other aspects of the BlockProposal module
 }
-
@@ -0,0 +1,222 @@
+// consensus_engine.rs
+// Interface shared by the consensus engines, and the configuration selecting one.
+
+use std::collections::HashSet;
+use big_decimal::BigDecimal;
+use serde::{Serialize, Deserialize};
+use blockchain_types::{Block, Transaction};
+
+use crate::block_proposal::BlockProposal;
+use crate::consensus_state::ConsensusState;
+use crate::hotstuff::HotStuffEngine;
+use crate::network_communication::NetworkCommunication;
+use crate::pos_algorithm::{Commit, ConsensusMessage, Height, PosAlgorithm, ValidatorIdentity};
+use crate::staking_transaction::{self, Payout, StakingTransaction};
+use crate::transaction_verifier::TransactionVerifier;
+use crate::utilities::crypto_utils::{self, Hash};
+use crate::utilities::time_utils;
+use crate::validator_set::{Validator, ValidatorSet};
+
+/// How far, in seconds, a block's timestamp may be ahead of the local clock.
+pub const MAX_BLOCK_TIME_DRIFT: u64 = 30;
+
+/// A consensus protocol deciding the sequence of finalized blocks.
+pub trait ConsensusEngine {
+    /// Runs the protocol until the next block is finalized.
+    fn run_consensus_round(&mut self);
+
+    /// Handles a consensus message received from the network.
+    /// Messages of other engines are ignored.
+    fn handle_message(&mut self, message: ConsensusMessage);
+
+    /// Returns the state as of the last finalized block.
+    fn consensus_state(&self) -> &ConsensusState;
+
+    /// Returns the validator set as of the last finalized block.
+    fn validator_set(&self) -> &ValidatorSet;
+}
+
+/// Consensus protocol a node runs. Every validator of a chain must run the same one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineKind {
+    /// Round-based BFT where each block is final once committed, see `PosAlgorithm`.
+    Tendermint,
+    /// Chained HotStuff, whose messages grow linearly with the validator count,
+    /// see `HotStuffEngine`.
+    HotStuff,
+    /// Chained HotStuff where validators also attest to each committed checkpoint, for large
+    /// validator sets: a finalized checkpoint is backed by two consecutive rounds of attestations
+    /// and can be followed checkpoint by checkpoint, see `finality_gadget`.
+    CheckpointedHotStuff,
+}
+
+impl Default for EngineKind {
+    fn default() -> Self {
+        EngineKind::Tendermint
+    }
+}
+
+/// Components an engine is built from.
+/// The validator set owns the stake manager; it is the only copy of the staking state.
+pub struct EngineComponents {
+    pub validator_set: ValidatorSet,
+    pub consensus_state: ConsensusState,
+    pub network_communication: NetworkCommunication,
+    pub transaction_verifier: TransactionVerifier,
+    pub block_proposal: BlockProposal,
+    pub identity: ValidatorIdentity,
+}
+
+impl EngineKind {
+    /// Builds the engine of this kind.
+    pub fn build(self, components: EngineComponents) -> Box<dyn ConsensusEngine> {
+        match self {
+            EngineKind::Tendermint => Box::new(PosAlgorithm::new(
+                components.validator_set,
+                components.consensus_state,
+                components.network_communication,
+                components.transaction_verifier,
+                components.block_proposal,
+                components.identity,
+            )),
+            EngineKind::HotStuff | EngineKind::CheckpointedHotStuff => {
+                let mut engine = HotStuffEngine::new(
+                    components.validator_set,
+                    components.consensus_state,
+                    components.network_communication,
+                    components.transaction_verifier,
+                    components.block_proposal,
+                    components.identity,
+                );
+                if self == EngineKind::CheckpointedHotStuff {
+                    engine.enable_checkpoint_attestations();
+                }
+                Box::new(engine)
+            }
+        }
+    }
+}
+
+/// Returns true if a block timestamp is later than its parent's, when the parent has one, and at
+/// most `MAX_BLOCK_TIME_DRIFT` ahead of local time. Block time releases unbonding entries in
+/// `UnbondingPeriod::Seconds` mode, so a proposer must not be able to move it ahead.
+pub(crate) fn valid_block_time(timestamp: u64, parent_timestamp: Option<u64>) -> bool {
+    parent_timestamp.map_or(true, |parent| timestamp > parent)
+        && timestamp <= time_utils::current_timestamp() + MAX_BLOCK_TIME_DRIFT
+}
+
+/// Applies a block finalized by `commit`: records who signed its parent, commits it, executes its staking
+/// transactions, mints and distributes the block reward, runs the validator set and governance
+/// end-block hooks and starts the next epoch when the block ends one. Returns true if the active
+/// set for the next height differs from the one that finalized the block, either because the
+/// block ended an epoch or because a validator was jailed or unjailed.
+pub(crate) fn apply_finalized_block(
+    validator_set: &mut ValidatorSet,
+    consensus_state: &mut ConsensusState,
+    block: Block,
+    commit: Commit,
+) -> bool {
+    let height = commit.height;
+    let block_hash = commit.block_hash;
+    let block_time = block.header.timestamp;
+    // Liveness is taken from the parent's commit included in the block, which every node
+    // finalizes identically, rather than from the commit this node happened to collect, and
+    // checked against the set that signed the parent. The genesis certificate HotStuff starts
+    // from is signed by no one.
+    let parent_signing = block
+        .last_commit
+        .as_ref()
+        .filter(|last_commit| last_commit.height > 0)
+        .map(|last_commit| {
+            let parent_validators = consensus_state.last_validators().to_vec();
+            let signers: HashSet<String> = last_commit
+                .signer_ids(&parent_validators)
+                .into_iter()
+                .map(str::to_string)
+                .collect();
+            (last_commit.height, parent_validators, signers)
+        });
+    let ends_epoch = consensus_state.is_epoch_end(height);
+    let transactions = block.transactions.clone();
+    // The block records the set that finalized it before any of its jails take effect.
+    consensus_state.commit_block(block, commit, validator_set);
+    if let Some((parent_height, parent_validators, signers)) = parent_signing {
+        validator_set.record_block_signatures(parent_height, &parent_validators, &signers);
+    }
+    execute_staking_transactions(validator_set, consensus_state, &transactions, height);
+    update_stakes(validator_set, consensus_state, height, block_time);
+    consensus_state.end_block_governance(height, validator_set);
+    if ends_epoch {
+        transition_epoch(validator_set, consensus_state, height + 1, block_hash);
+        return true;
+    }
+    let set_changed =
+        power_table(validator_set.get_active_validators()) != power_table(consensus_state.last_validators());
+    if set_changed {
+        // Proposer priorities are only carried over while the active set is unchanged.
+        validator_set.reset_proposer_priorities(height + 1);
+        consensus_state.record_active_set_change(height + 1);
+    }
+    set_changed
+}
+
+/// Returns the IDs and voting powers of `validators`, sorted by ID.
+fn power_table<'a>(validators: impl IntoIterator<Item = &'a Validator>) -> Vec<(String, u64)> {
+    let mut table: Vec<(String, u64)> = validators.into_iter().map(|v| (v.id.clone(), v.stake)).collect();
+    table.sort();
+    table
+}
+
+/// Executes the staking operations carried by a block's transactions, in block order.
+/// A failed operation has no effect; its transaction stays in the block, as with any transaction
+/// whose execution fails.
+fn execute_staking_transactions(
+    validator_set: &mut ValidatorSet,
+    consensus_state: &mut ConsensusState,
+    transactions: &[Transaction],
+    height: Height,
+) {
+    for transaction in transactions {
+        let operation = match StakingTransaction::decode(&transaction.payload) {
+            Some(operation) => operation,
+            None => continue,
+        };
+        let sender = staking_transaction::account_id(&transaction.sender);
+        if let Ok(Some(payout)) = operation.execute(&sender, validator_set, height) {
+            consensus_state.add_payout(payout);
+        }
+    }
+}
+
+/// Mints the block reward and distributes it to the validators' delegators, then runs the
+/// validator set end-block hook, which completes validator unbonding and releases matured
+/// unbonding entries. Released balances are recorded as payouts to their delegators.
+/// The rounding remainder of the reward, or the whole reward if no validator can receive it, is
+/// burned, so minting adds exactly what was distributed to the supply.
+fn update_stakes(validator_set: &mut ValidatorSet, consensus_state: &mut ConsensusState, height: Height, block_time: u64) {
+    let bonded = validator_set.stake_manager().get_total_staked().clone();
+    let provision = consensus_state.mint_block_provision(&bonded);
+    let undistributed = validator_set.distribute_rewards(&provision).unwrap_or(provision);
+    consensus_state.burn_undistributed_provision(&undistributed);
+
+    for entry in validator_set.end_block(height, block_time) {
+        // Entries slashed to nothing release nothing.
+        if entry.balance > BigDecimal::from(0) {
+            consensus_state.add_payout(Payout {
+                account: entry.delegator,
+                amount: entry.balance,
+            });
+        }
+    }
+}
+
+/// Starts the next epoch at `start_height`.
+/// Validator joins, exits and stake changes take effect here and nowhere else, jails aside, and
+/// the lottery seed is refreshed from the last block of the finished epoch.
+fn transition_epoch(validator_set: &mut ValidatorSet, consensus_state: &mut ConsensusState, start_height: Height, last_block_hash: Hash) {
+    let epoch = consensus_state.current_epoch() + 1;
+    let update = validator_set.rotate_validators(epoch, start_height);
+    let seed = crypto_utils::hash_bytes(&[validator_set.epoch_seed(), last_block_hash].concat());
+    validator_set.set_epoch_seed(seed);
+    consensus_state.begin_epoch(update);
+}
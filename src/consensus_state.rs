@@ -1,88 +1,751 @@
 // consensus_state.rs
 // Manages the state of the consensus process in the VENIA blockchain.
 
-use std::collections::HashMap;
-use crate::stake_manager::StakeManager;
-use crate::validator_set::ValidatorSet;
-use crate::block_proposal::BlockProposal;
+use std::collections::{BTreeMap, HashSet};
+use std::str::FromStr;
+use big_decimal::BigDecimal;
+use crate::validator_set::{Validator, ValidatorSet, ValidatorSetUpdate};
+use crate::evidence::{Evidence, EvidenceError};
+use crate::finality_gadget::{Attestation, Checkpoint, FinalityError, FinalityGadget};
+use crate::governance::Governance;
+use crate::fork_choice::{BlockTree, ForkChoiceError, ReorgEvent};
+use crate::monetary_policy::{MintError, MintParams, Minter};
+use crate::pos_algorithm::{Commit, Height};
+use crate::staking_transaction::Payout;
+use crate::storage::{self, ConsensusStore, StorageError};
+use crate::utilities::crypto_utils::{self, Hash};
+use crate::utilities::time_utils;
+use crate::utilities::merkle::{MerkleProof, SparseMerkleTree};
+use blockchain_types::Block;
 use serde::{Serialize, Deserialize};
 
+/// Number of blocks per epoch unless configured otherwise.
+const DEFAULT_EPOCH_LENGTH: u64 = 1000;
+
+/// Fraction of stake slashed for double-signing unless configured otherwise.
+const DEFAULT_SLASH_FRACTION_DOUBLE_SIGN: &str = "0.05";
+
+/// Number of blocks after an offence during which evidence of it is accepted.
+const DEFAULT_MAX_EVIDENCE_AGE: u64 = 100_000;
+
+/// Store key of the persisted consensus state.
+const CONSENSUS_STATE_KEY: &str = "state/consensus";
+
+/// Format version of the persisted consensus state. Records of other versions are not restored.
+const CONSENSUS_STATE_VERSION: u32 = 1;
+
+/// Consensus state as of one finalized height, written to the store as a single record so that
+/// it is replaced atomically. Blocks, commits and other per-height data are stored under their
+/// heights before it. `V` is the validator set, borrowed when writing and owned when reading.
+#[derive(Serialize, Deserialize)]
+struct PersistedConsensusState<V = ValidatorSet> {
+    version: u32,
+    height: Height,
+    latest_block_hash: Hash,
+    epoch: u64,
+    epoch_start_height: Height,
+    epoch_length: u64,
+    next_epoch_length: u64,
+    validator_set_updates: Vec<ValidatorSetUpdate>,
+    pending_evidence: Vec<Evidence>,
+    processed_evidence: HashSet<Hash>,
+    slash_fraction_double_sign: BigDecimal,
+    max_evidence_age: u64,
+    minter: Minter,
+    #[serde(default)]
+    finality_gadget: FinalityGadget,
+    #[serde(default)]
+    checkpoints: BTreeMap<Height, Hash>,
+    #[serde(default)]
+    payouts: Vec<Payout>,
+    #[serde(default)]
+    last_validators: Vec<Validator>,
+    #[serde(default)]
+    active_set_changes: Vec<Height>,
+    governance: Governance,
+    /// The validator set and its staking state.
+    validator_set: V,
+}
+
 /// Represents the current state of the consensus mechanism.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConsensusState {
-    /// The current set of validators.
-    validators: ValidatorSet,
+    /// Timestamp of the last state update.
+    last_updated: u64,
 
-    /// Current stake information.
-    stake_manager: StakeManager,
+    /// Height of the last finalized block.
+    height: Height,
 
-    /// The current highest block that has reached consensus.
-    current_block: BlockProposal,
+    /// Hash of the last finalized block.
+    latest_block_hash: Hash,
 
-    /// Mapping of validator IDs to their last agreed block.
-    validator_block_map: HashMap<String, BlockProposal>,
+    /// The last finalized block, together with the commit that finalized it.
+    last_committed_block: Option<Block>,
+    last_commit: Option<Commit>,
 
-    /// Timestamp of the last state update.
-    last_updated: u64,
+    /// Active validators that finalized the last block, against which the next block's last
+    /// commit is verified.
+    last_validators: Vec<Validator>,
+
+    /// The current epoch and its first height.
+    epoch: u64,
+    epoch_start_height: Height,
+
+    /// Length in blocks of the current epoch, and of the epochs after it.
+    epoch_length: u64,
+    next_epoch_length: u64,
+
+    /// Validator set changes applied at each epoch boundary so far.
+    validator_set_updates: Vec<ValidatorSetUpdate>,
+
+    /// First heights of the active sets that changed within an epoch, by jailing or unjailing.
+    active_set_changes: Vec<Height>,
+
+    /// Verified evidence waiting to be included in a block.
+    pending_evidence: Vec<Evidence>,
+
+    /// Offences that have already been punished, by evidence hash, see `Evidence::hash`.
+    processed_evidence: HashSet<Hash>,
+
+    /// Fraction of stake slashed for double-signing.
+    slash_fraction_double_sign: BigDecimal,
+
+    /// Number of blocks after an offence during which evidence of it is accepted.
+    max_evidence_age: u64,
+
+    /// Inflation schedule that mints the block rewards.
+    minter: Minter,
+
+    /// Checkpoint finality over the committed chain, fed by `process_attestation`. Validators
+    /// attest to committed checkpoints in the `EngineKind::CheckpointedHotStuff` mode.
+    finality_gadget: FinalityGadget,
+
+    /// Hashes of the committed checkpoint blocks from the finalized checkpoint on, by height,
+    /// against which attestations are checked.
+    checkpoints: BTreeMap<Height, Hash>,
+
+    /// Attestations to the next checkpoint, received before this node committed it. They are
+    /// counted once it is.
+    #[serde(skip)]
+    held_attestations: Vec<Attestation>,
+
+    /// Governance proposals, whose approved changes are applied at the end of a block.
+    governance: Governance,
+
+    /// Tokens owed to accounts by finalized blocks, not yet taken by `take_payouts`.
+    payouts: Vec<Payout>,
+
+    /// Blocks seen above the last finalized block, from which the fork-choice head is chosen.
+    /// Rebuilt from the last finalized block on restart.
+    #[serde(skip)]
+    block_tree: BlockTree<Block>,
+
+    /// Reorgs of the fork-choice head not yet taken by `take_reorg_events`.
+    #[serde(skip)]
+    reorg_events: Vec<ReorgEvent>,
 }
 
 impl ConsensusState {
-    /// Initializes a new ConsensusState with the given components.
-    pub fn new(validators: ValidatorSet, stake_manager: StakeManager, initial_block: BlockProposal) -> Self {
+    /// Initializes a new ConsensusState at genesis.
+    /// The genesis token supply is taken to be the stake bonded in `validator_set`.
+    pub fn new(validator_set: &ValidatorSet) -> Self {
+        let minter = Minter::new(MintParams::default(), validator_set.stake_manager().get_total_staked().clone())
+            .expect("default mint parameters are valid");
         ConsensusState {
-            validators,
-            stake_manager,
-            current_block: initial_block,
-            validator_block_map: HashMap::new(),
-            last_updated: get_current_timestamp(),
+            last_updated: time_utils::current_timestamp(),
+            height: 0,
+            latest_block_hash: [0; 32],
+            last_committed_block: None,
+            last_commit: None,
+            last_validators: Vec::new(),
+            epoch: 0,
+            epoch_start_height: 1,
+            epoch_length: DEFAULT_EPOCH_LENGTH,
+            next_epoch_length: DEFAULT_EPOCH_LENGTH,
+            validator_set_updates: Vec::new(),
+            active_set_changes: Vec::new(),
+            pending_evidence: Vec::new(),
+            processed_evidence: HashSet::new(),
+            slash_fraction_double_sign: BigDecimal::from_str(DEFAULT_SLASH_FRACTION_DOUBLE_SIGN)
+                .expect("default slash fraction is a valid decimal"),
+            max_evidence_age: DEFAULT_MAX_EVIDENCE_AGE,
+            minter,
+            finality_gadget: FinalityGadget::default(),
+            checkpoints: BTreeMap::from([(0, [0; 32])]),
+            held_attestations: Vec::new(),
+            governance: Governance::new(),
+            payouts: Vec::new(),
+            block_tree: BlockTree::new([0; 32], 0),
+            reorg_events: Vec::new(),
+        }
+    }
+
+    /// Returns the last finalized block, if any block has been finalized.
+    pub fn get_last_committed_block(&self) -> Option<&Block> {
+        self.last_committed_block.as_ref()
+    }
+
+    /// Records a block finalized by `commit` and advances to its height.
+    /// The commit is expected to have been checked for a +2/3 precommit quorum by the caller.
+    /// Evidence included in the block is applied, slashing the offenders in `validator_set`, and
+    /// attestations held for the block, if it is a checkpoint, are counted.
+    pub fn commit_block(&mut self, block: Block, commit: Commit, validator_set: &mut ValidatorSet) {
+        // Taken before the block's evidence is applied: the commit was signed by the set as it was.
+        self.last_validators = validator_set.get_active_validators().into_iter().cloned().collect();
+        for evidence in &block.evidence {
+            // Blocks are only committed after `check_evidence` accepted all of their evidence.
+            let _ = self.apply_evidence(evidence, validator_set);
+        }
+        self.finalize_in_block_tree(&block, &commit);
+        self.height = commit.height;
+        self.latest_block_hash = commit.block_hash;
+        self.last_committed_block = Some(block);
+        self.last_commit = Some(commit);
+        self.last_updated = time_utils::current_timestamp();
+        if self.finality_gadget.checkpoint_epoch(self.height).is_some() {
+            self.checkpoints.insert(self.height, self.latest_block_hash);
+            // Attestations to another block at this height are rejected, and those to a later
+            // checkpoint held again.
+            for attestation in std::mem::take(&mut self.held_attestations) {
+                let _ = self.process_attestation(attestation, validator_set);
+            }
+        }
+    }
+
+    /// Writes the last finalized block and its commit, then the consensus state together with
+    /// `validator_set` as one record, to `store`. A crash part-way leaves the previously persisted
+    /// state intact: the block and commit are keyed by height and rewritten identically when the
+    /// height is finalized again after a restart.
+    pub fn persist(&self, store: &mut dyn ConsensusStore, validator_set: &ValidatorSet) -> Result<(), StorageError> {
+        if let Some(block) = &self.last_committed_block {
+            store.put_block(self.height, block)?;
         }
+        if let Some(commit) = &self.last_commit {
+            store.put_commit(self.height, commit)?;
+        }
+        let record = PersistedConsensusState {
+            version: CONSENSUS_STATE_VERSION,
+            height: self.height,
+            latest_block_hash: self.latest_block_hash,
+            epoch: self.epoch,
+            epoch_start_height: self.epoch_start_height,
+            epoch_length: self.epoch_length,
+            next_epoch_length: self.next_epoch_length,
+            validator_set_updates: self.validator_set_updates.clone(),
+            pending_evidence: self.pending_evidence.clone(),
+            processed_evidence: self.processed_evidence.clone(),
+            slash_fraction_double_sign: self.slash_fraction_double_sign.clone(),
+            max_evidence_age: self.max_evidence_age,
+            minter: self.minter.clone(),
+            finality_gadget: self.finality_gadget.clone(),
+            checkpoints: self.checkpoints.clone(),
+            payouts: self.payouts.clone(),
+            last_validators: self.last_validators.clone(),
+            active_set_changes: self.active_set_changes.clone(),
+            governance: self.governance.clone(),
+            validator_set,
+        };
+        storage::put_json(store, CONSENSUS_STATE_KEY, &record)
+    }
+
+    /// Loads the state last written by `persist` and returns the validator set persisted with it.
+    /// Returns `None`, leaving the state untouched, if the store holds no consensus state.
+    pub fn restore(&mut self, store: &dyn ConsensusStore) -> Result<Option<ValidatorSet>, StorageError> {
+        let record: PersistedConsensusState = match storage::get_json(store, CONSENSUS_STATE_KEY)? {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        if record.version != CONSENSUS_STATE_VERSION {
+            return Err(StorageError::Serialization(format!(
+                "unsupported consensus state version {}",
+                record.version
+            )));
+        }
+        self.last_committed_block = store.get_block(record.height)?;
+        self.last_commit = store.get_commit(record.height)?;
+        self.height = record.height;
+        self.latest_block_hash = record.latest_block_hash;
+        self.epoch = record.epoch;
+        self.epoch_start_height = record.epoch_start_height;
+        self.epoch_length = record.epoch_length;
+        self.next_epoch_length = record.next_epoch_length;
+        self.validator_set_updates = record.validator_set_updates;
+        self.pending_evidence = record.pending_evidence;
+        self.processed_evidence = record.processed_evidence;
+        self.slash_fraction_double_sign = record.slash_fraction_double_sign;
+        self.max_evidence_age = record.max_evidence_age;
+        self.minter = record.minter;
+        self.finality_gadget = record.finality_gadget;
+        self.checkpoints = record.checkpoints;
+        self.held_attestations.clear();
+        self.payouts = record.payouts;
+        self.last_validators = record.last_validators;
+        self.active_set_changes = record.active_set_changes;
+        self.governance = record.governance;
+        self.block_tree = BlockTree::new(self.latest_block_hash, self.height);
+        Ok(Some(record.validator_set))
+    }
+
+    /// Adds a block seen on the network to the block tree.
+    /// Returns the reorg of the fork-choice head it caused, if any; the event is also kept for
+    /// `take_reorg_events`. Blocks whose parent is unknown or was pruned by finalization are rejected.
+    pub fn add_block(&mut self, block: Block) -> Result<Option<ReorgEvent>, ForkChoiceError> {
+        let hash = crypto_utils::hash_block(&block);
+        let parent = block.header.previous_hash;
+        let reorg = self.block_tree.add_block(hash, parent, block)?;
+        self.record_reorg(&reorg);
+        Ok(reorg)
+    }
+
+    /// Records that a validator with `voting_power` attests to the block `hash` and its ancestors.
+    /// Only a validator's latest attestation counts towards the fork choice.
+    pub fn add_attestation(&mut self, validator_id: &str, hash: Hash, voting_power: u64) -> Result<Option<ReorgEvent>, ForkChoiceError> {
+        let reorg = self.block_tree.add_attestation(validator_id, hash, voting_power)?;
+        self.record_reorg(&reorg);
+        Ok(reorg)
+    }
+
+    /// Returns the hash of the block at the tip of the heaviest attested chain descending from
+    /// the last finalized block.
+    pub fn fork_choice_head(&self) -> Hash {
+        self.block_tree.head()
+    }
+
+    /// Returns a block from the block tree.
+    pub fn get_block(&self, hash: &Hash) -> Option<&Block> {
+        self.block_tree.get(hash)
     }
 
-    /// Updates the state based on a new block proposal.
-    /// This function is called when a new block is proposed by a validator.
-    pub fn update_state_with_block(&mut self, block: BlockProposal) {
-        // Check if the block is valid and can be added to the blockchain
-        if self.is_block_valid(&block) {
-            self.current_block = block.clone();
-            self.validator_block_map.insert(block.proposer_id.clone(), block);
+    /// Returns the timestamp of the last committed block or of a block above it in the block tree.
+    /// Genesis and unknown blocks have none.
+    pub fn block_timestamp(&self, hash: &Hash) -> Option<u64> {
+        match self.get_last_committed_block() {
+            Some(block) if *hash == self.latest_block_hash => Some(block.header.timestamp),
+            _ => self.get_block(hash).map(|block| block.header.timestamp),
+        }
+    }
 
-            // Update the stake manager with any stake changes in the new block
-            self.stake_manager.update_stakes(&block);
+    /// Returns and clears the reorgs of the fork-choice head seen since the last call.
+    pub fn take_reorg_events(&mut self) -> Vec<ReorgEvent> {
+        std::mem::take(&mut self.reorg_events)
+    }
+
+    fn record_reorg(&mut self, reorg: &Option<ReorgEvent>) {
+        if let Some(reorg) = reorg {
+            self.reorg_events.push(reorg.clone());
+        }
+    }
 
-            // Update the timestamp
-            self.last_updated = get_current_timestamp();
+    /// Counts a checkpoint attestation towards finality. The target must be a committed
+    /// checkpoint block; the committed chain being linear, the source is its ancestor if it is a
+    /// committed checkpoint below it. An attestation to the next checkpoint, which this node has
+    /// not committed yet, is held and counted once it is. Returns the checkpoint the attestation
+    /// finalized, if any. An attestation conflicting with an earlier one from the same validator
+    /// is rejected and its evidence submitted for slashing.
+    pub fn process_attestation(&mut self, attestation: Attestation, validator_set: &ValidatorSet) -> Result<Option<Checkpoint>, FinalityError> {
+        let (source, target) = (attestation.source, attestation.target);
+        if target.height > self.height {
+            return self.hold_attestation(attestation, validator_set).map(|_| None);
+        }
+        if self.checkpoints.get(&target.height) != Some(&target.hash) {
+            return Err(FinalityError::UnknownTarget);
+        }
+        let source_is_ancestor = source.height < target.height && self.checkpoints.get(&source.height) == Some(&source.hash);
+        let finalized = match self.finality_gadget.process_attestation(attestation, source_is_ancestor, validator_set) {
+            Err(FinalityError::Conflicting(evidence)) => {
+                let _ = self.submit_evidence(Evidence::ConflictingAttestation((*evidence).clone()), validator_set);
+                return Err(FinalityError::Conflicting(evidence));
+            }
+            result => result?,
+        };
+        if let Some(checkpoint) = finalized {
+            // Attestations with a source before the finalized checkpoint are stale.
+            self.checkpoints.retain(|height, _| *height >= checkpoint.height);
+        }
+        Ok(finalized)
+    }
 
-            // TODO: Add logic for handling block rewards and penalties.
+    /// Holds a signed attestation to the first checkpoint above the last committed block.
+    /// At most two are held per validator, enough to catch a double vote. Attestations to later
+    /// checkpoints are rejected: a node that far behind does not count them.
+    fn hold_attestation(&mut self, attestation: Attestation, validator_set: &ValidatorSet) -> Result<(), FinalityError> {
+        if attestation.target.height > self.height + self.finality_gadget.checkpoint_interval() {
+            return Err(FinalityError::UnknownTarget);
+        }
+        let validator = validator_set
+            .get_validator(&attestation.validator_id)
+            .filter(|_| validator_set.voting_power(&attestation.validator_id) > 0)
+            .ok_or(FinalityError::UnknownValidator)?;
+        if !crypto_utils::verify_signature(&validator.public_key, &attestation.sign_bytes(), &attestation.signature) {
+            return Err(FinalityError::InvalidSignature);
         }
+        let held = self
+            .held_attestations
+            .iter()
+            .filter(|held| held.validator_id == attestation.validator_id)
+            .count();
+        if held < 2 && !self.held_attestations.contains(&attestation) {
+            self.held_attestations.push(attestation);
+        }
+        Ok(())
+    }
+
+    /// Returns the checkpoint finality state.
+    pub fn finality_gadget(&self) -> &FinalityGadget {
+        &self.finality_gadget
+    }
+
+    /// Sets the number of blocks between checkpoints. Meant for genesis, as it restarts
+    /// checkpoint finality from the genesis checkpoint.
+    pub fn set_checkpoint_interval(&mut self, checkpoint_interval: u64) {
+        let genesis = self.finality_gadget.finalized();
+        self.finality_gadget = FinalityGadget::new(checkpoint_interval, genesis);
+        self.checkpoints = BTreeMap::from([(genesis.height, genesis.hash)]);
+        self.held_attestations.clear();
     }
 
-    /// Validates whether the proposed block can be added to the blockchain.
-    fn is_block_valid(&self, block: &BlockProposal) -> bool {
-        // TODO: Implement block validation logic, including signature verification,
-        //       transaction validity, and consensus rules.
+    /// Makes the committed block the finalized root of the block tree, pruning conflicting branches.
+    /// A commit the tree cannot place, such as one for a block whose parent was never seen,
+    /// restarts the tree from the committed block.
+    fn finalize_in_block_tree(&mut self, block: &Block, commit: &Commit) {
+        let hash = commit.block_hash;
+        let placed = self
+            .block_tree
+            .add_block(hash, block.header.previous_hash, block.clone())
+            .and_then(|_| self.block_tree.finalize(hash));
+        match placed {
+            Ok(reorg) => self.record_reorg(&reorg),
+            Err(_) => self.block_tree = BlockTree::new(hash, commit.height),
+        }
+    }
 
-        // Placeholder logic for illustration
-        block.transactions.iter().all(|tx| tx.is_valid())
+    /// Computes the root of the state tree over `validator_set` and the consensus parameters,
+    /// committed to in each block header.
+    pub fn compute_state_root(&self, validator_set: &ValidatorSet) -> Hash {
+        self.state_tree(validator_set).root()
     }
 
-    /// Returns the current highest block in the consensus state.
-    pub fn get_current_block(&self) -> &BlockProposal {
-        &self.current_block
+    /// Returns a proof that `key` holds its current value in the state tree, or that it is absent.
+    /// Keys are listed in `state_tree`.
+    pub fn prove_state(&self, validator_set: &ValidatorSet, key: &str) -> MerkleProof {
+        self.state_tree(validator_set).prove(key.as_bytes())
     }
 
-    // TODO: Implement additional functionalities as needed, such as state recovery,
-    //       fork resolution, and validator set updates.
+    /// Builds the sparse Merkle tree over the staking and validator state of `validator_set`,
+    /// the governance proposals and the governed parameters.
+    /// Keys and values:
+    /// * `validator/{id}` - the validator record as JSON
+    /// * `stake/{validator}` - tokens bonded to the validator
+    /// * `delegation/{delegator}/{validator}` - shares held by the delegator
+    /// * `unbonding/{index}`, `redelegation/{index}` - entries in progress as JSON, in queue order
+    /// * `rewards/current/{validator}` - `{period}:{delegator shares}:{rewards}` of the period in progress
+    /// * `rewards/historical/{validator}/{period}` - `{cumulative ratio}:{reference count}`
+    /// * `rewards/start/{delegator}/{validator}` - `{previous period}:{stake}` of the delegation
+    /// * `rewards/slashes/{validator}` - slash events as JSON
+    /// * `rewards/unclaimed/{delegator}` - rewards settled but not withdrawn
+    /// * `commission/{validator}` - commission earned but not withdrawn
+    /// * `signing/{validator}` - signing window and jail record as JSON
+    /// * `governance/proposal/{id}` - the proposal, its tally and status as JSON
+    /// * `params/mint` - the inflation schedule parameters as JSON
+    /// * `params/slash_fraction_double_sign`, `params/max_evidence_age`, `params/epoch_length`
+    /// * `epoch` - the current epoch and its first height, as `{epoch}:{start_height}`
+    fn state_tree(&self, validator_set: &ValidatorSet) -> SparseMerkleTree {
+        let mut tree = SparseMerkleTree::new();
+        let mut insert = |key: String, value: Vec<u8>| tree.insert(key.as_bytes(), &value);
+
+        for validator in validator_set.get_validators() {
+            let record = serde_json::to_vec(validator).expect("validator is serializable");
+            insert(format!("validator/{}", validator.id), record);
+        }
+        let stake_manager = validator_set.stake_manager();
+        for (validator, stake) in stake_manager.get_current_stake_snapshot().stakes() {
+            insert(format!("stake/{}", validator), stake.to_string().into_bytes());
+        }
+        for ((delegator, validator), shares) in stake_manager.delegations() {
+            insert(format!("delegation/{}/{}", delegator, validator), shares.to_string().into_bytes());
+        }
+        for (index, entry) in stake_manager.get_unbonding_entries().iter().enumerate() {
+            let entry = serde_json::to_vec(entry).expect("unbonding entry is serializable");
+            insert(format!("unbonding/{}", index), entry);
+        }
+        for (index, entry) in stake_manager.get_redelegations().iter().enumerate() {
+            let entry = serde_json::to_vec(entry).expect("redelegation entry is serializable");
+            insert(format!("redelegation/{}", index), entry);
+        }
+        for (key, value) in stake_manager.reward_records() {
+            insert(key, value);
+        }
+        for (validator, commission) in validator_set.accumulated_commissions() {
+            insert(format!("commission/{}", validator), commission.to_string().into_bytes());
+        }
+        for (validator, record) in validator_set.signing_info_records() {
+            insert(format!("signing/{}", validator), record);
+        }
+        for (id, record) in self.governance.proposal_records() {
+            insert(format!("governance/proposal/{}", id), record);
+        }
+        let mint_params = serde_json::to_vec(self.minter.params()).expect("mint parameters are serializable");
+        insert("params/mint".to_string(), mint_params);
+        insert(
+            "params/slash_fraction_double_sign".to_string(),
+            self.slash_fraction_double_sign.to_string().into_bytes(),
+        );
+        insert("params/max_evidence_age".to_string(), self.max_evidence_age.to_string().into_bytes());
+        insert("params/epoch_length".to_string(), self.next_epoch_length.to_string().into_bytes());
+        insert(
+            "epoch".to_string(),
+            format!("{}:{}", self.epoch, self.epoch_start_height).into_bytes(),
+        );
+        tree
+    }
+
+    /// Returns the height of the last finalized block.
+    pub fn height(&self) -> Height {
+        self.height
+    }
+
+    /// Returns the hash of the last finalized block.
+    pub fn get_latest_block_hash(&self) -> Hash {
+        self.latest_block_hash
+    }
+
+    /// Returns the commit that finalized the last block, if any block has been finalized.
+    pub fn get_last_commit(&self) -> Option<&Commit> {
+        self.last_commit.as_ref()
+    }
+
+    /// Returns the active validators that finalized the last block, the set its commit's signer
+    /// bitmap refers to.
+    pub fn last_validators(&self) -> &[Validator] {
+        &self.last_validators
+    }
+
+    /// Returns true if `last_commit`, as carried by a block extending the last finalized block,
+    /// is a commit of that block by the validators that finalized it. The first block carries none.
+    /// It need not be this node's own commit, which may have other signers.
+    pub fn verify_last_commit(&self, last_commit: Option<&Commit>) -> bool {
+        match last_commit {
+            None => self.height == 0,
+            Some(commit) => {
+                commit.height == self.height
+                    && commit.block_hash == self.latest_block_hash
+                    && commit.verify(&self.last_validators).is_ok()
+            }
+        }
+    }
+
+    /// Submits evidence of misbehaviour so it can be included in a block.
+    /// The evidence is verified against `validator_set` now and applied once a block including it
+    /// is committed.
+    pub fn submit_evidence(&mut self, evidence: Evidence, validator_set: &ValidatorSet) -> Result<(), EvidenceError> {
+        self.check_evidence(&evidence, validator_set)?;
+        let hash = evidence.hash();
+        if !self.pending_evidence.iter().any(|pending| pending.hash() == hash) {
+            self.pending_evidence.push(evidence);
+        }
+        Ok(())
+    }
+
+    /// Returns verified evidence waiting to be included in a block.
+    pub fn pending_evidence(&self) -> &[Evidence] {
+        &self.pending_evidence
+    }
+
+    /// Checks that evidence is valid against `validator_set`, recent enough, of an offence not
+    /// already punished and against a validator not already tombstoned.
+    pub fn check_evidence(&self, evidence: &Evidence, validator_set: &ValidatorSet) -> Result<(), EvidenceError> {
+        if self.processed_evidence.contains(&evidence.hash()) {
+            return Err(EvidenceError::AlreadyProcessed);
+        }
+        if validator_set.is_tombstoned(evidence.validator_id()) {
+            return Err(EvidenceError::Tombstoned);
+        }
+        if evidence.height() + self.max_evidence_age < self.height {
+            return Err(EvidenceError::Expired);
+        }
+        evidence.verify(validator_set)
+    }
+
+    /// Sets the fraction of stake slashed for double-signing.
+    pub fn set_slash_fraction_double_sign(&mut self, fraction: BigDecimal) {
+        self.slash_fraction_double_sign = fraction;
+    }
+
+    /// Slashes the offender named in committed evidence, together with its delegators, tombstones
+    /// it and marks the offence as processed. Returns the amount slashed.
+    fn apply_evidence(&mut self, evidence: &Evidence, validator_set: &mut ValidatorSet) -> Result<BigDecimal, EvidenceError> {
+        self.check_evidence(evidence, validator_set)?;
+        let hash = evidence.hash();
+        self.processed_evidence.insert(hash);
+        self.pending_evidence.retain(|pending| pending.hash() != hash);
+        let slashed = validator_set.slash_validator(evidence.validator_id(), &self.slash_fraction_double_sign, evidence.height());
+        validator_set.tombstone(evidence.validator_id());
+        Ok(slashed)
+    }
+
+    /// Mints the reward of the block being finalized given the stake bonded at that point.
+    pub fn mint_block_provision(&mut self, bonded_tokens: &BigDecimal) -> BigDecimal {
+        self.minter.mint_block(bonded_tokens)
+    }
+
+    /// Burns the part of a block provision that was not distributed, so the supply only grows by
+    /// what was paid out.
+    pub fn burn_undistributed_provision(&mut self, amount: &BigDecimal) {
+        self.minter.burn(amount);
+    }
+
+    /// Returns the inflation schedule.
+    pub fn minter(&self) -> &Minter {
+        &self.minter
+    }
+
+    /// Replaces the inflation schedule parameters, e.g. after a governance vote.
+    pub fn set_mint_params(&mut self, params: MintParams) -> Result<(), MintError> {
+        self.minter.set_params(params)
+    }
+
+    /// Records tokens owed to an account by the block being finalized.
+    pub fn add_payout(&mut self, payout: Payout) {
+        self.payouts.push(payout);
+    }
+
+    /// Returns and clears the payouts of the blocks finalized since the last call, oldest first,
+    /// for the account layer to credit. They are persisted with the state until taken.
+    pub fn take_payouts(&mut self) -> Vec<Payout> {
+        std::mem::take(&mut self.payouts)
+    }
+
+    /// Returns the governance proposals.
+    pub fn governance(&self) -> &Governance {
+        &self.governance
+    }
+
+    /// Returns the governance proposals, to submit and vote on them.
+    pub fn governance_mut(&mut self) -> &mut Governance {
+        &mut self.governance
+    }
+
+    /// Closes the proposals whose voting period ended before `height`, tallying their votes by
+    /// voting power in `validator_set`, and applies the approved ones.
+    pub fn end_block_governance(&mut self, height: Height, validator_set: &ValidatorSet) {
+        let mut governance = std::mem::take(&mut self.governance);
+        governance.update_proposal_status(height, self, validator_set);
+        self.governance = governance;
+    }
+
+    /// Returns the current epoch.
+    pub fn current_epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Returns the first height of the current epoch.
+    pub fn epoch_start_height(&self) -> Height {
+        self.epoch_start_height
+    }
+
+    /// Returns the length in blocks of the current epoch.
+    pub fn epoch_length(&self) -> u64 {
+        self.epoch_length
+    }
+
+    /// Sets the epoch length in blocks. The current epoch keeps its length and the new length
+    /// applies from the next epoch onwards, except before the first block is finalized, when the
+    /// first epoch takes it too.
+    pub fn set_epoch_length(&mut self, epoch_length: u64) {
+        self.next_epoch_length = epoch_length.max(1);
+        if self.height == 0 {
+            self.epoch_length = self.next_epoch_length;
+        }
+    }
+
+    /// Returns true if `height` is the last height of the current epoch.
+    pub fn is_epoch_end(&self, height: Height) -> bool {
+        height + 1 == self.epoch_start_height + self.epoch_length
+    }
+
+    /// Starts the epoch described by `update`, recording its validator set changes.
+    pub fn begin_epoch(&mut self, update: ValidatorSetUpdate) {
+        self.epoch = update.epoch;
+        self.epoch_start_height = update.start_height;
+        self.epoch_length = self.next_epoch_length;
+        self.validator_set_updates.push(update);
+    }
+
+    /// Records that the active set changed within the epoch, from `start_height` on.
+    pub fn record_active_set_change(&mut self, start_height: Height) {
+        self.active_set_changes.push(start_height);
+    }
+
+    /// Loads from `store` the active validator set that validated `height`, as recorded at the
+    /// start of its epoch or at its last change since. Quorum certificates for the height are
+    /// verified against it.
+    /// This is a query for light clients and RPC; the engines verify against the set they hold.
+    pub fn validator_set_at(&self, store: &dyn ConsensusStore, height: Height) -> Result<Option<Vec<Validator>>, StorageError> {
+        let epoch_start = self
+            .validator_set_updates
+            .iter()
+            .map(|update| update.start_height)
+            .chain(self.active_set_changes.iter().copied())
+            .filter(|start_height| *start_height <= height)
+            .max()
+            .unwrap_or(1);
+        store.get_validator_set(epoch_start)
+    }
+
+    /// Returns the validator set changes applied at each epoch boundary so far.
+    pub fn validator_set_updates(&self) -> &[ValidatorSetUpdate] {
+        &self.validator_set_updates
+    }
+
+    // TODO: Implement additional functionalities as needed, such as state recovery
+    //       and validator set updates.
 
     // TODO: Integrate network communication mechanisms to propagate state changes
     //       to other nodes in the network.
 }
 
-/// Returns the current timestamp.
-/// This is a utility function for getting the current time in a format suitable for the blockchain.
-fn get_current_timestamp() -> u64 {
-    // TODO: Implement a more robust and accurate time-fetching mechanism, possibly synchronized with network time.
-    use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use crate::stake_manager::{StakeManager, UnbondingPeriod};
+    use crate::validator_set::Commission;
+
+    #[test]
+    fn test_state_root_covers_rewards_unbonding_commission_and_signing() {
+        let mut validator_set = ValidatorSet::new(StakeManager::new());
+        let rate = |r: &str| BigDecimal::from_str(r).unwrap();
+        let commission = Commission::new(rate("0.1"), rate("0.2"), rate("0.01")).unwrap();
+        validator_set.add_validator("a".to_string(), 10, Vec::new(), commission).unwrap();
+        validator_set.update_validator_status("a", true);
+        validator_set.stake_manager_mut().update_unbonding_period(UnbondingPeriod::Blocks(10));
+        let state = ConsensusState::new(&validator_set);
+        let mut root = state.compute_state_root(&validator_set);
+        let mut assert_root_changed = |validator_set: &ValidatorSet| {
+            let next = state.compute_state_root(validator_set);
+            assert_ne!(next, root);
+            root = next;
+        };
 
-    let start = SystemTime::now();
-    start.duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs()
+        // Rewards and commission accrue without changing any stake.
+        validator_set.distribute_rewards(&rate("20")).unwrap();
+        assert_root_changed(&validator_set);
+        validator_set.withdraw_commission("a").unwrap();
+        assert_root_changed(&validator_set);
+        let active: Vec<_> = validator_set.get_active_validators().into_iter().cloned().collect();
+        validator_set.record_block_signatures(1, &active, &HashSet::new());
+        assert_root_changed(&validator_set);
+        validator_set.stake_manager_mut().withdraw_rewards("a", "a").unwrap();
+        assert_root_changed(&validator_set);
+
+        // Unbonding stake leaves the delegation but is still owed to the delegator.
+        let stake_manager = validator_set.stake_manager_mut();
+        stake_manager.delegate_stake("d".to_string(), "a".to_string(), rate("4")).unwrap();
+        stake_manager.undelegate_stake("d".to_string(), "a".to_string(), rate("4")).unwrap();
+        assert_eq!(validator_set.stake_manager().get_unbonding_entries().len(), 1);
+        let before_release = state.compute_state_root(&validator_set);
+        validator_set.stake_manager_mut().end_block(20, 0);
+        assert!(validator_set.stake_manager().get_unbonding_entries().is_empty());
+        assert_ne!(state.compute_state_root(&validator_set), before_release);
+    }
 }
@@ -0,0 +1,162 @@
+// evidence.rs
+// Evidence of validator misbehaviour. Any node can submit evidence; once it is
+// committed in a block the offender is slashed.
+
+use serde::{Serialize, Deserialize};
+use crate::finality_gadget::Attestation;
+use crate::pos_algorithm::{Height, Proposal, Round, Vote};
+use crate::validator_set::ValidatorSet;
+use crate::utilities::crypto_utils::{self, Hash};
+
+/// Two conflicting votes signed by the same validator for the same height, round and vote type.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateVoteEvidence {
+    pub vote_a: Vote,
+    pub vote_b: Vote,
+}
+
+impl DuplicateVoteEvidence {
+    /// Checks that both votes come from the same validator, conflict, and are correctly signed.
+    pub fn verify(&self, validator_set: &ValidatorSet) -> Result<(), EvidenceError> {
+        let (a, b) = (&self.vote_a, &self.vote_b);
+        if a.validator_id != b.validator_id {
+            return Err(EvidenceError::MismatchedValidators);
+        }
+        if a.vote_type != b.vote_type || a.height != b.height || a.round != b.round || a.block_hash == b.block_hash {
+            return Err(EvidenceError::NotConflicting);
+        }
+        let validator = validator_set
+            .get_validator(&a.validator_id)
+            .ok_or(EvidenceError::UnknownValidator)?;
+        for vote in [a, b] {
+            if !crypto_utils::verify_signature(&validator.public_key, &vote.sign_bytes(), &vote.signature) {
+                return Err(EvidenceError::InvalidSignature);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Two different proposals signed by the same proposer for the same height and round.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DuplicateProposalEvidence {
+    pub proposal_a: Proposal,
+    pub proposal_b: Proposal,
+}
+
+impl DuplicateProposalEvidence {
+    /// Checks that both proposals come from the same proposer, conflict, and are correctly signed.
+    pub fn verify(&self, validator_set: &ValidatorSet) -> Result<(), EvidenceError> {
+        let (a, b) = (&self.proposal_a, &self.proposal_b);
+        if a.proposer_id != b.proposer_id {
+            return Err(EvidenceError::MismatchedValidators);
+        }
+        if a.height != b.height || a.round != b.round || a.sign_bytes() == b.sign_bytes() {
+            return Err(EvidenceError::NotConflicting);
+        }
+        let validator = validator_set
+            .get_validator(&a.proposer_id)
+            .ok_or(EvidenceError::UnknownValidator)?;
+        for proposal in [a, b] {
+            if !crypto_utils::verify_signature(&validator.public_key, &proposal.sign_bytes(), &proposal.signature) {
+                return Err(EvidenceError::InvalidSignature);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Two checkpoint attestations signed by the same validator that form a double vote or a surround vote.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ConflictingAttestationEvidence {
+    pub attestation_a: Attestation,
+    pub attestation_b: Attestation,
+}
+
+impl ConflictingAttestationEvidence {
+    /// Checks that both attestations come from the same validator, conflict, and are correctly signed.
+    pub fn verify(&self, validator_set: &ValidatorSet) -> Result<(), EvidenceError> {
+        let (a, b) = (&self.attestation_a, &self.attestation_b);
+        if a.validator_id != b.validator_id {
+            return Err(EvidenceError::MismatchedValidators);
+        }
+        if !a.conflicts_with(b) {
+            return Err(EvidenceError::NotConflicting);
+        }
+        let validator = validator_set
+            .get_validator(&a.validator_id)
+            .ok_or(EvidenceError::UnknownValidator)?;
+        for attestation in [a, b] {
+            if !crypto_utils::verify_signature(&validator.public_key, &attestation.sign_bytes(), &attestation.signature) {
+                return Err(EvidenceError::InvalidSignature);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Evidence that a validator signed conflicting consensus messages.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Evidence {
+    DuplicateVote(DuplicateVoteEvidence),
+    DuplicateProposal(DuplicateProposalEvidence),
+    ConflictingAttestation(ConflictingAttestationEvidence),
+}
+
+impl Evidence {
+    /// Returns the ID of the validator that misbehaved.
+    pub fn validator_id(&self) -> &str {
+        match self {
+            Evidence::DuplicateVote(e) => &e.vote_a.validator_id,
+            Evidence::DuplicateProposal(e) => &e.proposal_a.proposer_id,
+            Evidence::ConflictingAttestation(e) => &e.attestation_a.validator_id,
+        }
+    }
+
+    /// Returns the height at which the misbehaviour happened.
+    /// For attestations this is the height of the later target checkpoint.
+    pub fn height(&self) -> Height {
+        match self {
+            Evidence::DuplicateVote(e) => e.vote_a.height,
+            Evidence::DuplicateProposal(e) => e.proposal_a.height,
+            Evidence::ConflictingAttestation(e) => e.attestation_a.target.height.max(e.attestation_b.target.height),
+        }
+    }
+
+    /// Returns a hash identifying the offence: the offender, the kind of misbehaviour and the
+    /// height and round it happened at, but not the messages proving it. Three conflicting votes
+    /// a, b and c give the evidence pairs (a, b), (a, c) and (b, c), all of the same offence, so
+    /// an offence is only punished once.
+    pub fn hash(&self) -> Hash {
+        let (kind, round): (&str, Round) = match self {
+            Evidence::DuplicateVote(e) => ("duplicate-vote", e.vote_a.round),
+            Evidence::DuplicateProposal(e) => ("duplicate-proposal", e.proposal_a.round),
+            Evidence::ConflictingAttestation(_) => ("conflicting-attestation", 0),
+        };
+        let offence = serde_json::to_vec(&(kind, self.validator_id(), self.height(), round))
+            .expect("offence fields are serializable");
+        crypto_utils::hash_bytes(&offence)
+    }
+
+    /// Verifies the conflicting messages against the validator set.
+    pub fn verify(&self, validator_set: &ValidatorSet) -> Result<(), EvidenceError> {
+        match self {
+            Evidence::DuplicateVote(e) => e.verify(validator_set),
+            Evidence::DuplicateProposal(e) => e.verify(validator_set),
+            Evidence::ConflictingAttestation(e) => e.verify(validator_set),
+        }
+    }
+}
+
+/// Reasons evidence can be rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum EvidenceError {
+    UnknownValidator,
+    MismatchedValidators,
+    NotConflicting,
+    InvalidSignature,
+    AlreadyProcessed,
+    Expired,
+    /// The offender was already tombstoned for an earlier offence.
+    Tombstoned,
+}
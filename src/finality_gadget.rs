@@ -0,0 +1,338 @@
+// finality_gadget.rs
+// Checkpoint finality: validators attest to links between epoch checkpoints, and a checkpoint
+// is finalized once it and its child checkpoint are justified. `HotStuffEngine` drives it in the
+// `EngineKind::CheckpointedHotStuff` mode, attesting to each checkpoint block once committed.
+
+use std::collections::{BTreeMap, HashMap};
+use serde::{Serialize, Deserialize};
+use crate::evidence::ConflictingAttestationEvidence;
+use crate::pos_algorithm::Height;
+use crate::utilities::crypto_utils::{self, Hash};
+use crate::utilities::serde_utils;
+use crate::validator_set::ValidatorSet;
+
+/// Number of blocks between checkpoints unless configured otherwise.
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 64;
+
+/// The block at the first height of a checkpoint epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Checkpoint {
+    pub epoch: u64,
+    pub height: Height,
+    pub hash: Hash,
+}
+
+/// A validator's vote for the link from a justified `source` checkpoint to a later `target`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Attestation {
+    pub validator_id: String,
+    pub source: Checkpoint,
+    pub target: Checkpoint,
+    pub signature: Vec<u8>,
+}
+
+impl Attestation {
+    /// Returns the bytes covered by the attestation signature.
+    pub fn sign_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&("attestation", &self.source, &self.target)).expect("attestation fields are serializable")
+    }
+
+    /// Returns true if signing both attestations is slashable: two different targets for the same
+    /// epoch (double vote), or one link strictly inside the other (surround vote).
+    pub fn conflicts_with(&self, other: &Attestation) -> bool {
+        let double_vote = self.target.epoch == other.target.epoch && self.target != other.target;
+        let surrounds = |outer: &Attestation, inner: &Attestation| {
+            outer.source.epoch < inner.source.epoch && inner.target.epoch < outer.target.epoch
+        };
+        double_vote || surrounds(self, other) || surrounds(other, self)
+    }
+}
+
+/// Reasons an attestation is rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FinalityError {
+    UnknownValidator,
+    InvalidSignature,
+    /// The checkpoints are not at checkpoint heights, or the target is not after the source.
+    InvalidLink,
+    /// The target is neither a committed checkpoint block nor the next checkpoint to be committed.
+    UnknownTarget,
+    UnjustifiedSource,
+    /// The source is older than the finalized checkpoint.
+    StaleSource,
+    /// The source checkpoint is not an ancestor of the target block.
+    SourceNotAncestor,
+    /// The attestation conflicts with an earlier one from the same validator.
+    Conflicting(Box<ConflictingAttestationEvidence>),
+}
+
+/// Tracks justified and finalized checkpoints from attestations.
+/// A checkpoint is justified once validators holding more than two thirds of the voting power
+/// attest to a link from a justified source to it. A justified checkpoint is finalized once the
+/// checkpoint of the next epoch is justified with it as the source.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FinalityGadget {
+    checkpoint_interval: u64,
+    /// Justified checkpoints from the finalized epoch onwards, by epoch.
+    justified: BTreeMap<u64, Checkpoint>,
+    finalized: Checkpoint,
+    /// Voting power attesting to each link, by validator.
+    #[serde(with = "serde_utils::map_as_pairs")]
+    links: HashMap<(Checkpoint, Checkpoint), HashMap<String, u64>>,
+    /// Attestations of each validator that can still conflict with a new one.
+    attestations: HashMap<String, Vec<Attestation>>,
+}
+
+impl Default for FinalityGadget {
+    fn default() -> Self {
+        FinalityGadget::new(
+            DEFAULT_CHECKPOINT_INTERVAL,
+            Checkpoint {
+                epoch: 0,
+                height: 0,
+                hash: [0; 32],
+            },
+        )
+    }
+}
+
+impl FinalityGadget {
+    /// Creates a gadget with checkpoints every `checkpoint_interval` blocks, starting from the
+    /// justified and finalized `genesis` checkpoint.
+    pub fn new(checkpoint_interval: u64, genesis: Checkpoint) -> Self {
+        let mut justified = BTreeMap::new();
+        justified.insert(genesis.epoch, genesis);
+        FinalityGadget {
+            checkpoint_interval: checkpoint_interval.max(1),
+            justified,
+            finalized: genesis,
+            links: HashMap::new(),
+            attestations: HashMap::new(),
+        }
+    }
+
+    /// Returns the number of blocks between checkpoints.
+    pub fn checkpoint_interval(&self) -> u64 {
+        self.checkpoint_interval
+    }
+
+    /// Returns the checkpoint epoch starting at `height`, if `height` is a checkpoint height.
+    pub fn checkpoint_epoch(&self, height: Height) -> Option<u64> {
+        if height % self.checkpoint_interval == 0 {
+            Some(height / self.checkpoint_interval)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the last finalized checkpoint.
+    pub fn finalized(&self) -> Checkpoint {
+        self.finalized
+    }
+
+    /// Returns the justified checkpoint with the highest epoch.
+    /// Honest validators use it as the source of their next attestation.
+    pub fn last_justified(&self) -> Checkpoint {
+        *self.justified.values().next_back().unwrap_or(&self.finalized)
+    }
+
+    /// Returns true if `checkpoint` is justified and not older than the finalized checkpoint.
+    pub fn is_justified(&self, checkpoint: &Checkpoint) -> bool {
+        self.justified.get(&checkpoint.epoch) == Some(checkpoint)
+    }
+
+    /// Verifies an attestation against the validator set and counts it. `source_is_ancestor` tells
+    /// whether the target block descends from the source block, which only the block tree knows.
+    /// Returns the checkpoint the attestation finalized, if any. An attestation conflicting with
+    /// an earlier one from the same validator is rejected with the evidence of the offence, even
+    /// if it would otherwise be rejected for its source.
+    pub fn process_attestation(
+        &mut self,
+        attestation: Attestation,
+        source_is_ancestor: bool,
+        validator_set: &ValidatorSet,
+    ) -> Result<Option<Checkpoint>, FinalityError> {
+        let power = validator_set.voting_power(&attestation.validator_id);
+        let validator = validator_set
+            .get_validator(&attestation.validator_id)
+            .filter(|_| power > 0)
+            .ok_or(FinalityError::UnknownValidator)?;
+        if !crypto_utils::verify_signature(&validator.public_key, &attestation.sign_bytes(), &attestation.signature) {
+            return Err(FinalityError::InvalidSignature);
+        }
+        self.record_attestation(attestation, source_is_ancestor, power, validator_set.total_voting_power())
+    }
+
+    /// Counts an attestation with `power` towards its link, justifying and finalizing checkpoints
+    /// once the link holds more than two thirds of `total_power`.
+    /// Conflicts are checked before the source: a signed surround or double vote is slashable
+    /// whether or not its source could justify anything.
+    fn record_attestation(
+        &mut self,
+        attestation: Attestation,
+        source_is_ancestor: bool,
+        power: u64,
+        total_power: u64,
+    ) -> Result<Option<Checkpoint>, FinalityError> {
+        let (source, target) = (attestation.source, attestation.target);
+        let at_checkpoint = |checkpoint: &Checkpoint| checkpoint.height == checkpoint.epoch * self.checkpoint_interval;
+        if target.epoch <= source.epoch || !at_checkpoint(&source) || !at_checkpoint(&target) {
+            return Err(FinalityError::InvalidLink);
+        }
+        if let Some(earlier) = self
+            .attestations
+            .get(&attestation.validator_id)
+            .and_then(|previous| previous.iter().find(|earlier| earlier.conflicts_with(&attestation)))
+        {
+            return Err(FinalityError::Conflicting(Box::new(ConflictingAttestationEvidence {
+                attestation_a: earlier.clone(),
+                attestation_b: attestation,
+            })));
+        }
+        if source.epoch < self.finalized.epoch {
+            return Err(FinalityError::StaleSource);
+        }
+        if !self.is_justified(&source) {
+            return Err(FinalityError::UnjustifiedSource);
+        }
+        if !source_is_ancestor {
+            return Err(FinalityError::SourceNotAncestor);
+        }
+
+        let previous = self.attestations.entry(attestation.validator_id.clone()).or_default();
+        if previous.iter().any(|earlier| earlier.source == source && earlier.target == target) {
+            return Ok(None);
+        }
+        previous.push(attestation.clone());
+
+        let voters = self.links.entry((source, target)).or_default();
+        voters.insert(attestation.validator_id, power);
+        let link_power: u64 = voters.values().sum();
+        if link_power as u128 * 3 <= total_power as u128 * 2 {
+            return Ok(None);
+        }
+        // A second checkpoint justified in the same epoch would need a third of the stake to be
+        // slashable, so the first one is kept.
+        self.justified.entry(target.epoch).or_insert(target);
+        if self.is_justified(&target) && target.epoch == source.epoch + 1 && source.epoch > self.finalized.epoch {
+            self.finalize(source);
+            return Ok(Some(source));
+        }
+        Ok(None)
+    }
+
+    /// Finalizes `checkpoint` and drops the state that can no longer affect finality or slashing:
+    /// new attestations must have a source at or after it, so they can only conflict with
+    /// attestations whose target is after it.
+    fn finalize(&mut self, checkpoint: Checkpoint) {
+        self.finalized = checkpoint;
+        self.justified.retain(|epoch, _| *epoch >= checkpoint.epoch);
+        self.links.retain(|(source, _), _| source.epoch >= checkpoint.epoch);
+        for attestations in self.attestations.values_mut() {
+            attestations.retain(|attestation| attestation.target.epoch > checkpoint.epoch);
+        }
+        self.attestations.retain(|_, attestations| !attestations.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkpoint(epoch: u64, tag: u8) -> Checkpoint {
+        Checkpoint {
+            epoch,
+            height: epoch * 10,
+            hash: [tag; 32],
+        }
+    }
+
+    fn attestation(validator_id: &str, source: Checkpoint, target: Checkpoint) -> Attestation {
+        Attestation {
+            validator_id: validator_id.to_string(),
+            source,
+            target,
+            signature: Vec::new(),
+        }
+    }
+
+    fn gadget() -> FinalityGadget {
+        FinalityGadget::new(10, checkpoint(0, 0))
+    }
+
+    #[test]
+    fn test_consecutive_justified_checkpoints_finalize() {
+        let mut gadget = gadget();
+        let (genesis, first, second) = (checkpoint(0, 0), checkpoint(1, 1), checkpoint(2, 2));
+
+        assert_eq!(gadget.record_attestation(attestation("a", genesis, first), true, 40, 100), Ok(None));
+        assert_eq!(gadget.record_attestation(attestation("b", genesis, first), true, 30, 100), Ok(None));
+        assert!(gadget.is_justified(&first));
+        assert_eq!(gadget.finalized(), genesis);
+
+        gadget.record_attestation(attestation("a", first, second), true, 40, 100).unwrap();
+        assert_eq!(gadget.record_attestation(attestation("b", first, second), true, 30, 100), Ok(Some(first)));
+        assert_eq!(gadget.finalized(), first);
+        assert_eq!(gadget.last_justified(), second);
+        assert_eq!(
+            gadget.record_attestation(attestation("c", genesis, checkpoint(3, 3)), true, 30, 100),
+            Err(FinalityError::StaleSource)
+        );
+    }
+
+    #[test]
+    fn test_two_thirds_is_not_enough_to_justify() {
+        let mut gadget = gadget();
+        let first = checkpoint(1, 1);
+        gadget.record_attestation(attestation("a", checkpoint(0, 0), first), true, 2, 3).unwrap();
+        assert!(!gadget.is_justified(&first));
+        assert_eq!(
+            gadget.record_attestation(attestation("a", first, checkpoint(2, 2)), true, 2, 3),
+            Err(FinalityError::UnjustifiedSource)
+        );
+    }
+
+    #[test]
+    fn test_double_and_surround_votes_are_reported() {
+        let mut gadget = gadget();
+        let genesis = checkpoint(0, 0);
+        gadget.record_attestation(attestation("a", genesis, checkpoint(1, 1)), true, 70, 100).unwrap();
+        gadget.record_attestation(attestation("b", genesis, checkpoint(1, 1)), true, 30, 100).unwrap();
+
+        // Same target epoch, different block.
+        let result = gadget.record_attestation(attestation("a", genesis, checkpoint(1, 9)), true, 70, 100);
+        assert!(matches!(result, Err(FinalityError::Conflicting(_))));
+
+        // Genesis -> 3 surrounds 1 -> 2.
+        gadget.record_attestation(attestation("c", checkpoint(1, 1), checkpoint(2, 2)), true, 10, 100).unwrap();
+        let surrounding = attestation("c", genesis, checkpoint(3, 3));
+        match gadget.record_attestation(surrounding.clone(), true, 10, 100) {
+            Err(FinalityError::Conflicting(evidence)) => {
+                assert_eq!(evidence.attestation_b, surrounding);
+                assert!(evidence.attestation_a.conflicts_with(&evidence.attestation_b));
+            }
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_conflicts_are_reported_before_the_source_is_checked() {
+        let mut gadget = gadget();
+        let (genesis, first, second) = (checkpoint(0, 0), checkpoint(1, 1), checkpoint(2, 2));
+        gadget.record_attestation(attestation("a", genesis, first), true, 70, 100).unwrap();
+        gadget.record_attestation(attestation("a", first, second), true, 70, 100).unwrap();
+        assert_eq!(gadget.finalized(), first);
+
+        // The source is stale, yet genesis -> 3 still surrounds the counted 1 -> 2.
+        let surrounding = attestation("a", genesis, checkpoint(3, 3));
+        assert!(matches!(
+            gadget.record_attestation(surrounding, true, 70, 100),
+            Err(FinalityError::Conflicting(_))
+        ));
+
+        assert_eq!(
+            gadget.record_attestation(attestation("b", second, checkpoint(3, 3)), false, 30, 100),
+            Err(FinalityError::SourceNotAncestor)
+        );
+    }
+}
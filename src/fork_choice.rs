@@ -0,0 +1,299 @@
+// fork_choice.rs
+// Tree of known blocks and the fork-choice rule selecting the canonical head.
+
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+use crate::pos_algorithm::Height;
+use crate::utilities::crypto_utils::Hash;
+
+/// A block in the tree, linked to its parent.
+#[derive(Debug, Clone)]
+struct BlockNode<T> {
+    parent: Hash,
+    height: Height,
+    /// The block itself; `None` for the finalized root the tree was created with.
+    payload: Option<T>,
+    children: Vec<Hash>,
+}
+
+/// Emitted when the head moves to a block that does not descend from the previous head.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReorgEvent {
+    pub old_head: Hash,
+    pub new_head: Hash,
+    /// Last block shared by both branches.
+    pub common_ancestor: Hash,
+    /// Number of blocks of the old branch that are no longer canonical.
+    pub depth: u64,
+}
+
+/// Errors returned when extending the block tree.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ForkChoiceError {
+    /// The block's parent is unknown, or was pruned because it conflicts with the finalized block.
+    UnknownParent,
+    UnknownBlock,
+    /// The block does not descend from the finalized block.
+    ConflictsWithFinalized,
+}
+
+/// Blocks descending from the last finalized block, with the latest attestation of each validator.
+/// The head is chosen greedily from the finalized block: at each fork, the child whose subtree
+/// carries the most attested stake is followed, ties going to the lowest hash.
+/// Only the latest attestation of a validator counts, so moving to another branch takes its
+/// weight off the old one.
+#[derive(Debug, Clone)]
+pub struct BlockTree<T> {
+    nodes: HashMap<Hash, BlockNode<T>>,
+    /// Latest attested block and stake of each validator.
+    attestations: HashMap<String, (Hash, u64)>,
+    finalized: Hash,
+    head: Hash,
+}
+
+impl<T> Default for BlockTree<T> {
+    /// An empty tree rooted at the zero hash, as before the first block.
+    fn default() -> Self {
+        BlockTree::new([0; 32], 0)
+    }
+}
+
+impl<T> BlockTree<T> {
+    /// Creates a tree rooted at the finalized block `root` at `height`.
+    pub fn new(root: Hash, height: Height) -> Self {
+        let mut nodes = HashMap::new();
+        nodes.insert(
+            root,
+            BlockNode {
+                parent: root,
+                height,
+                payload: None,
+                children: Vec::new(),
+            },
+        );
+        BlockTree {
+            nodes,
+            attestations: HashMap::new(),
+            finalized: root,
+            head: root,
+        }
+    }
+
+    /// Adds a block whose parent is already in the tree and updates the head.
+    /// Returns the reorg the block caused, if any. Adding a known block has no effect.
+    pub fn add_block(&mut self, hash: Hash, parent: Hash, payload: T) -> Result<Option<ReorgEvent>, ForkChoiceError> {
+        if self.nodes.contains_key(&hash) {
+            return Ok(None);
+        }
+        let parent_node = self.nodes.get_mut(&parent).ok_or(ForkChoiceError::UnknownParent)?;
+        parent_node.children.push(hash);
+        let height = parent_node.height + 1;
+        self.nodes.insert(
+            hash,
+            BlockNode {
+                parent,
+                height,
+                payload: Some(payload),
+                children: Vec::new(),
+            },
+        );
+        Ok(self.update_head())
+    }
+
+    /// Records that a validator with `stake` attests to `hash` and the chain leading to it,
+    /// replacing its previous attestation, and updates the head.
+    /// Returns the reorg the attestation caused, if any.
+    pub fn add_attestation(&mut self, validator_id: &str, hash: Hash, stake: u64) -> Result<Option<ReorgEvent>, ForkChoiceError> {
+        if !self.nodes.contains_key(&hash) {
+            return Err(ForkChoiceError::UnknownBlock);
+        }
+        self.attestations.insert(validator_id.to_string(), (hash, stake));
+        Ok(self.update_head())
+    }
+
+    /// Marks `hash` as finalized and prunes every block that does not descend from it.
+    /// The head always descends from the finalized block afterwards.
+    pub fn finalize(&mut self, hash: Hash) -> Result<Option<ReorgEvent>, ForkChoiceError> {
+        if !self.nodes.contains_key(&hash) {
+            return Err(ForkChoiceError::UnknownBlock);
+        }
+        if !self.is_descendant(&hash, &self.finalized) {
+            return Err(ForkChoiceError::ConflictsWithFinalized);
+        }
+        let mut keep = HashMap::new();
+        let mut pending = vec![hash];
+        while let Some(current) = pending.pop() {
+            if let Some(node) = self.nodes.remove(&current) {
+                pending.extend(node.children.iter().copied());
+                keep.insert(current, node);
+            }
+        }
+        self.nodes = keep;
+        self.finalized = hash;
+        let nodes = &self.nodes;
+        self.attestations.retain(|_, (attested, _)| nodes.contains_key(attested));
+        Ok(self.update_head())
+    }
+
+    /// Returns the canonical head.
+    pub fn head(&self) -> Hash {
+        self.head
+    }
+
+    /// Returns the last finalized block.
+    pub fn finalized(&self) -> Hash {
+        self.finalized
+    }
+
+    /// Returns the block with the given hash, if it is in the tree and is not the root.
+    pub fn get(&self, hash: &Hash) -> Option<&T> {
+        self.nodes.get(hash).and_then(|node| node.payload.as_ref())
+    }
+
+    /// Returns the height of a block in the tree.
+    pub fn height_of(&self, hash: &Hash) -> Option<Height> {
+        self.nodes.get(hash).map(|node| node.height)
+    }
+
+    /// Returns true if `hash` is `ancestor` or descends from it.
+    pub fn is_descendant(&self, hash: &Hash, ancestor: &Hash) -> bool {
+        let mut current = *hash;
+        loop {
+            if current == *ancestor {
+                return true;
+            }
+            match self.nodes.get(&current) {
+                Some(node) if current != self.finalized => current = node.parent,
+                _ => return false,
+            }
+        }
+    }
+
+    /// Returns the ancestor of `hash` at `height`, if both are in the tree.
+    pub fn ancestor_at(&self, hash: &Hash, height: Height) -> Option<Hash> {
+        let mut current = *hash;
+        loop {
+            let node = self.nodes.get(&current)?;
+            if node.height == height {
+                return Some(current);
+            }
+            if node.height < height || current == self.finalized {
+                return None;
+            }
+            current = node.parent;
+        }
+    }
+
+    /// Re-runs the fork-choice rule and returns the reorg event if the head switched branches.
+    fn update_head(&mut self) -> Option<ReorgEvent> {
+        let weights = self.subtree_weights();
+        let mut head = self.finalized;
+        while let Some(node) = self.nodes.get(&head) {
+            let best = node.children.iter().max_by(|a, b| {
+                let weight = |hash: &Hash| weights.get(hash).copied().unwrap_or(0);
+                weight(a).cmp(&weight(b)).then_with(|| b.cmp(a))
+            });
+            match best {
+                Some(child) => head = *child,
+                None => break,
+            }
+        }
+
+        let old_head = std::mem::replace(&mut self.head, head);
+        if !self.nodes.contains_key(&old_head) || self.is_descendant(&head, &old_head) {
+            return None;
+        }
+        let common_ancestor = self.common_ancestor(&old_head, &head);
+        let depth = self.nodes[&old_head].height - self.nodes[&common_ancestor].height;
+        Some(ReorgEvent {
+            old_head,
+            new_head: head,
+            common_ancestor,
+            depth,
+        })
+    }
+
+    /// Sums the attested stake of every block's subtree.
+    fn subtree_weights(&self) -> HashMap<Hash, u64> {
+        let mut weights = HashMap::new();
+        for (attested, stake) in self.attestations.values() {
+            let mut current = *attested;
+            while let Some(node) = self.nodes.get(&current) {
+                *weights.entry(current).or_insert(0) += stake;
+                if current == self.finalized {
+                    break;
+                }
+                current = node.parent;
+            }
+        }
+        weights
+    }
+
+    /// Returns the latest block both `a` and `b` descend from.
+    fn common_ancestor(&self, a: &Hash, b: &Hash) -> Hash {
+        let (mut a, mut b) = (*a, *b);
+        while a != b {
+            let (height_a, height_b) = (self.nodes[&a].height, self.nodes[&b].height);
+            if height_a >= height_b {
+                a = self.nodes[&a].parent;
+            }
+            if height_b >= height_a {
+                b = self.nodes[&b].parent;
+            }
+        }
+        a
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(byte: u8) -> Hash {
+        [byte; 32]
+    }
+
+    /// Root 0 with branches 0 <- 1 <- 2 and 0 <- 3.
+    fn forked_tree() -> BlockTree<()> {
+        let mut tree = BlockTree::new(hash(0), 0);
+        tree.add_block(hash(1), hash(0), ()).unwrap();
+        tree.add_block(hash(2), hash(1), ()).unwrap();
+        tree.add_block(hash(3), hash(0), ()).unwrap();
+        tree
+    }
+
+    #[test]
+    fn test_head_follows_heaviest_attested_branch() {
+        let mut tree = forked_tree();
+        tree.add_attestation("a", hash(2), 10).unwrap();
+        assert_eq!(tree.head(), hash(2));
+
+        let reorg = tree.add_attestation("b", hash(3), 20).unwrap();
+        assert_eq!(tree.head(), hash(3));
+        assert_eq!(
+            reorg,
+            Some(ReorgEvent {
+                old_head: hash(2),
+                new_head: hash(3),
+                common_ancestor: hash(0),
+                depth: 2,
+            })
+        );
+
+        // Moving the heavier validator back takes its weight off the other branch.
+        tree.add_attestation("b", hash(1), 20).unwrap();
+        assert_eq!(tree.head(), hash(2));
+    }
+
+    #[test]
+    fn test_finalization_prunes_conflicting_branches() {
+        let mut tree = forked_tree();
+        tree.add_attestation("a", hash(3), 50).unwrap();
+        tree.finalize(hash(1)).unwrap();
+
+        assert_eq!(tree.head(), hash(2));
+        assert!(tree.height_of(&hash(3)).is_none());
+        assert_eq!(tree.add_block(hash(4), hash(3), ()), Err(ForkChoiceError::UnknownParent));
+        assert_eq!(tree.finalize(hash(0)), Err(ForkChoiceError::UnknownBlock));
+    }
+}
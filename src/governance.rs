@@ -2,76 +2,183 @@
 // Manages the governance aspects of the VENIA blockchain's consensus mechanism.
 // This includes handling proposals, voting mechanisms, and updating consensus rules.
 
-use std::collections::HashMap;
-use crate::validator_set::Validator;
+use std::collections::{BTreeMap, HashMap};
+use serde::{Serialize, Deserialize};
+use crate::validator_set::{Validator, ValidatorSet};
 use crate::consensus_state::ConsensusState;
-use crate::utilities::crypto_utils;
+use crate::monetary_policy::MintParams;
+
+/// Share of the total voting power that must take part in a vote for a proposal to pass.
+const QUORUM_NUMERATOR: u128 = 1;
+const QUORUM_DENOMINATOR: u128 = 3;
 
 /// Represents a governance proposal in the blockchain.
-struct Proposal {
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Proposal {
     id: u64,
     proposer: Validator,
     description: String,
     start_block: u64,
     end_block: u64,
+    /// Each validator's vote, by validator ID. A validator can vote once.
+    #[serde(default)]
+    votes: BTreeMap<String, bool>,
+    /// Voting power for and against the proposal, tallied when voting ends.
     votes_for: u64,
     votes_against: u64,
     status: ProposalStatus,
+    content: ProposalContent,
+}
+
+impl Proposal {
+    /// Creates a proposal open for votes from `start_block` to `end_block`.
+    pub fn new(id: u64, proposer: Validator, description: String, start_block: u64, end_block: u64, content: ProposalContent) -> Self {
+        Proposal {
+            id,
+            proposer,
+            description,
+            start_block,
+            end_block,
+            votes: BTreeMap::new(),
+            votes_for: 0,
+            votes_against: 0,
+            status: ProposalStatus::Active,
+            content,
+        }
+    }
+
+    /// Returns the proposal's ID.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the proposal's status.
+    pub fn status(&self) -> &ProposalStatus {
+        &self.status
+    }
+
+    /// Returns the voting power for and against the proposal, once voting has ended.
+    pub fn tally(&self) -> (u64, u64) {
+        (self.votes_for, self.votes_against)
+    }
+}
+
+/// What an approved proposal changes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ProposalContent {
+    /// A signalling proposal with no on-chain effect.
+    Text,
+    /// Replaces the inflation schedule parameters.
+    MintParams(MintParams),
 }
 
 /// Enum representing the status of a proposal.
-enum ProposalStatus {
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ProposalStatus {
     Pending,
     Active,
     Approved,
     Rejected,
+    /// Approved and applied to the consensus state.
+    Executed,
+}
+
+/// Errors returned when submitting or voting on proposals.
+#[derive(Debug, PartialEq, Eq)]
+pub enum GovernanceError {
+    /// The proposer or voter has no voting power.
+    NotAValidator,
+    /// A proposal with the same ID was already submitted.
+    DuplicateProposal,
+    /// The voting period ends before it starts.
+    InvalidVotingPeriod,
+    UnknownProposal,
+    /// Voting on the proposal has ended.
+    VotingClosed,
+    /// The validator already voted on the proposal.
+    AlreadyVoted,
 }
 
 /// Governance module for managing proposals and voting.
+/// Held by the consensus state, which runs it at the end of every finalized block and persists
+/// the proposals with the rest of its state.
+///
+/// Votes are weighted by the voters' voting power when voting ends. A proposal is approved if
+/// the validators that voted hold more than `QUORUM_NUMERATOR / QUORUM_DENOMINATOR` of the total
+/// voting power and more of that power voted for it than against it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Governance {
     proposals: HashMap<u64, Proposal>,
-    consensus_state: ConsensusState,
 }
 
 impl Governance {
     /// Creates a new governance module.
-    pub fn new(consensus_state: ConsensusState) -> Self {
+    pub fn new() -> Self {
         Governance {
             proposals: HashMap::new(),
-            consensus_state,
         }
     }
 
-    /// Submits a new proposal to the blockchain.
-    pub fn submit_proposal(&mut self, proposal: Proposal) {
-        // TODO: Implement validation checks for proposal submission.
+    /// Submits a new proposal to the blockchain. Only validators with voting power can propose.
+    pub fn submit_proposal(&mut self, proposal: Proposal, validator_set: &ValidatorSet) -> Result<(), GovernanceError> {
+        if validator_set.voting_power(&proposal.proposer.id) == 0 {
+            return Err(GovernanceError::NotAValidator);
+        }
+        if self.proposals.contains_key(&proposal.id) {
+            return Err(GovernanceError::DuplicateProposal);
+        }
+        if proposal.end_block < proposal.start_block {
+            return Err(GovernanceError::InvalidVotingPeriod);
+        }
         self.proposals.insert(proposal.id, proposal);
+        Ok(())
     }
 
-    /// Allows validators to vote on a proposal.
-    pub fn vote(&mut self, proposal_id: u64, validator: &Validator, vote: bool) {
-        // TODO: Implement checks to ensure only validators can vote.
-        if let Some(proposal) = self.proposals.get_mut(&proposal_id) {
-            if vote {
-                proposal.votes_for += 1;
-            } else {
-                proposal.votes_against += 1;
-            }
+    /// Records a validator's vote on a proposal. Only validators with voting power can vote, once
+    /// per proposal, while it is open.
+    pub fn vote(&mut self, proposal_id: u64, validator: &Validator, vote: bool, validator_set: &ValidatorSet) -> Result<(), GovernanceError> {
+        if validator_set.voting_power(&validator.id) == 0 {
+            return Err(GovernanceError::NotAValidator);
+        }
+        let proposal = self.proposals.get_mut(&proposal_id).ok_or(GovernanceError::UnknownProposal)?;
+        if !matches!(proposal.status, ProposalStatus::Pending | ProposalStatus::Active) {
+            return Err(GovernanceError::VotingClosed);
         }
+        if proposal.votes.contains_key(&validator.id) {
+            return Err(GovernanceError::AlreadyVoted);
+        }
+        proposal.votes.insert(validator.id.clone(), vote);
+        Ok(())
     }
 
-    /// Updates the status of proposals based on votes and current block number.
-    pub fn update_proposal_status(&mut self, current_block: u64) {
+    /// Updates the status of proposals based on votes and current block number, and applies the
+    /// approved ones to `consensus_state`. Votes are tallied with the voters' current voting power.
+    pub fn update_proposal_status(&mut self, current_block: u64, consensus_state: &mut ConsensusState, validator_set: &ValidatorSet) {
+        let total_power = validator_set.total_voting_power() as u128;
         for proposal in self.proposals.values_mut() {
-            if current_block > proposal.end_block {
-                if proposal.votes_for > proposal.votes_against {
-                    proposal.status = ProposalStatus::Approved;
-                    // TODO: Implement logic to apply approved proposals.
+            let open = matches!(proposal.status, ProposalStatus::Pending | ProposalStatus::Active);
+            if open && current_block > proposal.end_block {
+                let power = |in_favour: bool| -> u64 {
+                    proposal
+                        .votes
+                        .iter()
+                        .filter(|(_, vote)| **vote == in_favour)
+                        .map(|(validator_id, _)| validator_set.voting_power(validator_id))
+                        .sum()
+                };
+                let (votes_for, votes_against) = (power(true), power(false));
+                proposal.votes_for = votes_for;
+                proposal.votes_against = votes_against;
+                let voted = proposal.votes_for as u128 + proposal.votes_against as u128;
+                let quorum = voted * QUORUM_DENOMINATOR > total_power * QUORUM_NUMERATOR;
+                proposal.status = if quorum && proposal.votes_for > proposal.votes_against {
+                    ProposalStatus::Approved
                 } else {
-                    proposal.status = ProposalStatus::Rejected;
-                }
+                    ProposalStatus::Rejected
+                };
             }
         }
+        self.apply_governance_changes(consensus_state);
     }
 
     /// Retrieves the current list of proposals.
@@ -79,12 +186,92 @@ impl Governance {
         &self.proposals
     }
 
+    /// Returns every proposal as JSON with its ID, for the state tree.
+    pub fn proposal_records(&self) -> impl Iterator<Item = (u64, Vec<u8>)> + '_ {
+        self.proposals
+            .values()
+            .map(|proposal| (proposal.id, serde_json::to_vec(proposal).expect("proposal is serializable")))
+    }
+
     /// Applies approved governance changes to the consensus state.
-    fn apply_governance_changes(&mut self) {
-        // TODO: Implement the mechanism to apply governance changes.
-        // This could include updating validator sets, adjusting consensus parameters, etc.
+    /// Approved proposals whose change is rejected by the consensus state are marked as rejected.
+    fn apply_governance_changes(&mut self, consensus_state: &mut ConsensusState) {
+        // TODO: Support further changes, such as updating validator sets or other consensus parameters.
+        let mut approved: Vec<&mut Proposal> = self
+            .proposals
+            .values_mut()
+            .filter(|proposal| matches!(proposal.status, ProposalStatus::Approved))
+            .collect();
+        // Apply in ID order so every node ends up with the same parameters.
+        approved.sort_by_key(|proposal| proposal.id);
+        for proposal in approved {
+            let applied = match &proposal.content {
+                ProposalContent::Text => true,
+                ProposalContent::MintParams(params) => consensus_state.set_mint_params(params.clone()).is_ok(),
+            };
+            proposal.status = if applied {
+                ProposalStatus::Executed
+            } else {
+                ProposalStatus::Rejected
+            };
+        }
     }
 }
 
 // Additional utility functions and structs related to governance can be added here.
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use big_decimal::BigDecimal;
+    use crate::stake_manager::StakeManager;
+    use crate::validator_set::{Commission, ValidatorSet};
+
+    #[test]
+    fn test_approved_mint_params_take_effect_at_end_of_voting() {
+        let mut validator_set = ValidatorSet::new(StakeManager::new());
+        for (id, stake) in [("a", 10), ("b", 10), ("c", 40)] {
+            validator_set.add_validator(id.to_string(), stake, vec![1; 32], Commission::default()).unwrap();
+            validator_set.update_validator_status(id, true);
+        }
+        let validator = |id: &str| validator_set.get_validator(id).unwrap().clone();
+        let (a, b, c) = (validator("a"), validator("b"), validator("c"));
+        let mut consensus_state = ConsensusState::new(&validator_set);
+        let params = MintParams {
+            inflation_max: BigDecimal::from_str("0.1").unwrap(),
+            ..MintParams::default()
+        };
+        let proposal = |id: u64| {
+            Proposal::new(id, a.clone(), "Lower the maximum inflation".to_string(), 1, 5, ProposalContent::MintParams(params.clone()))
+        };
+
+        let governance = consensus_state.governance_mut();
+        governance.submit_proposal(proposal(1), &validator_set).unwrap();
+        governance.submit_proposal(proposal(2), &validator_set).unwrap();
+        assert_eq!(governance.submit_proposal(proposal(2), &validator_set), Err(GovernanceError::DuplicateProposal));
+        // Two validators for and one against, but the one against holds most of the power.
+        governance.vote(1, &a, true, &validator_set).unwrap();
+        governance.vote(1, &b, true, &validator_set).unwrap();
+        governance.vote(1, &c, false, &validator_set).unwrap();
+        governance.vote(2, &c, true, &validator_set).unwrap();
+        assert_eq!(governance.vote(2, &c, false, &validator_set), Err(GovernanceError::AlreadyVoted));
+        let mut outsider = a.clone();
+        outsider.id = "d".to_string();
+        assert_eq!(governance.vote(2, &outsider, false, &validator_set), Err(GovernanceError::NotAValidator));
+
+        consensus_state.end_block_governance(5, &validator_set);
+        assert_ne!(consensus_state.minter().params(), &params);
+
+        consensus_state.end_block_governance(6, &validator_set);
+        assert_eq!(consensus_state.minter().params(), &params);
+        let proposals = consensus_state.governance().get_proposals();
+        assert_eq!(proposals[&1].status(), &ProposalStatus::Rejected);
+        assert_eq!(proposals[&1].tally(), (20, 40));
+        assert_eq!(proposals[&2].status(), &ProposalStatus::Executed);
+        assert_eq!(
+            consensus_state.governance_mut().vote(2, &a, true, &validator_set),
+            Err(GovernanceError::VotingClosed)
+        );
+    }
+}
@@ -0,0 +1,995 @@
+// hotstuff.rs
+// Chained HotStuff consensus engine. Every proposal carries the certificate of the block it
+// extends, so a single round of votes per view certifies the new block and moves the blocks
+// below it one phase closer to being committed.
+
+use std::collections::{HashMap, HashSet};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+use blockchain_types::Block;
+
+use crate::block_proposal::BlockProposal;
+use crate::consensus_engine::{self, ConsensusEngine};
+use crate::consensus_state::ConsensusState;
+use crate::evidence::Evidence;
+use crate::finality_gadget::{Attestation, Checkpoint};
+use crate::network_communication::NetworkCommunication;
+use crate::pos_algorithm::{Commit, ConsensusMessage, Height, Round, ValidatorIdentity, Vote, VoteType};
+use crate::quorum_certificate::QuorumCertificate;
+use crate::storage::{self, ConsensusStore, StorageError};
+use crate::transaction_verifier::TransactionVerifier;
+use crate::utilities::crypto_utils::{self, Hash};
+use crate::utilities::time_utils;
+use crate::validator_set::ValidatorSet;
+use crate::vote_set::{VoteSet, VoteSetError};
+
+/// Length of a view that follows a successful one.
+const DEFAULT_VIEW_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Each consecutive failed view doubles the next view's length, at most this many times.
+const MAX_VIEW_TIMEOUT_DOUBLINGS: u32 = 6;
+
+/// How long to wait before polling the network again when no message is pending.
+const MESSAGE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Store key of the safety state, see `SafetyState`.
+const SAFETY_STATE_KEY: &str = "state/hotstuff_safety";
+
+/// View number. Each view has a single leader and certifies at most one block.
+/// Votes carry their view in `round`.
+pub type View = Round;
+
+/// A block proposed by the leader of a view.
+/// Quorum certificates are `Commit`s: the aggregated votes of more than two thirds of the voting
+/// power for a block, with the view the block was proposed in as their round.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HotStuffProposal {
+    pub view: View,
+    pub block: Block,
+    /// Certificate of the block `block` extends; the highest certificate the leader knows of.
+    pub justify: Commit,
+    pub proposer_id: String,
+    pub signature: Vec<u8>,
+}
+
+impl HotStuffProposal {
+    /// Returns the bytes covered by the proposal signature.
+    pub fn sign_bytes(&self) -> Vec<u8> {
+        let block_hash = crypto_utils::hash_block(&self.block);
+        serde_json::to_vec(&("hotstuff-proposal", self.view, block_hash, self.justify.round, self.justify.block_hash))
+            .expect("proposal fields are serializable")
+    }
+}
+
+/// Sent to the leader of `view` when the previous view timed out, carrying the sender's
+/// highest certificate so that the leader can extend it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NewView {
+    pub view: View,
+    pub high_qc: Commit,
+    pub validator_id: String,
+    pub signature: Vec<u8>,
+}
+
+impl NewView {
+    /// Returns the bytes covered by the message signature.
+    pub fn sign_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&("new-view", self.view, self.high_qc.height, self.high_qc.round, self.high_qc.block_hash))
+            .expect("new-view fields are serializable")
+    }
+}
+
+/// What a validator must remember across restarts so that it never votes against its lock,
+/// votes twice in a view or proposes twice in a view.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SafetyState {
+    high_qc: Commit,
+    locked_qc: Commit,
+    last_voted_view: View,
+    last_proposed_view: View,
+}
+
+/// A proposed block that has not been committed yet.
+struct PendingBlock {
+    block: Block,
+    height: Height,
+    /// Certificate of the block's parent.
+    justify: Commit,
+}
+
+/// Chained HotStuff engine.
+/// Validators send their vote for a view's block to the leader of the next view only, and a
+/// timed-out view is abandoned by sending the highest certificate to the next leader, so both
+/// the normal case and view changes cost a linear number of messages.
+///
+/// Validator set changes take effect when the last block of an epoch is committed, and jails and
+/// unjails when the block that carries them is. Blocks above it, which the outgoing validators
+/// kept certifying meanwhile, are dropped without being applied, and the new set extends the
+/// commit of that block. Every certificate above the last finalized block is therefore signed by
+/// the current active set.
+///
+/// With checkpoint attestations enabled, each validator also attests to every checkpoint block
+/// it commits, from the last justified checkpoint, and counts the attestations of the others, so
+/// that checkpoints are finalized by the whole active set, see `finality_gadget`.
+///
+/// With a store attached, the lock, the highest certificate and the last voted and proposed views
+/// are written to it before every vote and proposal, so a restarted validator resumes with them.
+pub struct HotStuffEngine {
+    validator_set: ValidatorSet,
+    consensus_state: ConsensusState,
+    network_communication: NetworkCommunication,
+    transaction_verifier: TransactionVerifier,
+    block_proposal: BlockProposal,
+    identity: ValidatorIdentity,
+    view_timeout: Duration,
+    view: View,
+    view_deadline: Instant,
+    /// Number of consecutive views that timed out.
+    failed_views: u32,
+    /// Proposed blocks above the last finalized block, by hash.
+    blocks: HashMap<Hash, PendingBlock>,
+    /// Highest certificate seen; the next proposal of this node extends its block.
+    high_qc: Commit,
+    /// Certificate of the block this node is locked on. It only votes for blocks extending it,
+    /// unless a proposal carries a newer certificate.
+    locked_qc: Commit,
+    last_voted_view: View,
+    last_proposed_view: View,
+    /// Votes received as leader of the next view, by height and view, so that votes claiming
+    /// another height than the block's cannot take the place of the block's votes.
+    votes: HashMap<(Height, View), VoteSet>,
+    /// Validators that moved to a view after a timeout, as reported to its leader.
+    new_views: HashMap<View, HashSet<String>>,
+    /// Whether this node attests to the checkpoints it commits, see `enable_checkpoint_attestations`.
+    attest_checkpoints: bool,
+    /// Store that finalized state and the safety state are written to, see `attach_store`.
+    store: Option<Box<dyn ConsensusStore>>,
+}
+
+impl HotStuffEngine {
+    /// Creates an engine continuing from the last block in `consensus_state`, whose commit serves
+    /// as the first certificate.
+    pub fn new(
+        validator_set: ValidatorSet,
+        consensus_state: ConsensusState,
+        network_communication: NetworkCommunication,
+        transaction_verifier: TransactionVerifier,
+        block_proposal: BlockProposal,
+        identity: ValidatorIdentity,
+    ) -> Self {
+        let root = root_certificate(&consensus_state);
+        HotStuffEngine {
+            validator_set,
+            consensus_state,
+            network_communication,
+            transaction_verifier,
+            block_proposal,
+            identity,
+            view_timeout: DEFAULT_VIEW_TIMEOUT,
+            view: root.round + 1,
+            view_deadline: Instant::now() + DEFAULT_VIEW_TIMEOUT,
+            failed_views: 0,
+            blocks: HashMap::new(),
+            high_qc: root.clone(),
+            last_voted_view: root.round,
+            last_proposed_view: root.round,
+            locked_qc: root,
+            votes: HashMap::new(),
+            new_views: HashMap::new(),
+            attest_checkpoints: false,
+            store: None,
+        }
+    }
+
+    /// Restores the consensus state and validator set last persisted to `store`, and the safety
+    /// state written before this node's last vote or proposal, if any. From now on every finalized
+    /// block is persisted to it, and the safety state before every vote and proposal.
+    pub fn attach_store(&mut self, mut store: Box<dyn ConsensusStore>) -> Result<(), StorageError> {
+        if let Some(validator_set) = self.consensus_state.restore(store.as_ref())? {
+            self.validator_set = validator_set;
+            let root = root_certificate(&self.consensus_state);
+            self.blocks.clear();
+            self.votes.clear();
+            self.new_views.clear();
+            self.view = root.round + 1;
+            self.high_qc = root.clone();
+            self.last_voted_view = root.round;
+            self.last_proposed_view = root.round;
+            self.locked_qc = root;
+        } else {
+            // A fresh store: record the active set of the first epoch, which no epoch change stores.
+            let active: Vec<_> = self.validator_set.get_active_validators().into_iter().cloned().collect();
+            store.put_validator_set(self.consensus_state.epoch_start_height(), &active)?;
+        }
+        if let Some(safety) = storage::get_json::<_, SafetyState>(store.as_ref(), SAFETY_STATE_KEY)? {
+            if safety.high_qc.round > self.high_qc.round {
+                self.high_qc = safety.high_qc;
+            }
+            // The locked block itself is lost, so until a certificate newer than the lock
+            // arrives the node votes for nothing.
+            if safety.locked_qc.round > self.locked_qc.round {
+                self.locked_qc = safety.locked_qc;
+            }
+            self.last_voted_view = self.last_voted_view.max(safety.last_voted_view);
+            self.last_proposed_view = self.last_proposed_view.max(safety.last_proposed_view);
+            self.view = self.view.max(self.last_voted_view.max(self.last_proposed_view) + 1);
+        }
+        self.store = Some(store);
+        Ok(())
+    }
+
+    /// Returns the current view.
+    pub fn view(&self) -> View {
+        self.view
+    }
+
+    /// Updates the length of views started from now on.
+    pub fn update_view_timeout(&mut self, view_timeout: Duration) {
+        self.view_timeout = view_timeout;
+    }
+
+    /// Makes this node attest to every checkpoint block it commits, as in the
+    /// `EngineKind::CheckpointedHotStuff` mode. Attestations of other validators are counted
+    /// either way.
+    pub fn enable_checkpoint_attestations(&mut self) {
+        self.attest_checkpoints = true;
+    }
+
+    /// Handles a proposal for the current or a later view.
+    /// The node votes for the block if it extends the locked block or carries a certificate newer
+    /// than the lock, then moves to the next view.
+    fn on_proposal(&mut self, proposal: HotStuffProposal) {
+        let view = proposal.view;
+        if view < self.view
+            || proposal.justify.round >= view
+            || self.leader(view).as_deref() != Some(proposal.proposer_id.as_str())
+            || !self.verify_signed(&proposal.proposer_id, &proposal.sign_bytes(), &proposal.signature)
+            || !self.verify_qc(&proposal.justify)
+        {
+            return;
+        }
+        // The certificate stands whatever the block. Processing it first finalizes the blocks it
+        // commits, which the leader did before proposing, so the state roots can be compared.
+        self.process_certificate(proposal.justify.clone());
+        if !self.validate_block(&proposal.block, &proposal.justify) {
+            return;
+        }
+
+        let block_hash = crypto_utils::hash_block(&proposal.block);
+        let height = proposal.justify.height + 1;
+        let _ = self.consensus_state.add_block(proposal.block.clone());
+        self.blocks.insert(
+            block_hash,
+            PendingBlock {
+                block: proposal.block,
+                height,
+                justify: proposal.justify.clone(),
+            },
+        );
+
+        let safe = self.extends(&block_hash, &self.locked_qc.block_hash) || proposal.justify.round > self.locked_qc.round;
+        if safe && view > self.last_voted_view && height > self.consensus_state.height() {
+            self.cast_vote(view, height, block_hash);
+        }
+        self.enter_view(view + 1, true);
+    }
+
+    /// Collects a vote as leader of the view after the vote's. Once the block has votes of
+    /// more than two thirds of the voting power, their certificate becomes the highest one and
+    /// the node proposes in the next view.
+    fn on_vote(&mut self, vote: Vote) {
+        let view = vote.round;
+        let block_hash = match vote.block_hash {
+            Some(block_hash) if vote.vote_type == VoteType::Precommit => block_hash,
+            _ => return,
+        };
+        if self.leader(view + 1).as_deref() != Some(self.identity.validator_id.as_str()) {
+            return;
+        }
+
+        let height = vote.height;
+        if height <= self.consensus_state.height() {
+            return;
+        }
+        let validator_set = &self.validator_set;
+        let votes = self
+            .votes
+            .entry((height, view))
+            .or_insert_with(|| VoteSet::new(height, view, VoteType::Precommit, validator_set));
+        match votes.add_vote(vote, validator_set) {
+            Ok(true) => {}
+            Err(VoteSetError::Conflicting(evidence)) => {
+                let _ = self.consensus_state.submit_evidence(Evidence::DuplicateVote(*evidence), validator_set);
+                return;
+            }
+            _ => return,
+        }
+        if self.high_qc.round >= view || !votes.has_two_thirds_for(Some(block_hash)) {
+            return;
+        }
+        let active: Vec<_> = validator_set.get_active_validators().into_iter().cloned().collect();
+        let qc = match QuorumCertificate::from_votes(height, view, block_hash, &votes.votes_for(Some(block_hash)), &active) {
+            Ok(qc) => qc,
+            Err(_) => return,
+        };
+        self.process_certificate(qc);
+        self.enter_view(view + 1, true);
+        self.try_propose();
+    }
+
+    /// Collects a new-view message as leader of its view, and proposes once validators holding
+    /// more than two thirds of the voting power have moved to the view.
+    fn on_new_view(&mut self, new_view: NewView) {
+        let view = new_view.view;
+        if view < self.view
+            || self.leader(view).as_deref() != Some(self.identity.validator_id.as_str())
+            || self.validator_set.voting_power(&new_view.validator_id) == 0
+            || !self.verify_signed(&new_view.validator_id, &new_view.sign_bytes(), &new_view.signature)
+            || !self.verify_qc(&new_view.high_qc)
+        {
+            return;
+        }
+        self.process_certificate(new_view.high_qc);
+        self.new_views.entry(view).or_default().insert(new_view.validator_id);
+        if self.view_change_complete(view) {
+            self.enter_view(view, false);
+            self.try_propose();
+        }
+    }
+
+    /// Gives up on the current view and sends the highest certificate to the next view's leader.
+    fn on_view_timeout(&mut self) {
+        self.enter_view(self.view + 1, false);
+        if self.validator_set.voting_power(&self.identity.validator_id) == 0 {
+            return;
+        }
+        let mut new_view = NewView {
+            view: self.view,
+            high_qc: self.high_qc.clone(),
+            validator_id: self.identity.validator_id.clone(),
+            signature: Vec::new(),
+        };
+        new_view.signature = crypto_utils::sign_message(&self.identity.keypair, &new_view.sign_bytes());
+        self.send_to_leader(self.view, ConsensusMessage::NewView(new_view));
+    }
+
+    /// Proposes a block extending the highest certificate if this node leads the current view and
+    /// either certified the previous view's block or completed a view change into this view.
+    fn try_propose(&mut self) {
+        let view = self.view;
+        if self.last_proposed_view >= view
+            || self.leader(view).as_deref() != Some(self.identity.validator_id.as_str())
+            || (self.high_qc.round + 1 != view && !self.view_change_complete(view))
+        {
+            return;
+        }
+        let transactions = self.network_communication.fetch_transactions();
+        let block = self.block_proposal.propose_block_on(
+            Some(&self.high_qc),
+            &self.consensus_state,
+            &self.validator_set,
+            transactions,
+            &self.identity.block_signer,
+            None,
+        );
+        self.last_proposed_view = view;
+        if !self.persist_safety_state() {
+            return;
+        }
+        let mut proposal = HotStuffProposal {
+            view,
+            block,
+            justify: self.high_qc.clone(),
+            proposer_id: self.identity.validator_id.clone(),
+            signature: Vec::new(),
+        };
+        proposal.signature = crypto_utils::sign_message(&self.identity.keypair, &proposal.sign_bytes());
+        self.network_communication
+            .broadcast_consensus_message(&ConsensusMessage::HotStuffProposal(proposal.clone()));
+        self.on_proposal(proposal);
+    }
+
+    /// Signs a vote for a block and sends it to the leader of the next view.
+    fn cast_vote(&mut self, view: View, height: Height, block_hash: Hash) {
+        if self.validator_set.voting_power(&self.identity.validator_id) == 0 {
+            return;
+        }
+        self.last_voted_view = view;
+        if !self.persist_safety_state() {
+            return;
+        }
+        let mut vote = Vote {
+            vote_type: VoteType::Precommit,
+            height,
+            round: view,
+            block_hash: Some(block_hash),
+            validator_id: self.identity.validator_id.clone(),
+            signature: Vec::new(),
+            bls_signature: Vec::new(),
+        };
+        vote.signature = crypto_utils::sign_message(&self.identity.keypair, &vote.sign_bytes());
+        vote.bls_signature = crypto_utils::bls_sign(&self.identity.bls_key, &vote.sign_bytes());
+        self.send_to_leader(view + 1, ConsensusMessage::Vote(vote));
+    }
+
+    /// Sends a message to the leader of `view`, handling it directly if this node is the leader.
+    fn send_to_leader(&mut self, view: View, message: ConsensusMessage) {
+        match self.leader(view) {
+            Some(leader) if leader == self.identity.validator_id => self.handle_message(message),
+            Some(leader) => self.network_communication.send_consensus_message(&leader, &message),
+            None => {}
+        }
+    }
+
+    /// Updates the highest certificate, the lock and the finalized chain from a certificate.
+    /// With `qc` certifying a block b2 whose certificate is for b1, whose certificate is for b0,
+    /// the node locks on b1, and commits b0 and its ancestors if b0, b1 and b2 were proposed in
+    /// consecutive views.
+    fn process_certificate(&mut self, qc: Commit) {
+        if qc.round > self.high_qc.round {
+            self.high_qc = qc.clone();
+        }
+        let qc1 = match self.blocks.get(&qc.block_hash) {
+            Some(b2) => b2.justify.clone(),
+            None => return,
+        };
+        if qc1.round > self.locked_qc.round {
+            self.locked_qc = qc1.clone();
+        }
+        let qc0 = match self.blocks.get(&qc1.block_hash) {
+            Some(b1) => b1.justify.clone(),
+            None => return,
+        };
+        if qc.round == qc1.round + 1 && qc1.round == qc0.round + 1 {
+            self.commit_chain(qc0);
+        }
+    }
+
+    /// Finalizes the block certified by `qc` and its uncommitted ancestors, oldest first.
+    fn commit_chain(&mut self, qc: Commit) {
+        let root = self.consensus_state.get_latest_block_hash();
+        let mut chain = Vec::new();
+        let mut certificate = qc;
+        while certificate.block_hash != root {
+            let parent_certificate = match self.blocks.get(&certificate.block_hash) {
+                Some(pending) if pending.height > self.consensus_state.height() => pending.justify.clone(),
+                // The chain does not connect to the finalized block.
+                _ => return,
+            };
+            chain.push(certificate);
+            certificate = parent_certificate;
+        }
+
+        for certificate in chain.into_iter().rev() {
+            let block = match self.blocks.remove(&certificate.block_hash) {
+                Some(pending) => pending.block,
+                None => return,
+            };
+            let (height, block_hash) = (certificate.height, certificate.block_hash);
+            let set_changed =
+                consensus_engine::apply_finalized_block(&mut self.validator_set, &mut self.consensus_state, block, certificate);
+            // A height that fails to persist is covered by the record of the next one.
+            let _ = self.persist_state(height, set_changed);
+            self.attest_checkpoint(height, block_hash);
+            if set_changed {
+                self.restart_with_new_set();
+                return;
+            }
+        }
+        let height = self.consensus_state.height();
+        self.blocks.retain(|_, pending| pending.height > height);
+    }
+
+    /// Restarts from the block that changed the active set, at the end of an epoch or by jailing
+    /// or unjailing a validator. Pending blocks above it were certified by the previous active set
+    /// and are dropped, so the lock and the highest certificate fall back to the commit of the
+    /// finalized block.
+    fn restart_with_new_set(&mut self) {
+        let root = root_certificate(&self.consensus_state);
+        self.blocks.clear();
+        self.votes.clear();
+        self.new_views.clear();
+        self.high_qc = root.clone();
+        self.locked_qc = root;
+        let _ = self.persist_safety_state();
+    }
+
+    /// Attests to the committed block at `height` if it is a checkpoint and attestations are
+    /// enabled. The source is the last justified checkpoint, which never moves back, and each
+    /// checkpoint is committed once, so this node never signs a double or surround vote.
+    fn attest_checkpoint(&mut self, height: Height, block_hash: Hash) {
+        let epoch = match self.consensus_state.finality_gadget().checkpoint_epoch(height) {
+            Some(epoch) if self.attest_checkpoints => epoch,
+            _ => return,
+        };
+        if self.validator_set.voting_power(&self.identity.validator_id) == 0 {
+            return;
+        }
+        let mut attestation = Attestation {
+            validator_id: self.identity.validator_id.clone(),
+            source: self.consensus_state.finality_gadget().last_justified(),
+            target: Checkpoint {
+                epoch,
+                height,
+                hash: block_hash,
+            },
+            signature: Vec::new(),
+        };
+        attestation.signature = crypto_utils::sign_message(&self.identity.keypair, &attestation.sign_bytes());
+        self.network_communication
+            .broadcast_consensus_message(&ConsensusMessage::Attestation(attestation.clone()));
+        let _ = self.consensus_state.process_attestation(attestation, &self.validator_set);
+    }
+
+    /// Writes the state resulting from finalizing `height` to the attached store, if any, the
+    /// same way `PosAlgorithm` does.
+    fn persist_state(&mut self, height: Height, set_changed: bool) -> Result<(), StorageError> {
+        let store = match &mut self.store {
+            Some(store) => store.as_mut(),
+            None => return Ok(()),
+        };
+        if set_changed {
+            let active: Vec<_> = self.validator_set.get_active_validators().into_iter().cloned().collect();
+            store.put_validator_set(height + 1, &active)?;
+        }
+        store.put_stake_snapshot(&self.validator_set.stake_manager().get_current_stake_snapshot())?;
+        self.consensus_state.persist(store, &self.validator_set)
+    }
+
+    /// Writes the safety state to the attached store, if any.
+    /// Returns false if it could not be made durable, in which case the caller must not sign.
+    fn persist_safety_state(&mut self) -> bool {
+        let store = match &mut self.store {
+            Some(store) => store.as_mut(),
+            None => return true,
+        };
+        let safety = SafetyState {
+            high_qc: self.high_qc.clone(),
+            locked_qc: self.locked_qc.clone(),
+            last_voted_view: self.last_voted_view,
+            last_proposed_view: self.last_proposed_view,
+        };
+        storage::put_json(store, SAFETY_STATE_KEY, &safety).is_ok()
+    }
+
+    /// Moves to a later view and restarts the view timer. The timer doubles with every
+    /// consecutive view entered without progress.
+    fn enter_view(&mut self, view: View, progressed: bool) {
+        if view <= self.view {
+            return;
+        }
+        self.view = view;
+        self.failed_views = if progressed { 0 } else { self.failed_views + 1 };
+        let doublings = self.failed_views.min(MAX_VIEW_TIMEOUT_DOUBLINGS);
+        self.view_deadline = Instant::now() + self.view_timeout * 2u32.pow(doublings);
+        self.votes.retain(|(_, vote_view), _| *vote_view + 1 >= view);
+        self.new_views.retain(|new_view, _| *new_view >= view);
+    }
+
+    /// Returns true if validators holding more than two thirds of the voting power moved to `view`.
+    fn view_change_complete(&self, view: View) -> bool {
+        self.new_views.get(&view).map_or(false, |senders| {
+            self.is_quorum(senders.iter().map(|id| self.validator_set.voting_power(id)).sum())
+        })
+    }
+
+    /// Returns the leader of `view`, drawn from the epoch seed with probability proportional
+    /// to voting power, so every node derives it from the view alone.
+    fn leader(&self, view: View) -> Option<String> {
+        let mut validators = self.validator_set.get_active_validators();
+        validators.sort_by(|a, b| a.id.cmp(&b.id));
+        let total: u64 = validators.iter().map(|validator| validator.stake).sum();
+        if total == 0 {
+            return None;
+        }
+        let digest = crypto_utils::hash_bytes(&[&self.validator_set.epoch_seed()[..], &view.to_be_bytes()].concat());
+        let mut ticket = u64::from_be_bytes(digest[..8].try_into().expect("digest has 32 bytes")) % total;
+        for validator in validators {
+            if ticket < validator.stake {
+                return Some(validator.id.clone());
+            }
+            ticket -= validator.stake;
+        }
+        None
+    }
+
+    /// Returns true if `qc` certifies its block against the validators in force at its height:
+    /// the certificate of the last finalized block is checked against the set that finalized it,
+    /// which differs from the current one once that block changed the active set, and certificates of
+    /// blocks above it against the current active set.
+    fn verify_qc(&self, qc: &Commit) -> bool {
+        let height = self.consensus_state.height();
+        if qc.height < height {
+            return false;
+        }
+        if qc.height == height {
+            if qc.block_hash != self.consensus_state.get_latest_block_hash() {
+                return false;
+            }
+            // Genesis is certified by no one.
+            return match self.consensus_state.get_last_commit() {
+                Some(_) => qc.verify(self.consensus_state.last_validators()).is_ok(),
+                None => *qc == root_certificate(&self.consensus_state),
+            };
+        }
+        let active: Vec<_> = self.validator_set.get_active_validators().into_iter().cloned().collect();
+        qc.verify(&active).is_ok()
+    }
+
+    /// Checks that a block extends the block certified by `justify` and carries `justify` as its
+    /// last commit, that its timestamp follows that block's without running ahead of local time,
+    /// that its state root is the root of the finalized state once `justify` has been processed,
+    /// and that its transactions and evidence are valid.
+    fn validate_block(&self, block: &Block, justify: &Commit) -> bool {
+        // Only genesis has no timestamp to follow.
+        let parent_timestamp = self.consensus_state.block_timestamp(&justify.block_hash);
+        block.header.previous_hash == justify.block_hash
+            && block.last_commit.as_ref() == Some(justify)
+            && (parent_timestamp.is_some() || justify.height == 0)
+            && consensus_engine::valid_block_time(block.header.timestamp, parent_timestamp)
+            && block.header.state_root == self.consensus_state.compute_state_root(&self.validator_set)
+            && block
+                .transactions
+                .iter()
+                .all(|tx| self.transaction_verifier.verify_transaction(tx).is_ok())
+            && block
+                .evidence
+                .iter()
+                .all(|evidence| self.consensus_state.check_evidence(evidence, &self.validator_set).is_ok())
+    }
+
+    /// Returns true if the block `hash` is `ancestor` or descends from it.
+    fn extends(&self, hash: &Hash, ancestor: &Hash) -> bool {
+        let mut current = *hash;
+        loop {
+            if current == *ancestor {
+                return true;
+            }
+            match self.blocks.get(&current) {
+                Some(pending) => current = pending.block.header.previous_hash,
+                None => return false,
+            }
+        }
+    }
+
+    /// Returns true if a validator's signature over `message` is valid.
+    fn verify_signed(&self, validator_id: &str, message: &[u8], signature: &[u8]) -> bool {
+        self.validator_set
+            .get_validator(validator_id)
+            .map_or(false, |validator| crypto_utils::verify_signature(&validator.public_key, message, signature))
+    }
+
+    /// Returns true if `power` is strictly more than two thirds of the total voting power.
+    fn is_quorum(&self, power: u64) -> bool {
+        power as u128 * 3 > self.validator_set.total_voting_power() as u128 * 2
+    }
+}
+
+/// Returns the commit of the last finalized block, the first certificate an engine extends.
+/// Genesis has no commit and gets an unsigned certificate.
+fn root_certificate(consensus_state: &ConsensusState) -> Commit {
+    consensus_state
+        .get_last_commit()
+        .cloned()
+        .unwrap_or_else(|| QuorumCertificate::unsigned(consensus_state.height(), 0, consensus_state.get_latest_block_hash()))
+}
+
+impl ConsensusEngine for HotStuffEngine {
+    /// Runs views until a block is finalized.
+    fn run_consensus_round(&mut self) {
+        let height = self.consensus_state.height();
+        self.try_propose();
+        while self.consensus_state.height() == height {
+            if Instant::now() >= self.view_deadline {
+                self.on_view_timeout();
+                continue;
+            }
+            match self.network_communication.receive_consensus_message() {
+                Some(message) => self.handle_message(message),
+                None => thread::sleep(time_utils::time_until(self.view_deadline).min(MESSAGE_POLL_INTERVAL)),
+            }
+        }
+    }
+
+    fn handle_message(&mut self, message: ConsensusMessage) {
+        match message {
+            ConsensusMessage::HotStuffProposal(proposal) => self.on_proposal(proposal),
+            ConsensusMessage::Vote(vote) => self.on_vote(vote),
+            ConsensusMessage::NewView(new_view) => self.on_new_view(new_view),
+            ConsensusMessage::Attestation(attestation) => {
+                let _ = self.consensus_state.process_attestation(attestation, &self.validator_set);
+            }
+            ConsensusMessage::Proposal(_) => {}
+        }
+    }
+
+    fn consensus_state(&self) -> &ConsensusState {
+        &self.consensus_state
+    }
+
+    fn validator_set(&self) -> &ValidatorSet {
+        &self.validator_set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use schnorrkel::{ExpansionMode, Keypair, MiniSecretKey};
+    use crate::stake_manager::StakeManager;
+    use crate::state::BlockchainState;
+    use crate::utilities::crypto_utils::BlsSecretKey;
+    use crate::validator_set::{BlsKeyRegistration, Commission};
+
+    /// Validators `a` to `d` are active from genesis; `e` joins in some tests.
+    const IDS: [&str; 5] = ["a", "b", "c", "d", "e"];
+
+    fn keypair(index: usize) -> Keypair {
+        MiniSecretKey::from_bytes(&[index as u8 + 1; 32]).unwrap().expand_to_keypair(ExpansionMode::Ed25519)
+    }
+
+    fn bls_key(index: usize) -> BlsSecretKey {
+        BlsSecretKey::key_gen(&[index as u8 + 1; 32], &[]).unwrap()
+    }
+
+    /// Four active validators of equal stake with registered BLS keys.
+    fn validator_set() -> ValidatorSet {
+        let mut set = ValidatorSet::new(StakeManager::new());
+        for (index, id) in IDS.iter().enumerate().take(4) {
+            set.add_validator(id.to_string(), 10, keypair(index).public.to_bytes().to_vec(), Commission::default()).unwrap();
+            set.update_validator_status(id, true);
+            set.register_bls_key(&BlsKeyRegistration::sign(id, Vec::new(), &bls_key(index), &keypair(index)))
+                .unwrap();
+        }
+        set
+    }
+
+    /// An engine at genesis run by validator `a`, without a store.
+    fn engine() -> HotStuffEngine {
+        let identity = ValidatorIdentity {
+            validator_id: "a".to_string(),
+            block_signer: blockchain_types::Validator::new(keypair(0).public.to_bytes().to_vec()),
+            keypair: keypair(0),
+            bls_key: bls_key(0),
+        };
+        HotStuffEngine::new(
+            validator_set(),
+            ConsensusState::new(&validator_set()),
+            NetworkCommunication::new("127.0.0.1", 0),
+            TransactionVerifier::new(BlockchainState::default()),
+            BlockProposal::new(TransactionVerifier::new(BlockchainState::default())),
+            identity,
+        )
+    }
+
+    /// A certificate of the block `block_hash` at `height`, proposed in `view`, signed by `b`, `c` and `d`.
+    fn certificate(engine: &HotStuffEngine, height: Height, view: View, block_hash: Hash) -> Commit {
+        certificate_by(engine, &[1, 2, 3], height, view, block_hash)
+    }
+
+    /// A certificate signed by `IDS[index]` for each of `signers`, over the current active set.
+    fn certificate_by(engine: &HotStuffEngine, signers: &[usize], height: Height, view: View, block_hash: Hash) -> Commit {
+        let votes: Vec<Vote> = signers
+            .iter()
+            .map(|&index| Vote {
+                vote_type: VoteType::Precommit,
+                height,
+                round: view,
+                block_hash: Some(block_hash),
+                validator_id: IDS[index].to_string(),
+                signature: Vec::new(),
+                bls_signature: crypto_utils::bls_sign(
+                    &bls_key(index),
+                    &Vote::sign_bytes_for(VoteType::Precommit, height, view, Some(block_hash)),
+                ),
+            })
+            .collect();
+        let active: Vec<_> = engine.validator_set.get_active_validators().into_iter().cloned().collect();
+        QuorumCertificate::from_votes(height, view, block_hash, &votes, &active).unwrap()
+    }
+
+    /// Delivers a proposal by the leader of `view` of a block extending `justify`, and returns the
+    /// block's hash. Blocks of different views differ in their timestamp.
+    fn propose(engine: &mut HotStuffEngine, view: View, justify: &Commit) -> Hash {
+        let mut block = engine.block_proposal.propose_block_on(
+            Some(justify),
+            &engine.consensus_state,
+            &engine.validator_set,
+            Vec::new(),
+            &engine.identity.block_signer,
+            None,
+        );
+        block.header.timestamp += view as u64;
+        block.set_hash(crypto_utils::hash_block(&block));
+        let block_hash = crypto_utils::hash_block(&block);
+
+        let leader = engine.leader(view).unwrap();
+        let index = IDS.iter().position(|id| *id == leader).unwrap();
+        let mut proposal = HotStuffProposal {
+            view,
+            block,
+            justify: justify.clone(),
+            proposer_id: leader,
+            signature: Vec::new(),
+        };
+        proposal.signature = crypto_utils::sign_message(&keypair(index), &proposal.sign_bytes());
+        engine.handle_message(ConsensusMessage::HotStuffProposal(proposal));
+        block_hash
+    }
+
+    /// Proposes a block extending `justify` in `view`, and replaces `justify` with its certificate.
+    /// Returns the block as a checkpoint, for a checkpoint interval of one block.
+    fn extend_chain(engine: &mut HotStuffEngine, view: View, justify: &mut Commit) -> Checkpoint {
+        let height = justify.height + 1;
+        let hash = propose(engine, view, justify);
+        *justify = certificate(engine, height, view, hash);
+        Checkpoint {
+            epoch: height,
+            height,
+            hash,
+        }
+    }
+
+    /// An attestation signed by `IDS[index]` to the link from `source` to `target`.
+    fn attestation(index: usize, source: Checkpoint, target: Checkpoint) -> ConsensusMessage {
+        let mut attestation = Attestation {
+            validator_id: IDS[index].to_string(),
+            source,
+            target,
+            signature: Vec::new(),
+        };
+        attestation.signature = crypto_utils::sign_message(&keypair(index), &attestation.sign_bytes());
+        ConsensusMessage::Attestation(attestation)
+    }
+
+    #[test]
+    fn test_three_chain_of_consecutive_views_commits_its_first_block() {
+        let mut engine = engine();
+        let root = engine.high_qc.clone();
+        let first = propose(&mut engine, 1, &root);
+        let first_qc = certificate(&engine, 1, 1, first);
+        let second = propose(&mut engine, 2, &first_qc);
+        let second_qc = certificate(&engine, 2, 2, second);
+        let third = propose(&mut engine, 3, &second_qc);
+        assert_eq!(engine.locked_qc, first_qc);
+        assert_eq!(engine.consensus_state.height(), 0);
+
+        // The third block's certificate completes the chain first <- second <- third.
+        engine.process_certificate(certificate(&engine, 3, 3, third));
+        assert_eq!(engine.consensus_state.height(), 1);
+        assert_eq!(engine.consensus_state.get_latest_block_hash(), first);
+        assert_eq!(engine.consensus_state.get_last_commit(), Some(&first_qc));
+        assert_eq!(engine.locked_qc, second_qc);
+    }
+
+    #[test]
+    fn test_locked_node_votes_only_for_blocks_extending_its_lock() {
+        let mut engine = engine();
+        let root = engine.high_qc.clone();
+        let first = propose(&mut engine, 1, &root);
+        let first_qc = certificate(&engine, 1, 1, first);
+        let second = propose(&mut engine, 2, &first_qc);
+        propose(&mut engine, 3, &certificate(&engine, 2, 2, second));
+        assert_eq!(engine.locked_qc, first_qc);
+        assert_eq!(engine.last_voted_view, 3);
+
+        // A fork from genesis neither extends the locked block nor carries a newer certificate.
+        propose(&mut engine, 4, &root);
+        assert_eq!(engine.last_voted_view, 3);
+
+        propose(&mut engine, 5, &first_qc);
+        assert_eq!(engine.last_voted_view, 5);
+    }
+
+    #[test]
+    fn test_leader_proposes_once_a_quorum_moved_to_its_view() {
+        let mut engine = engine();
+        let view = (2..).find(|view| engine.leader(*view).as_deref() == Some("a")).unwrap();
+        let root = engine.high_qc.clone();
+        for index in 1..=3 {
+            // `b` and `c` hold two thirds of the voting power, which is not enough.
+            assert_eq!(engine.last_proposed_view, 0);
+            let mut new_view = NewView {
+                view,
+                high_qc: root.clone(),
+                validator_id: IDS[index].to_string(),
+                signature: Vec::new(),
+            };
+            new_view.signature = crypto_utils::sign_message(&keypair(index), &new_view.sign_bytes());
+            engine.handle_message(ConsensusMessage::NewView(new_view));
+        }
+        assert_eq!(engine.last_proposed_view, view);
+        assert_eq!(engine.last_voted_view, view);
+        assert_eq!(engine.view(), view + 1);
+    }
+
+    #[test]
+    fn test_epoch_change_restarts_from_the_epoch_end_with_the_new_set() {
+        let mut engine = engine();
+        engine.consensus_state.set_epoch_length(1);
+        let public_key = keypair(4).public.to_bytes().to_vec();
+        engine.validator_set.add_validator("e".to_string(), 10, public_key, Commission::default()).unwrap();
+        engine
+            .validator_set
+            .register_bls_key(&BlsKeyRegistration::sign("e", Vec::new(), &bls_key(4), &keypair(4)))
+            .unwrap();
+
+        // Height 1 ends the epoch; the blocks above it are certified by `a` to `d`.
+        let root = engine.high_qc.clone();
+        let first = propose(&mut engine, 1, &root);
+        let first_qc = certificate(&engine, 1, 1, first);
+        let second = propose(&mut engine, 2, &first_qc);
+        let second_qc = certificate(&engine, 2, 2, second);
+        let third = propose(&mut engine, 3, &second_qc);
+        engine.process_certificate(certificate(&engine, 3, 3, third));
+
+        assert_eq!(engine.consensus_state.height(), 1);
+        assert_eq!(engine.consensus_state.current_epoch(), 1);
+        assert_eq!(engine.validator_set.voting_power("e"), 10);
+        assert!(engine.blocks.is_empty());
+        assert_eq!(engine.high_qc, first_qc);
+        assert_eq!(engine.locked_qc, first_qc);
+        // The epoch's last commit lacks a quorum of the new set but is checked against the old one.
+        let active: Vec<_> = engine.validator_set.get_active_validators().into_iter().cloned().collect();
+        assert!(first_qc.verify(&active).is_err());
+        assert!(engine.verify_qc(&first_qc));
+        assert!(!engine.verify_qc(&second_qc));
+
+        // The new epoch extends the finalized block, certified by the new set.
+        let next = propose(&mut engine, 4, &first_qc);
+        assert_eq!(engine.last_voted_view, 4);
+        let next_qc = certificate_by(&engine, &[1, 2, 3, 4], 2, 4, next);
+        assert!(engine.verify_qc(&next_qc));
+        propose(&mut engine, 5, &next_qc);
+        assert_eq!(engine.last_voted_view, 5);
+    }
+
+    #[test]
+    fn test_committed_checkpoints_are_attested_and_finalized() {
+        let mut engine = engine();
+        engine.enable_checkpoint_attestations();
+        engine.consensus_state.set_checkpoint_interval(1);
+        let genesis = engine.consensus_state.finality_gadget().finalized();
+        let mut justify = engine.high_qc.clone();
+        let first = extend_chain(&mut engine, 1, &mut justify);
+        let second = extend_chain(&mut engine, 2, &mut justify);
+        let third = extend_chain(&mut engine, 3, &mut justify);
+
+        // `b` and `c` attest to the first checkpoint before it is committed here; their
+        // attestations are held, and counted with the one `a` makes when it commits it.
+        for index in [1, 2] {
+            engine.handle_message(attestation(index, genesis, first));
+        }
+        assert_eq!(engine.consensus_state.finality_gadget().last_justified(), genesis);
+        extend_chain(&mut engine, 4, &mut justify);
+        assert_eq!(engine.consensus_state.height(), 1);
+        assert_eq!(engine.consensus_state.finality_gadget().last_justified(), first);
+
+        // Justifying the second checkpoint from the first finalizes the first.
+        for index in [1, 2] {
+            engine.handle_message(attestation(index, first, second));
+        }
+        extend_chain(&mut engine, 5, &mut justify);
+        assert_eq!(engine.consensus_state.finality_gadget().last_justified(), second);
+        assert_eq!(engine.consensus_state.finality_gadget().finalized(), first);
+
+        // A link from genesis to the third checkpoint surrounds `b`'s link from the first to the
+        // second, so it is reported for slashing in the next block.
+        engine.handle_message(attestation(1, genesis, third));
+        extend_chain(&mut engine, 6, &mut justify);
+        assert_eq!(engine.consensus_state.height(), 3);
+        let evidence = engine.consensus_state.pending_evidence();
+        assert_eq!(evidence.len(), 1);
+        assert!(matches!(&evidence[0], Evidence::ConflictingAttestation(e) if e.attestation_b.validator_id == "b"));
+        let next = engine.block_proposal.propose_block_on(
+            Some(&justify),
+            &engine.consensus_state,
+            &engine.validator_set,
+            Vec::new(),
+            &engine.identity.block_signer,
+            None,
+        );
+        assert_eq!(next.evidence.len(), 1);
+    }
+}
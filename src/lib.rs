@@ -1 +1,22 @@
 // Library root, exposing the consensus module's functionality.
+
+pub mod block_proposal;
+pub mod consensus_engine;
+pub mod consensus_state;
+pub mod evidence;
+pub mod finality_gadget;
+pub mod fork_choice;
+pub mod governance;
+pub mod hotstuff;
+pub mod monetary_policy;
+pub mod network_communication;
+pub mod pos_algorithm;
+pub mod quorum_certificate;
+pub mod stake_manager;
+pub mod staking_transaction;
+pub mod storage;
+pub mod transaction_verifier;
+pub mod utilities;
+pub mod validator_set;
+pub mod vote_set;
+pub mod wal;
@@ -0,0 +1,221 @@
+// monetary_policy.rs
+// Mints block rewards under an inflation schedule that tracks a target bonded ratio.
+
+use std::str::FromStr;
+use big_decimal::{BigDecimal, RoundingMode};
+use serde::{Serialize, Deserialize};
+
+/// Number of decimal places kept for the inflation rate.
+const INFLATION_SCALE: i64 = 18;
+
+/// Parameters of the inflation schedule.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MintParams {
+    /// Largest yearly change of the inflation rate, reached when nothing is bonded.
+    pub inflation_rate_change: BigDecimal,
+    pub inflation_max: BigDecimal,
+    pub inflation_min: BigDecimal,
+    /// Fraction of the supply the schedule aims to have bonded.
+    pub goal_bonded: BigDecimal,
+    /// Expected number of blocks per year, used to turn yearly amounts into per-block ones.
+    pub blocks_per_year: u64,
+}
+
+impl Default for MintParams {
+    fn default() -> Self {
+        let decimal = |value: &str| BigDecimal::from_str(value).expect("valid decimal");
+        MintParams {
+            inflation_rate_change: decimal("0.13"),
+            inflation_max: decimal("0.20"),
+            inflation_min: decimal("0.07"),
+            goal_bonded: decimal("0.67"),
+            blocks_per_year: 6_311_520,
+        }
+    }
+}
+
+impl MintParams {
+    /// Checks that the rates are fractions, `inflation_min <= inflation_max`,
+    /// the bonded goal is positive and a year has at least one block.
+    pub fn validate(&self) -> Result<(), MintError> {
+        let zero = BigDecimal::from(0);
+        let one = BigDecimal::from(1);
+        let is_fraction = |value: &BigDecimal| *value >= zero && *value <= one;
+        if !is_fraction(&self.inflation_rate_change)
+            || !is_fraction(&self.inflation_min)
+            || !is_fraction(&self.inflation_max)
+            || self.inflation_min > self.inflation_max
+        {
+            return Err(MintError::InvalidInflationBounds);
+        }
+        if self.goal_bonded <= zero || self.goal_bonded > one {
+            return Err(MintError::InvalidGoalBonded);
+        }
+        if self.blocks_per_year == 0 {
+            return Err(MintError::ZeroBlocksPerYear);
+        }
+        Ok(())
+    }
+}
+
+/// Errors returned when configuring the minter.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MintError {
+    InvalidInflationBounds,
+    InvalidGoalBonded,
+    ZeroBlocksPerYear,
+}
+
+/// Tracks the token supply and mints the provision of each block.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Minter {
+    params: MintParams,
+    /// Current annual inflation rate.
+    inflation: BigDecimal,
+    /// Tokens expected to be minted over a year at the current rate and supply.
+    annual_provisions: BigDecimal,
+    total_supply: BigDecimal,
+}
+
+impl Minter {
+    /// Creates a minter for the given genesis supply, starting at the minimum inflation rate.
+    pub fn new(params: MintParams, total_supply: BigDecimal) -> Result<Self, MintError> {
+        params.validate()?;
+        Ok(Minter {
+            inflation: params.inflation_min.clone(),
+            annual_provisions: BigDecimal::from(0),
+            params,
+            total_supply,
+        })
+    }
+
+    /// Returns the inflation schedule parameters.
+    pub fn params(&self) -> &MintParams {
+        &self.params
+    }
+
+    /// Replaces the inflation schedule parameters. The current rate is pulled into the new bounds
+    /// on the next block.
+    pub fn set_params(&mut self, params: MintParams) -> Result<(), MintError> {
+        params.validate()?;
+        self.params = params;
+        Ok(())
+    }
+
+    /// Returns the current annual inflation rate.
+    pub fn inflation(&self) -> &BigDecimal {
+        &self.inflation
+    }
+
+    /// Returns the tokens expected to be minted over a year at the current rate.
+    pub fn annual_provisions(&self) -> &BigDecimal {
+        &self.annual_provisions
+    }
+
+    /// Returns the total token supply, including everything minted so far.
+    pub fn total_supply(&self) -> &BigDecimal {
+        &self.total_supply
+    }
+
+    /// Adjusts the inflation rate for the bonded stake, mints the block provision and returns it.
+    /// The rate moves up while less than `goal_bonded` of the supply is bonded and down while
+    /// more is, by at most `inflation_rate_change` per year, and stays within the configured bounds.
+    pub fn mint_block(&mut self, bonded_tokens: &BigDecimal) -> BigDecimal {
+        self.inflation = self.next_inflation_rate(bonded_tokens);
+        self.annual_provisions = &self.inflation * &self.total_supply;
+        let provision = self.block_provision();
+        self.total_supply += &provision;
+        provision
+    }
+
+    /// Removes tokens that were minted but not paid to anyone from the supply.
+    pub fn burn(&mut self, amount: &BigDecimal) {
+        self.total_supply -= amount;
+    }
+
+    /// Returns the inflation rate for the next block given the bonded stake.
+    fn next_inflation_rate(&self, bonded_tokens: &BigDecimal) -> BigDecimal {
+        let bonded_ratio = if self.total_supply == BigDecimal::from(0) {
+            BigDecimal::from(0)
+        } else {
+            bonded_tokens / &self.total_supply
+        };
+        let change_per_year =
+            (BigDecimal::from(1) - bonded_ratio / &self.params.goal_bonded) * &self.params.inflation_rate_change;
+        let change = change_per_year / BigDecimal::from(self.params.blocks_per_year);
+        (&self.inflation + change)
+            .with_scale_round(INFLATION_SCALE, RoundingMode::Down)
+            .max(self.params.inflation_min.clone())
+            .min(self.params.inflation_max.clone())
+    }
+
+    /// Returns the whole tokens minted per block at the current annual provisions.
+    pub fn block_provision(&self) -> BigDecimal {
+        (&self.annual_provisions / BigDecimal::from(self.params.blocks_per_year)).with_scale_round(0, RoundingMode::Down)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(value: &str) -> BigDecimal {
+        BigDecimal::from_str(value).unwrap()
+    }
+
+    fn params(blocks_per_year: u64) -> MintParams {
+        MintParams {
+            inflation_rate_change: dec("0.1"),
+            inflation_max: dec("0.2"),
+            inflation_min: dec("0.05"),
+            goal_bonded: dec("0.5"),
+            blocks_per_year,
+        }
+    }
+
+    #[test]
+    fn test_inflation_rises_below_goal_and_falls_above_it() {
+        let mut minter = Minter::new(params(10), dec("1000000")).unwrap();
+        minter.mint_block(&dec("0"));
+        assert_eq!(minter.inflation(), &dec("0.06"));
+
+        let fully_bonded = minter.total_supply().clone();
+        minter.mint_block(&fully_bonded);
+        assert_eq!(minter.inflation(), &dec("0.05"));
+    }
+
+    #[test]
+    fn test_inflation_stays_within_bounds() {
+        let mut minter = Minter::new(params(1), dec("1000")).unwrap();
+        for _ in 0..5 {
+            minter.mint_block(&dec("0"));
+        }
+        assert_eq!(minter.inflation(), &dec("0.2"));
+    }
+
+    #[test]
+    fn test_block_provision_is_added_to_supply() {
+        let mut minter = Minter::new(params(10), dec("1000000")).unwrap();
+        let provision = minter.mint_block(&dec("500000"));
+
+        assert_eq!(provision, dec("5000"));
+        assert_eq!(minter.total_supply(), &dec("1005000"));
+    }
+
+    #[test]
+    fn test_burned_tokens_leave_the_supply() {
+        let mut minter = Minter::new(params(10), dec("1000000")).unwrap();
+        minter.mint_block(&dec("500000"));
+        minter.burn(&dec("0.5"));
+
+        assert_eq!(minter.total_supply(), &dec("1004999.5"));
+    }
+
+    #[test]
+    fn test_invalid_params_are_rejected() {
+        let mut invalid = params(10);
+        invalid.inflation_min = dec("0.3");
+        assert_eq!(Minter::new(invalid, dec("1")).unwrap_err(), MintError::InvalidInflationBounds);
+        assert_eq!(Minter::new(params(0), dec("1")).unwrap_err(), MintError::ZeroBlocksPerYear);
+    }
+}
@@ -1,13 +1,15 @@
 // network_communication.rs
 // Handles communication with other nodes in the VENIA blockchain network.
 
+use std::collections::{HashMap, VecDeque};
 use std::net::{TcpListener, TcpStream};
 use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use serde_json::{Value, Error};
 use crate::block::Block;
 use crate::transaction::Transaction;
-use crate::consensus_state::ConsensusState;
+use crate::pos_algorithm::ConsensusMessage;
 
 // Constants for network parameters
 const MAX_CONNECTIONS: usize = 128;
@@ -24,7 +26,10 @@ pub struct Node {
 pub struct NetworkManager {
     listener: TcpListener,
     peers: Vec<Node>,
-    consensus_state: ConsensusState,
+    /// Consensus messages received from peers, waiting to be processed.
+    consensus_inbox: Arc<Mutex<VecDeque<ConsensusMessage>>>,
+    /// Peer run by each validator, for messages addressed to a single validator.
+    validator_peers: HashMap<String, Node>,
 }
 
 impl NetworkManager {
@@ -34,7 +39,8 @@ impl NetworkManager {
         NetworkManager {
             listener,
             peers: Vec::new(),
-            consensus_state: ConsensusState::new(),
+            consensus_inbox: Arc::new(Mutex::new(VecDeque::new())),
+            validator_peers: HashMap::new(),
         }
     }
 
@@ -43,8 +49,9 @@ impl NetworkManager {
         for stream in self.listener.incoming() {
             match stream {
                 Ok(stream) => {
+                    let inbox = Arc::clone(&self.consensus_inbox);
                     thread::spawn(move || {
-                        handle_client(stream);
+                        handle_client(stream, inbox);
                     });
                 }
                 Err(e) => { /* Handle connection error */ }
@@ -59,6 +66,32 @@ impl NetworkManager {
         }
     }
 
+    /// Broadcasts a consensus proposal or vote to all connected peers.
+    pub fn broadcast_consensus_message(&self, message: &ConsensusMessage) {
+        let encoded = serde_json::to_string(message).expect("consensus messages are serializable");
+        self.broadcast_message(&encoded);
+    }
+
+    /// Records the peer a validator can be reached at.
+    pub fn register_validator_peer(&mut self, validator_id: String, peer: Node) {
+        self.validator_peers.insert(validator_id, peer);
+    }
+
+    /// Sends a consensus message to a single validator.
+    /// Falls back to broadcasting if the validator's peer is unknown.
+    pub fn send_consensus_message(&self, validator_id: &str, message: &ConsensusMessage) {
+        let encoded = serde_json::to_string(message).expect("consensus messages are serializable");
+        match self.validator_peers.get(validator_id) {
+            Some(peer) => self.send_message(peer, &encoded),
+            None => self.broadcast_message(&encoded),
+        }
+    }
+
+    /// Takes the oldest consensus message received from peers, if any.
+    pub fn receive_consensus_message(&self) -> Option<ConsensusMessage> {
+        self.consensus_inbox.lock().unwrap().pop_front()
+    }
+
     /// Sends a message to a specific peer.
     fn send_message(&self, peer: &Node, message: &str) {
         let mut stream = TcpStream::connect(format!("{}:{}", peer.address, peer.port)).unwrap();
@@ -73,13 +106,13 @@ impl NetworkManager {
 }
 
 /// Handles an individual client connection.
-fn handle_client(mut stream: TcpStream) {
+fn handle_client(mut stream: TcpStream, inbox: Arc<Mutex<VecDeque<ConsensusMessage>>>) {
     let mut buffer = [0; MESSAGE_SIZE];
 
     while match stream.read(&mut buffer) {
         Ok(size) => {
             // Process the incoming message
-            process_message(&buffer[0..size]);
+            process_message(&buffer[0..size], &inbox);
             true
         },
         Err(_) => {
@@ -90,14 +123,19 @@ fn handle_client(mut stream: TcpStream) {
 }
 
 /// Processes an incoming network message.
-fn process_message(message: &[u8]) {
+/// Consensus messages are queued for the consensus engine.
+fn process_message(message: &[u8], inbox: &Mutex<VecDeque<ConsensusMessage>>) {
     // Deserialize the message
     let msg: Result<Value, Error> = serde_json::from_slice(message);
     
     match msg {
         Ok(value) => {
             // Handle the deserialized message
-            // TODO: Implement logic for different message types
+            if let Ok(consensus_message) = serde_json::from_value::<ConsensusMessage>(value) {
+                inbox.lock().unwrap().push_back(consensus_message);
+                return;
+            }
+            // TODO: Implement logic for other message types
         },
         Err(e) => { /* Handle deserialization error */ }
     }
@@ -1,72 +1,1380 @@
 // pos_algorithm.rs
 // Implementation of the PoS algorithm for VENIA blockchain
 
-use crate::stake_manager::StakeManager;
-use crate::validator_set::ValidatorSet;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+use schnorrkel::Keypair;
+use blockchain_types::Block;
+
+use crate::validator_set::{ProposerSelection, ValidatorSet};
 use crate::block_proposal::BlockProposal;
+use crate::consensus_engine::{self, ConsensusEngine};
 use crate::consensus_state::ConsensusState;
 use crate::network_communication::NetworkCommunication;
 use crate::transaction_verifier::TransactionVerifier;
-use crate::utilities::{time_utils, crypto_utils};
+use crate::quorum_certificate::QuorumCertificate;
+use crate::evidence::{DuplicateProposalEvidence, Evidence};
+use crate::finality_gadget::Attestation;
+use crate::hotstuff::{HotStuffProposal, NewView};
+use crate::vote_set::{VoteSet, VoteSetError};
+use crate::utilities::crypto_utils::{self, BlsSecretKey, Hash, VrfProof};
+use crate::utilities::time_utils;
+use crate::storage::{ConsensusStore, StorageError};
+use crate::wal::{Wal, WalEntry};
+
+/// How long to wait before polling the network again when no message is pending.
+const MESSAGE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Height of a block in the chain.
+pub type Height = u64;
+
+/// Round number within a height. Round 0 is the first attempt at a height.
+pub type Round = u32;
+
+/// Step of the round state machine.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Step {
+    Propose,
+    Prevote,
+    Precommit,
+    Commit,
+}
+
+/// Kind of vote cast by a validator during a round.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VoteType {
+    Prevote,
+    Precommit,
+}
+
+/// A signed prevote or precommit.
+/// A `block_hash` of `None` is a vote for nil.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Vote {
+    pub vote_type: VoteType,
+    pub height: Height,
+    pub round: Round,
+    pub block_hash: Option<Hash>,
+    pub validator_id: String,
+    pub signature: Vec<u8>,
+    /// BLS signature of the same bytes, aggregated into quorum certificates. Votes are only
+    /// counted if it verifies against the validator's registered BLS key.
+    pub bls_signature: Vec<u8>,
+}
+
+impl Vote {
+    /// Returns the bytes covered by the vote signature.
+    pub fn sign_bytes(&self) -> Vec<u8> {
+        Vote::sign_bytes_for(self.vote_type, self.height, self.round, self.block_hash)
+    }
+
+    /// Returns the bytes signed by any validator casting the given vote.
+    pub fn sign_bytes_for(vote_type: VoteType, height: Height, round: Round, block_hash: Option<Hash>) -> Vec<u8> {
+        serde_json::to_vec(&(vote_type, height, round, &block_hash)).expect("vote fields are serializable")
+    }
+}
+
+/// A signed block proposal for a height and round.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Proposal {
+    pub height: Height,
+    pub round: Round,
+    pub block: Block,
+    /// Round in which the proposer saw +2/3 prevotes for `block`, if any.
+    pub valid_round: Option<Round>,
+    pub proposer_id: String,
+    pub signature: Vec<u8>,
+    /// Proof of lock change: the prevotes for `block` in `valid_round`, so that validators which
+    /// missed them can check the re-proposal is justified. Not covered by the signature, as each
+    /// vote is signed by its validator.
+    #[serde(default)]
+    pub pol_prevotes: Vec<Vote>,
+    /// The proposer's winning VRF ticket for this height and round when proposers are elected by
+    /// lottery and `block` is re-proposed from an earlier round. The block's header keeps the
+    /// ticket it was built with, and a fresh block carries the current ticket there instead.
+    #[serde(default)]
+    pub leader_proof: Option<VrfProof>,
+}
+
+impl Proposal {
+    /// Returns the bytes covered by the proposal signature.
+    pub fn sign_bytes(&self) -> Vec<u8> {
+        let block_hash = crypto_utils::hash_block(&self.block);
+        serde_json::to_vec(&(self.height, self.round, block_hash, self.valid_round, &self.leader_proof))
+            .expect("proposal fields are serializable")
+    }
+}
+
+/// Per-step timeouts. Each timeout grows by its delta for every round that fails at a height,
+/// so a height with an offline proposer eventually gets a round long enough to succeed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TimeoutConfig {
+    pub propose: Duration,
+    pub propose_delta: Duration,
+    pub prevote: Duration,
+    pub prevote_delta: Duration,
+    pub precommit: Duration,
+    pub precommit_delta: Duration,
+}
+
+impl TimeoutConfig {
+    /// Returns how long to wait in `step` during `round`.
+    pub fn timeout(&self, step: Step, round: Round) -> Duration {
+        let (base, delta) = match step {
+            Step::Propose => (self.propose, self.propose_delta),
+            Step::Prevote => (self.prevote, self.prevote_delta),
+            Step::Precommit | Step::Commit => (self.precommit, self.precommit_delta),
+        };
+        base + delta * round
+    }
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            propose: Duration::from_millis(3000),
+            propose_delta: Duration::from_millis(500),
+            prevote: Duration::from_millis(1000),
+            prevote_delta: Duration::from_millis(500),
+            precommit: Duration::from_millis(1000),
+            precommit_delta: Duration::from_millis(500),
+        }
+    }
+}
+
+/// A timeout for a step of a given height and round.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout {
+    pub height: Height,
+    pub round: Round,
+    pub step: Step,
+}
+
+/// Messages exchanged between validators while running a round.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ConsensusMessage {
+    Proposal(Proposal),
+    Vote(Vote),
+    /// Messages of the HotStuff engine, see `hotstuff`. HotStuff votes are sent as `Vote`s.
+    HotStuffProposal(HotStuffProposal),
+    NewView(NewView),
+    /// A checkpoint attestation, see `finality_gadget`.
+    Attestation(Attestation),
+}
+
+/// Quorum certificate finalizing a block: +2/3 of the voting power precommitted to it, with
+/// their BLS signatures aggregated into one. The next block carries it as its last commit, see
+/// `BlockProposal::propose_block`.
+pub type Commit = QuorumCertificate;
+
+/// Keys and identifiers the local node uses when it acts as a validator.
+pub struct ValidatorIdentity {
+    /// ID of the local validator in the `ValidatorSet`.
+    pub validator_id: String,
+    /// Block producer identity passed to `BlockProposal`.
+    pub block_signer: blockchain_types::Validator,
+    /// Key used to sign proposals and votes.
+    pub keypair: Keypair,
+    /// Key votes are additionally signed with for aggregation into quorum certificates. Its public
+    /// key must be registered with the validator set, see `ValidatorSet::register_bls_key`.
+    pub bls_key: BlsSecretKey,
+}
+
+/// Progress of the local node through the current height.
+#[derive(Debug, Clone)]
+pub struct RoundState {
+    pub height: Height,
+    pub round: Round,
+    pub step: Step,
+    /// Block the node is locked on; it only prevotes for this block until unlocked.
+    pub locked_value: Option<Block>,
+    pub locked_round: Option<Round>,
+    /// Most recent block seen with +2/3 prevotes; re-proposed when this node is proposer.
+    pub valid_value: Option<Block>,
+    pub valid_round: Option<Round>,
+    /// Proposals received for this height, by round and block hash.
+    proposals: HashMap<(Round, Hash), Proposal>,
+    /// Hash of the block of each round's proposal, the one this node prevotes on.
+    round_proposals: HashMap<Round, Hash>,
+    /// Votes received for this height, by round and type.
+    votes: HashMap<(Round, VoteType), VoteSet>,
+    /// Whether +2/3 prevotes for the current round's proposal have already been acted on.
+    polka_seen: bool,
+    /// Whether the prevote and precommit timeouts of the current round have been scheduled.
+    prevote_timeout_scheduled: bool,
+    precommit_timeout_scheduled: bool,
+    /// Whether the first round of the height has been started.
+    started: bool,
+}
+
+impl RoundState {
+    /// Creates the state for the first round of a new height.
+    pub fn new(height: Height) -> Self {
+        RoundState {
+            height,
+            round: 0,
+            step: Step::Propose,
+            locked_value: None,
+            locked_round: None,
+            valid_value: None,
+            valid_round: None,
+            proposals: HashMap::new(),
+            round_proposals: HashMap::new(),
+            votes: HashMap::new(),
+            polka_seen: false,
+            prevote_timeout_scheduled: false,
+            precommit_timeout_scheduled: false,
+            started: false,
+        }
+    }
+}
 
 /// Main PoS Algorithm struct
 /// This struct encapsulates the main logic for the PoS consensus mechanism.
 pub struct PosAlgorithm {
-    stake_manager: StakeManager,
     validator_set: ValidatorSet,
     consensus_state: ConsensusState,
     network_communication: NetworkCommunication,
     transaction_verifier: TransactionVerifier,
+    block_proposal: BlockProposal,
+    identity: ValidatorIdentity,
+    round_state: RoundState,
+    timeout_config: TimeoutConfig,
+    /// Pending timeouts with their deadlines. Timeouts for past rounds are ignored when they fire.
+    scheduled_timeouts: Vec<(Instant, Timeout)>,
+    /// Log of the inputs acted on at the current height, see `attach_wal`.
+    wal: Option<Wal>,
+    /// Whether the write-ahead log is being replayed. No new messages are signed while replaying.
+    replaying: bool,
+    /// Store that finalized state is written to, see `attach_store`.
+    store: Option<Box<dyn ConsensusStore>>,
 }
 
 impl PosAlgorithm {
     /// Initializes a new instance of the PoS algorithm.
-    pub fn new() -> Self {
-        // Initialization code here
+    /// Consensus starts at the height following the last block in `consensus_state`.
+    pub fn new(
+        validator_set: ValidatorSet,
+        consensus_state: ConsensusState,
+        network_communication: NetworkCommunication,
+        transaction_verifier: TransactionVerifier,
+        block_proposal: BlockProposal,
+        identity: ValidatorIdentity,
+    ) -> Self {
+        let height = consensus_state.height() + 1;
+        PosAlgorithm {
+            validator_set,
+            consensus_state,
+            network_communication,
+            transaction_verifier,
+            block_proposal,
+            identity,
+            round_state: RoundState::new(height),
+            timeout_config: TimeoutConfig::default(),
+            scheduled_timeouts: Vec::new(),
+            wal: None,
+            replaying: false,
+            store: None,
+        }
+    }
+
+    /// Restores the consensus state and validator set last persisted to `store`, if any, and
+    /// persists every finalized height to it from now on. Consensus resumes at the height after
+    /// the restored one. Attach the store before the write-ahead log, whose entries are for that height.
+    pub fn attach_store(&mut self, mut store: Box<dyn ConsensusStore>) -> Result<(), StorageError> {
+        if let Some(validator_set) = self.consensus_state.restore(store.as_ref())? {
+            self.validator_set = validator_set;
+            self.round_state = RoundState::new(self.consensus_state.height() + 1);
+            self.scheduled_timeouts.clear();
+        } else {
+            // A fresh store: record the active set of the first epoch, which no epoch change stores.
+            let active: Vec<_> = self.validator_set.get_active_validators().into_iter().cloned().collect();
+            store.put_validator_set(self.consensus_state.epoch_start_height(), &active)?;
+        }
+        self.store = Some(store);
+        Ok(())
+    }
+
+    /// Restores the round state of the current height from a write-ahead log, then records every
+    /// further input in it before acting on it.
+    /// Messages this node signed before a crash are taken from the log rather than signed again,
+    /// so a restarted validator never double-signs. A message lost in the crash was never sent,
+    /// and the round falls back on its timeouts.
+    pub fn attach_wal(&mut self, wal: Wal) -> io::Result<()> {
+        let entries = wal.entries()?;
+        self.replaying = true;
+        if !entries.is_empty() {
+            self.start_round(0);
+            while self.apply_round_rules() {}
+        }
+        for entry in entries {
+            match entry {
+                WalEntry::Message(message) => self.process_message(message),
+                WalEntry::Timeout(timeout) => {
+                    self.handle_timeout(timeout);
+                    while self.apply_round_rules() {}
+                }
+                WalEntry::EndHeight(_) => {}
+            }
+        }
+        self.replaying = false;
+        self.wal = Some(wal);
+        Ok(())
+    }
+
+    /// Updates the step timeouts used from the next scheduled timeout onwards.
+    pub fn update_timeout_config(&mut self, timeout_config: TimeoutConfig) {
+        self.timeout_config = timeout_config;
     }
 
     /// Main entry point for the consensus algorithm.
-    /// This function coordinates the process of block proposal, validation, and finalization.
+    /// Runs rounds at the current height until a block is finalized with a commit.
+    /// Rounds that stall are abandoned when their timeouts expire.
+    /// A height restored from the write-ahead log resumes where it left off.
     pub fn run_consensus_round(&mut self) {
-        // Logic for running a single round of consensus
+        let height = self.round_state.height;
+        if !self.round_state.started {
+            self.start_round(0);
+            while self.apply_round_rules() {}
+        }
+
+        while self.round_state.height == height {
+            self.fire_expired_timeouts();
+            if self.round_state.height != height {
+                break;
+            }
+            match self.network_communication.receive_consensus_message() {
+                Some(message) => self.handle_message(message),
+                None => thread::sleep(self.poll_interval()),
+            }
+        }
+    }
+
+    /// Records an incoming proposal or vote and applies every round rule it enables.
+    /// Messages that cannot be written to the write-ahead log are dropped.
+    pub fn handle_message(&mut self, message: ConsensusMessage) {
+        if self.write_wal(WalEntry::Message(message.clone())) {
+            self.process_message(message);
+        }
+    }
+
+    /// Records a proposal or vote and applies every round rule it enables.
+    fn process_message(&mut self, message: ConsensusMessage) {
+        match message {
+            ConsensusMessage::Proposal(proposal) => self.record_proposal(proposal),
+            ConsensusMessage::Vote(vote) => self.record_vote(vote),
+            ConsensusMessage::HotStuffProposal(_) | ConsensusMessage::NewView(_) | ConsensusMessage::Attestation(_) => return,
+        }
+        while self.apply_round_rules() {}
+    }
+
+    /// Returns the current height, round and step.
+    pub fn round_state(&self) -> &RoundState {
+        &self.round_state
     }
 
-    /// Selects validators for the next consensus round.
-    /// This function uses the stake information from the StakeManager to select validators.
-    fn select_validators(&mut self) {
-        // Validator selection logic
+    /// Moves to `round` and, if this node is the proposer, broadcasts a proposal.
+    /// The locked block is re-proposed in preference to a fresh one.
+    /// Other validators wait for the proposal until the propose timeout expires.
+    fn start_round(&mut self, round: Round) {
+        self.round_state.started = true;
+        self.round_state.round = round;
+        self.round_state.step = Step::Propose;
+        self.round_state.polka_seen = false;
+        self.round_state.prevote_timeout_scheduled = false;
+        self.round_state.precommit_timeout_scheduled = false;
+
+        let height = self.round_state.height;
+        let validator_id = self.identity.validator_id.as_str();
+        let leader_proof = match self.validator_set.proposer_selection() {
+            ProposerSelection::RoundRobin => {
+                if self.validator_set.proposer_for(height, round).as_deref() != Some(validator_id) {
+                    self.schedule_timeout(Step::Propose);
+                    return;
+                }
+                None
+            }
+            ProposerSelection::VrfLottery => {
+                match self.validator_set.evaluate_leader_lottery(validator_id, &self.identity.keypair, height, round) {
+                    Some(proof) => Some(proof),
+                    None => {
+                        self.schedule_timeout(Step::Propose);
+                        return;
+                    }
+                }
+            }
+        };
+
+        // A proposal signed before a crash is replayed from the write-ahead log.
+        if self.replaying {
+            self.schedule_timeout(Step::Propose);
+            return;
+        }
+
+        // A fresh block carries the ticket in its header; a re-proposed block keeps its header
+        // and the ticket goes with the proposal.
+        let (block, valid_round, leader_proof) = match &self.round_state.valid_value {
+            Some(block) => (block.clone(), self.round_state.valid_round, leader_proof),
+            None => (self.propose_block(leader_proof), None, None),
+        };
+        let pol_prevotes = match valid_round {
+            Some(valid_round) => self
+                .round_state
+                .votes
+                .get(&(valid_round, VoteType::Prevote))
+                .map_or_else(Vec::new, |votes| votes.votes_for(Some(crypto_utils::hash_block(&block)))),
+            None => Vec::new(),
+        };
+        let mut proposal = Proposal {
+            height,
+            round,
+            block,
+            valid_round,
+            proposer_id: self.identity.validator_id.clone(),
+            signature: Vec::new(),
+            pol_prevotes,
+            leader_proof,
+        };
+        proposal.signature = crypto_utils::sign_message(&self.identity.keypair, &proposal.sign_bytes());
+        if !self.write_wal(WalEntry::Message(ConsensusMessage::Proposal(proposal.clone()))) {
+            self.schedule_timeout(Step::Propose);
+            return;
+        }
+        self.network_communication
+            .broadcast_consensus_message(&ConsensusMessage::Proposal(proposal.clone()));
+        self.record_proposal(proposal);
     }
 
-    /// Proposes a new block to be added to the blockchain.
-    /// This function creates a new block proposal and broadcasts it to other validators.
-    fn propose_block(&self) {
-        // Block proposal logic
+    /// Stores a proposal if it is for the current height, is signed by its proposer and the
+    /// proposer was eligible.
+    /// Every proposal is kept, so a block that gathers +2/3 of the votes can be acted on whichever
+    /// proposal this node prevoted on. In lottery mode several validators may win a round; the
+    /// lowest ticket becomes the round's proposal, which never changes once this node has voted in
+    /// the round.
+    fn record_proposal(&mut self, proposal: Proposal) {
+        if proposal.height != self.round_state.height {
+            return;
+        }
+        // Checked before anything is stored, so a forgery cannot take the place of the proposer's
+        // genuine proposal.
+        let signed = self.validator_set.get_validator(&proposal.proposer_id).map_or(false, |validator| {
+            crypto_utils::verify_signature(&validator.public_key, &proposal.sign_bytes(), &proposal.signature)
+        });
+        if !signed {
+            return;
+        }
+        let score = match self.leader_score(&proposal) {
+            Some(score) => score,
+            None => return,
+        };
+        let round = proposal.round;
+        if let Some(existing) = self
+            .round_state
+            .proposals
+            .iter()
+            .find(|((existing_round, _), existing)| *existing_round == round && existing.proposer_id == proposal.proposer_id)
+            .map(|(_, existing)| existing)
+        {
+            if existing.sign_bytes() != proposal.sign_bytes() {
+                let evidence = DuplicateProposalEvidence {
+                    proposal_a: existing.clone(),
+                    proposal_b: proposal.clone(),
+                };
+                let _ = self
+                    .consensus_state
+                    .submit_evidence(Evidence::DuplicateProposal(evidence), &self.validator_set);
+            }
+            return;
+        }
+        // The proof of lock change is counted like prevotes received directly; each is verified
+        // and a conflict with a known vote becomes evidence.
+        if let Some(valid_round) = proposal.valid_round {
+            let block_hash = Some(crypto_utils::hash_block(&proposal.block));
+            for vote in &proposal.pol_prevotes {
+                if vote.vote_type == VoteType::Prevote && vote.round == valid_round && vote.block_hash == block_hash {
+                    self.record_vote(vote.clone());
+                }
+            }
+        }
+        // Competing proposals are kept in the block tree so the fork choice can see every branch.
+        let _ = self.consensus_state.add_block(proposal.block.clone());
+
+        let block_hash = crypto_utils::hash_block(&proposal.block);
+        let voted = self.has_voted_in(round);
+        // Proposers re-proposing the same block share its entry, which the best ranked one keeps.
+        let outranks = |current: Option<&Proposal>| {
+            current
+                .and_then(|current| self.leader_score(current))
+                .map_or(true, |current| score < current)
+        };
+        let selected = self
+            .round_state
+            .round_proposals
+            .get(&round)
+            .and_then(|hash| self.round_state.proposals.get(&(round, *hash)));
+        let select = selected.is_none() || (!voted && outranks(selected));
+        let existing = self.round_state.proposals.get(&(round, block_hash));
+        if existing.is_none() || (!voted && outranks(existing)) {
+            self.round_state.proposals.insert((round, block_hash), proposal);
+        }
+        if select {
+            self.round_state.round_proposals.insert(round, block_hash);
+        }
+    }
+
+    /// Returns the proposal of `round` this node prevotes on, if one has been received.
+    fn round_proposal(&self, round: Round) -> Option<&Proposal> {
+        let block_hash = self.round_state.round_proposals.get(&round)?;
+        self.round_state.proposals.get(&(round, *block_hash))
+    }
+
+    /// Returns true if this node has prevoted or precommitted in `round`.
+    fn has_voted_in(&self, round: Round) -> bool {
+        let validator_id = self.identity.validator_id.as_str();
+        [VoteType::Prevote, VoteType::Precommit].iter().any(|vote_type| {
+            self.round_state
+                .votes
+                .get(&(round, *vote_type))
+                .map_or(false, |votes| votes.get(validator_id).is_some())
+        })
+    }
+
+    /// Returns the rank of a proposal's proposer for its round, lower being better,
+    /// or `None` if the proposer was not entitled to propose.
+    fn leader_score(&self, proposal: &Proposal) -> Option<Hash> {
+        match self.validator_set.proposer_selection() {
+            ProposerSelection::RoundRobin => {
+                let expected = self.validator_set.proposer_for(proposal.height, proposal.round)?;
+                if expected == proposal.proposer_id {
+                    Some([0; 32])
+                } else {
+                    None
+                }
+            }
+            ProposerSelection::VrfLottery => {
+                // A header ticket of another round or proposer does not verify.
+                let proof = proposal.leader_proof.as_ref().or(proposal.block.header.vrf_proof.as_ref())?;
+                self.validator_set
+                    .verify_leader_proof(&proposal.proposer_id, proposal.height, proposal.round, proof)
+            }
+        }
+    }
+
+    /// Stores a correctly signed vote for the current height. Only the first vote per validator,
+    /// round and type is kept; a conflicting second vote is submitted as evidence.
+    fn record_vote(&mut self, vote: Vote) {
+        let height = self.round_state.height;
+        if vote.height != height {
+            return;
+        }
+        let (round, vote_type) = (vote.round, vote.vote_type);
+        let (validator_id, block_hash) = (vote.validator_id.clone(), vote.block_hash);
+        let validator_set = &self.validator_set;
+        let votes = self
+            .round_state
+            .votes
+            .entry((round, vote_type))
+            .or_insert_with(|| VoteSet::new(height, round, vote_type, validator_set));
+        match votes.add_vote(vote, validator_set) {
+            Ok(true) => {
+                if let Some(block_hash) = block_hash {
+                    let power = self.validator_set.voting_power(&validator_id);
+                    let _ = self.consensus_state.add_attestation(&validator_id, block_hash, power);
+                }
+            }
+            Err(VoteSetError::Conflicting(evidence)) => {
+                let _ = self.consensus_state.submit_evidence(Evidence::DuplicateVote(*evidence), validator_set);
+            }
+            _ => {}
+        }
+    }
+
+    /// Signs a vote for the current round, records it locally and broadcasts it.
+    /// Nodes without voting power do not vote, and votes signed before a crash are replayed from
+    /// the write-ahead log instead.
+    fn cast_vote(&mut self, vote_type: VoteType, block_hash: Option<Hash>) {
+        if self.replaying || self.validator_set.voting_power(&self.identity.validator_id) == 0 {
+            return;
+        }
+        let mut vote = Vote {
+            vote_type,
+            height: self.round_state.height,
+            round: self.round_state.round,
+            block_hash,
+            validator_id: self.identity.validator_id.clone(),
+            signature: Vec::new(),
+            bls_signature: Vec::new(),
+        };
+        vote.signature = crypto_utils::sign_message(&self.identity.keypair, &vote.sign_bytes());
+        vote.bls_signature = crypto_utils::bls_sign(&self.identity.bls_key, &vote.sign_bytes());
+        if !self.write_wal(WalEntry::Message(ConsensusMessage::Vote(vote.clone()))) {
+            return;
+        }
+        self.network_communication
+            .broadcast_consensus_message(&ConsensusMessage::Vote(vote.clone()));
+        self.record_vote(vote);
+    }
+
+    /// Applies the first round rule whose conditions hold.
+    /// Returns true if the state changed, so callers should apply the rules again.
+    ///
+    /// Safety rests on the lock: a node precommits a block only in the round it sees +2/3 prevotes
+    /// for it, and then locks on it. Later rounds it prevotes only for the locked block, or for a
+    /// block whose proof of lock change is +2/3 prevotes in a round at or after the locked one.
+    /// Once +2/3 precommit a block in some round, more than 1/3 of the voting power is honest and
+    /// locked on it, so no other block gets +2/3 prevotes, and hence precommits, at that height
+    /// while less than 1/3 of the voting power is faulty.
+    fn apply_round_rules(&mut self) -> bool {
+        if let Some((block, commit)) = self.find_decision() {
+            self.finalize_block(block, commit);
+            return true;
+        }
+
+        if let Some(round) = self.skip_round() {
+            self.start_round(round);
+            return true;
+        }
+
+        let round = self.round_state.round;
+        if self.round_state.step == Step::Propose {
+            if let Some(proposal) = self.round_proposal(round).cloned() {
+                let block_hash = crypto_utils::hash_block(&proposal.block);
+                let acceptable = match proposal.valid_round {
+                    None => Some(self.round_state.locked_round.is_none() || self.is_locked_on(block_hash)),
+                    Some(valid_round) if valid_round >= round => Some(false),
+                    Some(valid_round) if self.has_quorum_for(valid_round, VoteType::Prevote, Some(block_hash)) => Some(
+                        self.round_state.locked_round.map_or(true, |locked| locked <= valid_round)
+                            || self.is_locked_on(block_hash),
+                    ),
+                    // Wait for the prevotes that justify re-proposing the block.
+                    Some(_) => None,
+                };
+                if let Some(acceptable) = acceptable {
+                    let value = if acceptable && self.validate_block(&proposal.block) {
+                        Some(block_hash)
+                    } else {
+                        None
+                    };
+                    self.cast_vote(VoteType::Prevote, value);
+                    self.round_state.step = Step::Prevote;
+                    return true;
+                }
+            }
+        }
+
+        // Any proposed block of the round can gather the prevotes, not only the one this node
+        // prevoted on.
+        if self.round_state.step >= Step::Prevote && !self.round_state.polka_seen {
+            let polka = self
+                .round_state
+                .proposals
+                .iter()
+                .filter(|((proposal_round, block_hash), _)| {
+                    *proposal_round == round && self.has_quorum_for(round, VoteType::Prevote, Some(*block_hash))
+                })
+                .map(|((_, block_hash), proposal)| (*block_hash, proposal.block.clone()))
+                .find(|(_, block)| self.validate_block(block));
+            if let Some((block_hash, block)) = polka {
+                self.round_state.polka_seen = true;
+                if self.round_state.step == Step::Prevote {
+                    self.round_state.locked_value = Some(block.clone());
+                    self.round_state.locked_round = Some(round);
+                    self.cast_vote(VoteType::Precommit, Some(block_hash));
+                    self.round_state.step = Step::Precommit;
+                }
+                self.round_state.valid_value = Some(block);
+                self.round_state.valid_round = Some(round);
+                return true;
+            }
+        }
+
+        if self.round_state.step == Step::Prevote && self.has_quorum_for(round, VoteType::Prevote, None) {
+            self.cast_vote(VoteType::Precommit, None);
+            self.round_state.step = Step::Precommit;
+            return true;
+        }
+
+        if self.round_state.step == Step::Prevote
+            && !self.round_state.prevote_timeout_scheduled
+            && self.has_quorum_any(round, VoteType::Prevote)
+        {
+            self.round_state.prevote_timeout_scheduled = true;
+            self.schedule_timeout(Step::Prevote);
+            return true;
+        }
+
+        if !self.round_state.precommit_timeout_scheduled && self.has_quorum_any(round, VoteType::Precommit) {
+            self.round_state.precommit_timeout_scheduled = true;
+            self.schedule_timeout(Step::Precommit);
+            return true;
+        }
+
+        false
+    }
+
+    /// Schedules the timeout of `step` for the current height and round.
+    fn schedule_timeout(&mut self, step: Step) {
+        let timeout = Timeout {
+            height: self.round_state.height,
+            round: self.round_state.round,
+            step,
+        };
+        let deadline = Instant::now() + self.timeout_config.timeout(step, timeout.round);
+        self.scheduled_timeouts.push((deadline, timeout));
+    }
+
+    /// Handles every timeout whose deadline has passed, then applies the round rules they enable.
+    fn fire_expired_timeouts(&mut self) {
+        let now = Instant::now();
+        let (expired, pending): (Vec<_>, Vec<_>) = self
+            .scheduled_timeouts
+            .drain(..)
+            .partition(|(deadline, _)| *deadline <= now);
+        self.scheduled_timeouts = pending;
+
+        for (deadline, timeout) in expired {
+            // A timeout that cannot be logged is retried on the next poll.
+            if !self.write_wal(WalEntry::Timeout(timeout)) {
+                self.scheduled_timeouts.push((deadline, timeout));
+                continue;
+            }
+            self.handle_timeout(timeout);
+            while self.apply_round_rules() {}
+        }
+    }
+
+    /// Appends an entry to the write-ahead log, if one is attached.
+    /// Returns false if the entry could not be made durable, in which case the caller must not act on it.
+    fn write_wal(&mut self, entry: WalEntry) -> bool {
+        match &mut self.wal {
+            Some(wal) => wal.write(&entry).is_ok(),
+            None => true,
+        }
+    }
+
+    /// Handles an expired timeout if the node is still in the height, round and step it was scheduled for.
+    /// A propose timeout prevotes nil, a prevote timeout precommits nil and a precommit timeout
+    /// moves to the next round, which rotates the proposer.
+    fn handle_timeout(&mut self, timeout: Timeout) {
+        if timeout.height != self.round_state.height || timeout.round != self.round_state.round {
+            return;
+        }
+        match timeout.step {
+            Step::Propose if self.round_state.step == Step::Propose => {
+                self.cast_vote(VoteType::Prevote, None);
+                self.round_state.step = Step::Prevote;
+            }
+            Step::Prevote if self.round_state.step == Step::Prevote => {
+                self.cast_vote(VoteType::Precommit, None);
+                self.round_state.step = Step::Precommit;
+            }
+            Step::Precommit => self.start_round(timeout.round + 1),
+            _ => {}
+        }
+    }
+
+    /// Returns how long to wait for messages before checking timeouts again.
+    fn poll_interval(&self) -> Duration {
+        self.scheduled_timeouts
+            .iter()
+            .map(|(deadline, _)| time_utils::time_until(*deadline))
+            .fold(MESSAGE_POLL_INTERVAL, Duration::min)
+    }
+
+    /// Looks for a proposal at the current height backed by +2/3 precommits, in any round.
+    fn find_decision(&self) -> Option<(Block, Commit)> {
+        for ((round, block_hash), proposal) in &self.round_state.proposals {
+            let block_hash = *block_hash;
+            if !self.has_quorum_for(*round, VoteType::Precommit, Some(block_hash))
+                || !self.validate_block(&proposal.block)
+            {
+                continue;
+            }
+            // Every counted precommit carries a verified BLS signature, so they aggregate.
+            let precommits = self.round_state.votes[&(*round, VoteType::Precommit)].votes_for(Some(block_hash));
+            let active: Vec<_> = self.validator_set.get_active_validators().into_iter().cloned().collect();
+            if let Ok(commit) = QuorumCertificate::from_votes(self.round_state.height, *round, block_hash, &precommits, &active) {
+                return Some((proposal.block.clone(), commit));
+            }
+        }
+        None
+    }
+
+    /// Returns the lowest round above the current one in which more than 1/3 of the voting power has voted.
+    /// At least one honest validator is in that round, so this node moves there too.
+    fn skip_round(&self) -> Option<Round> {
+        let mut rounds: Vec<Round> = self
+            .round_state
+            .votes
+            .keys()
+            .map(|(round, _)| *round)
+            .filter(|round| *round > self.round_state.round)
+            .collect();
+        rounds.sort_unstable();
+        rounds.dedup();
+
+        let total = self.validator_set.total_voting_power() as u128;
+        rounds.into_iter().find(|round| {
+            let voters: HashSet<&str> = [VoteType::Prevote, VoteType::Precommit]
+                .iter()
+                .filter_map(|vote_type| self.round_state.votes.get(&(*round, *vote_type)))
+                .flat_map(VoteSet::voters)
+                .collect();
+            let power: u64 = voters.into_iter().map(|id| self.validator_set.voting_power(id)).sum();
+            power as u128 * 3 > total
+        })
+    }
+
+    /// Returns true if +2/3 of the voting power voted for `value` in the round.
+    fn has_quorum_for(&self, round: Round, vote_type: VoteType, value: Option<Hash>) -> bool {
+        self.round_state
+            .votes
+            .get(&(round, vote_type))
+            .map_or(false, |votes| votes.has_two_thirds_for(value))
+    }
+
+    /// Returns true if +2/3 of the voting power voted in the round, for any values.
+    fn has_quorum_any(&self, round: Round, vote_type: VoteType) -> bool {
+        self.round_state
+            .votes
+            .get(&(round, vote_type))
+            .map_or(false, VoteSet::has_two_thirds_any)
+    }
+
+    /// Returns true if the node is locked on the block with the given hash.
+    fn is_locked_on(&self, block_hash: Hash) -> bool {
+        self.round_state
+            .locked_value
+            .as_ref()
+            .map_or(false, |block| crypto_utils::hash_block(block) == block_hash)
+    }
+
+    /// Builds a new block from pending transactions and evidence on top of the last finalized block.
+    /// The caller signs and broadcasts it as a proposal.
+    fn propose_block(&self, leader_proof: Option<VrfProof>) -> Block {
+        let transactions = self.network_communication.fetch_transactions();
+        self.block_proposal.propose_block(
+            &self.consensus_state,
+            &self.validator_set,
+            transactions,
+            &self.identity.block_signer,
+            leader_proof,
+        )
     }
 
     /// Validates a proposed block.
-    /// This function checks the validity of the block and its transactions.
-    fn validate_block(&self, block: &BlockProposal) -> bool {
-        // Block validation logic
+    /// This function checks that the block extends the last committed block and carries a valid
+    /// commit of it, that its timestamp follows that block's without running ahead of local time,
+    /// that its state root is the root of the state that block left, and that its transactions and
+    /// evidence are valid.
+    fn validate_block(&self, block: &Block) -> bool {
+        let parent_timestamp = self.consensus_state.get_last_committed_block().map(|parent| parent.header.timestamp);
+        block.header.previous_hash == self.consensus_state.get_latest_block_hash()
+            && consensus_engine::valid_block_time(block.header.timestamp, parent_timestamp)
+            && self.consensus_state.verify_last_commit(block.last_commit.as_ref())
+            && block.header.state_root == self.consensus_state.compute_state_root(&self.validator_set)
+            && block
+                .transactions
+                .iter()
+                .all(|tx| self.transaction_verifier.verify_transaction(tx).is_ok())
+            && block
+                .evidence
+                .iter()
+                .all(|evidence| self.consensus_state.check_evidence(evidence, &self.validator_set).is_ok())
     }
 
-    /// Finalizes the block.
-    /// This function finalizes the block and updates the blockchain state.
-    fn finalize_block(&mut self, block: BlockProposal) {
-        // Block finalization logic
+    /// Finalizes the block with `commit`, which `find_decision` verified when aggregating it.
+    /// The node then moves to the next height.
+    fn finalize_block(&mut self, block: Block, commit: Commit) {
+        let height = commit.height;
+        let next_height = height + 1;
+        let set_changed =
+            consensus_engine::apply_finalized_block(&mut self.validator_set, &mut self.consensus_state, block, commit);
+        self.validator_set.advance_proposer_priorities(next_height);
+        self.round_state = RoundState::new(next_height);
+        self.scheduled_timeouts.clear();
+
+        // Until the height is persisted, its inputs stay in the write-ahead log so that a restart
+        // can decide the block again.
+        if self.persist_state(height, set_changed).is_err() {
+            return;
+        }
+        if let Some(wal) = &mut self.wal {
+            // If this fails, the finished height's entries are replayed after a restart and
+            // ignored, since they are for a past height.
+            let _ = wal.end_height(height);
+        }
     }
 
-    /// Handles stake updates.
-    /// This function updates the stakes based on validator performance and other factors.
-    fn update_stakes(&mut self) {
-        // Stake updating logic
+    /// Writes the state resulting from finalizing `height` to the attached store, if any.
+    /// The active set is recorded under its first height whenever it changes. Per-height records
+    /// are written first and the consensus state, validator set included, last as a single record,
+    /// so a crash part-way restores the previous height, which is then finalized again.
+    fn persist_state(&mut self, height: Height, set_changed: bool) -> Result<(), StorageError> {
+        let store = match &mut self.store {
+            Some(store) => store.as_mut(),
+            None => return Ok(()),
+        };
+        if set_changed {
+            let active: Vec<_> = self.validator_set.get_active_validators().into_iter().cloned().collect();
+            store.put_validator_set(height + 1, &active)?;
+        }
+        store.put_stake_snapshot(&self.validator_set.stake_manager().get_current_stake_snapshot())?;
+        self.consensus_state.persist(store, &self.validator_set)
     }
+}
 
-    /// Synchronizes the state with other nodes in the network.
-    /// Ensures the local consensus state is in sync with the network.
-    fn synchronize_state(&mut self) {
-        // State synchronization logic
+impl ConsensusEngine for PosAlgorithm {
+    fn run_consensus_round(&mut self) {
+        PosAlgorithm::run_consensus_round(self)
+    }
+
+    fn handle_message(&mut self, message: ConsensusMessage) {
+        PosAlgorithm::handle_message(self, message)
+    }
+
+    fn consensus_state(&self) -> &ConsensusState {
+        &self.consensus_state
+    }
+
+    fn validator_set(&self) -> &ValidatorSet {
+        &self.validator_set
     }
 }
 
 // Additional helper functions and modules related to the PoS algorithm
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use big_decimal::BigDecimal;
+    use schnorrkel::{ExpansionMode, MiniSecretKey};
+    use crate::stake_manager::{StakeManager, UnbondingPeriod};
+    use crate::staking_transaction::Payout;
+    use crate::state::BlockchainState;
+    use crate::storage::MemoryStore;
+    use crate::evidence::{DuplicateVoteEvidence, EvidenceError};
+    use crate::validator_set::{BlsKeyRegistration, Commission, UnjailTransaction, ValidatorSetError};
+
+    const IDS: [&str; 4] = ["a", "b", "c", "d"];
+
+    fn keypair(index: usize) -> Keypair {
+        MiniSecretKey::from_bytes(&[index as u8 + 1; 32]).unwrap().expand_to_keypair(ExpansionMode::Ed25519)
+    }
+
+    fn bls_key(index: usize) -> BlsSecretKey {
+        BlsSecretKey::key_gen(&[index as u8 + 1; 32], &[]).unwrap()
+    }
+
+    /// Active validators `a`, `b`, ... with the given stakes and registered BLS keys.
+    fn validator_set(stakes: &[u64]) -> ValidatorSet {
+        let mut set = ValidatorSet::new(StakeManager::new());
+        for (index, stake) in stakes.iter().enumerate() {
+            let public_key = keypair(index).public.to_bytes().to_vec();
+            set.add_validator(IDS[index].to_string(), *stake, public_key, Commission::default()).unwrap();
+            set.update_validator_status(IDS[index], true);
+            set.register_bls_key(&BlsKeyRegistration::sign(IDS[index], Vec::new(), &bls_key(index), &keypair(index)))
+                .unwrap();
+        }
+        set
+    }
+
+    /// An engine at height 1 run by validator `IDS[local]`, without a store or write-ahead log.
+    fn engine(stakes: &[u64], local: usize) -> PosAlgorithm {
+        let keypair = keypair(local);
+        let identity = ValidatorIdentity {
+            validator_id: IDS[local].to_string(),
+            block_signer: blockchain_types::Validator::new(keypair.public.to_bytes().to_vec()),
+            keypair,
+            bls_key: bls_key(local),
+        };
+        PosAlgorithm::new(
+            validator_set(stakes),
+            ConsensusState::new(&validator_set(stakes)),
+            NetworkCommunication::new("127.0.0.1", 0),
+            TransactionVerifier::new(BlockchainState::default()),
+            BlockProposal::new(TransactionVerifier::new(BlockchainState::default())),
+            identity,
+        )
+    }
+
+    /// A vote signed by validator `IDS[index]`.
+    fn vote(index: usize, vote_type: VoteType, height: Height, round: Round, block_hash: Option<Hash>) -> Vote {
+        let mut vote = Vote {
+            vote_type,
+            height,
+            round,
+            block_hash,
+            validator_id: IDS[index].to_string(),
+            signature: Vec::new(),
+            bls_signature: Vec::new(),
+        };
+        vote.signature = crypto_utils::sign_message(&keypair(index), &vote.sign_bytes());
+        vote.bls_signature = crypto_utils::bls_sign(&bls_key(index), &vote.sign_bytes());
+        vote
+    }
+
+    /// A proposal of `block` at height 1 signed by the round-robin proposer of `round`.
+    fn proposal(engine: &PosAlgorithm, round: Round, block: Block, valid_round: Option<Round>, pol_prevotes: Vec<Vote>) -> Proposal {
+        let proposer_id = engine.validator_set.proposer_for(1, round).unwrap();
+        let index = IDS.iter().position(|id| *id == proposer_id).unwrap();
+        let mut proposal = Proposal {
+            height: 1,
+            round,
+            block,
+            valid_round,
+            proposer_id,
+            signature: Vec::new(),
+            pol_prevotes,
+            leader_proof: None,
+        };
+        proposal.signature = crypto_utils::sign_message(&keypair(index), &proposal.sign_bytes());
+        proposal
+    }
+
+    /// Two different valid blocks for height 1.
+    fn conflicting_blocks(engine: &PosAlgorithm) -> (Block, Block) {
+        let first = engine.propose_block(None);
+        let mut second = first.clone();
+        second.header.timestamp += 1;
+        second.set_hash(crypto_utils::hash_block(&second));
+        (first, second)
+    }
+
+    /// Returns the block hash `IDS[index]` prevoted for in `round`, if it prevoted.
+    fn prevote_of(engine: &PosAlgorithm, index: usize, round: Round) -> Option<Option<Hash>> {
+        let prevotes = engine.round_state.votes.get(&(round, VoteType::Prevote))?;
+        prevotes.get(IDS[index]).map(|vote| vote.block_hash)
+    }
+
+    #[test]
+    fn test_double_vote_is_committed_and_reduces_voting_power() {
+        // `a` holds more than two thirds of the voting power and finalizes height 1 on its own.
+        let mut engine = engine(&[700, 100, 100, 100], 0);
+        engine.consensus_state.set_epoch_length(1);
+        engine.handle_message(ConsensusMessage::Vote(vote(1, VoteType::Prevote, 1, 0, None)));
+        engine.handle_message(ConsensusMessage::Vote(vote(1, VoteType::Prevote, 1, 0, Some([1; 32]))));
+        assert_eq!(engine.consensus_state.pending_evidence().len(), 1);
+
+        engine.start_round(0);
+        while engine.apply_round_rules() {}
+
+        assert_eq!(engine.consensus_state.height(), 1);
+        assert_eq!(engine.consensus_state.get_last_committed_block().unwrap().evidence.len(), 1);
+        assert!(engine.consensus_state.pending_evidence().is_empty());
+        // Slashed by 5% in the engine's own validator set, effective from the epoch starting at height 2.
+        assert_eq!(engine.validator_set.stake_manager().get_stake(&"b".to_string()), Some(&BigDecimal::from(95)));
+        assert_eq!(engine.validator_set.voting_power("b"), 95);
+    }
+
+    #[test]
+    fn test_consecutive_heights_extend_the_finalized_chain() {
+        let mut engine = engine(&[10], 0);
+        for height in 1..=3 {
+            let parent = engine.consensus_state.get_latest_block_hash();
+            engine.start_round(0);
+            while engine.apply_round_rules() {}
+
+            assert_eq!(engine.consensus_state.height(), height);
+            let block = engine.consensus_state.get_last_committed_block().unwrap();
+            assert_eq!(block.header.previous_hash, parent);
+            // Each block carries the commit of its parent, from which liveness is recorded.
+            assert_eq!(block.last_commit.as_ref().map(|commit| commit.block_hash), (height > 1).then_some(parent));
+            assert_eq!(engine.round_state.height, height + 1);
+        }
+    }
+
+    #[test]
+    fn test_finalized_state_is_restored_from_a_single_record() {
+        let mut engine = engine(&[10], 0);
+        engine.start_round(0);
+        while engine.apply_round_rules() {}
+        let mut store = MemoryStore::new();
+        engine.consensus_state.persist(&mut store, &engine.validator_set).unwrap();
+
+        let mut restored = ConsensusState::new(&validator_set(&[10]));
+        let restored_set = restored.restore(&store).unwrap().unwrap();
+        assert_eq!(restored.height(), 1);
+        assert_eq!(restored.get_latest_block_hash(), engine.consensus_state.get_latest_block_hash());
+        assert_eq!(
+            restored.compute_state_root(&restored_set),
+            engine.consensus_state.compute_state_root(&engine.validator_set)
+        );
+        assert_eq!(restored.get_last_commit(), engine.consensus_state.get_last_commit());
+    }
+
+    #[test]
+    fn test_released_unbonding_is_paid_out() {
+        let mut engine = engine(&[10], 0);
+        let stake_manager = engine.validator_set.stake_manager_mut();
+        stake_manager.update_unbonding_period(UnbondingPeriod::Blocks(1));
+        stake_manager.delegate_stake("d".to_string(), "a".to_string(), BigDecimal::from(4)).unwrap();
+        stake_manager.undelegate_stake("d".to_string(), "a".to_string(), BigDecimal::from(4)).unwrap();
+
+        engine.start_round(0);
+        while engine.apply_round_rules() {}
+
+        assert_eq!(engine.consensus_state.height(), 1);
+        let payouts = engine.consensus_state.take_payouts();
+        assert_eq!(payouts, vec![Payout { account: "d".to_string(), amount: BigDecimal::from(4) }]);
+        assert!(engine.consensus_state.take_payouts().is_empty());
+    }
+
+    #[test]
+    fn test_lottery_winner_re_proposes_the_valid_block_with_its_ticket() {
+        // With four equal stakes every validator's ticket wins, so `a` proposes in every round.
+        let mut engine = engine(&[10, 10, 10, 10], 0);
+        engine.validator_set.set_proposer_selection(ProposerSelection::VrfLottery);
+        engine.start_round(0);
+        let first = engine.round_proposal(0).cloned().unwrap();
+        assert!(first.block.header.vrf_proof.is_some());
+        assert_eq!(first.leader_proof, None);
+        assert_eq!(first.valid_round, None);
+
+        // The re-proposed block keeps the ticket of round 0 in its header, and the proposal
+        // carries the ticket of round 1 alongside it.
+        engine.round_state.valid_value = Some(first.block.clone());
+        engine.round_state.valid_round = Some(0);
+        engine.start_round(1);
+        let second = engine.round_proposal(1).cloned().unwrap();
+        assert_eq!(crypto_utils::hash_block(&second.block), crypto_utils::hash_block(&first.block));
+        assert_eq!(second.block.header.vrf_proof, first.block.header.vrf_proof);
+        assert_eq!(second.valid_round, Some(0));
+        assert!(second.leader_proof.is_some());
+        assert_ne!(second.leader_proof, first.block.header.vrf_proof);
+
+        // Without its own ticket, the re-proposal would rest on the header's ticket of round 0.
+        let mut unticketed = second.clone();
+        unticketed.leader_proof = None;
+        assert!(engine.leader_score(&unticketed).is_none());
+    }
+
+    #[test]
+    fn test_locked_node_prevotes_nil_for_a_conflicting_block() {
+        // `a` proposes in none of the first rounds, and `a` with two others is a quorum.
+        let mut engine = engine(&[1, 10, 10, 10], 0);
+        let (locked, conflicting) = conflicting_blocks(&engine);
+        let locked_hash = crypto_utils::hash_block(&locked);
+        engine.start_round(0);
+        engine.handle_message(ConsensusMessage::Proposal(proposal(&engine, 0, locked, None, Vec::new())));
+        for index in [1, 2] {
+            engine.handle_message(ConsensusMessage::Vote(vote(index, VoteType::Prevote, 1, 0, Some(locked_hash))));
+        }
+        assert_eq!(engine.round_state.locked_round, Some(0));
+
+        engine.start_round(1);
+        let conflicting_hash = crypto_utils::hash_block(&conflicting);
+        engine.handle_message(ConsensusMessage::Proposal(proposal(&engine, 1, conflicting, None, Vec::new())));
+        assert_eq!(prevote_of(&engine, 0, 1), Some(None));
+        assert_eq!(engine.round_proposal(1).map(|proposal| crypto_utils::hash_block(&proposal.block)), Some(conflicting_hash));
+        assert_eq!(engine.round_state.locked_round, Some(0));
+    }
+
+    #[test]
+    fn test_lock_changes_only_on_prevotes_at_or_after_the_locked_round() {
+        let mut engine = engine(&[1, 10, 10, 10], 0);
+        let (locked, other) = conflicting_blocks(&engine);
+        let (locked_hash, other_hash) = (crypto_utils::hash_block(&locked), crypto_utils::hash_block(&other));
+        engine.start_round(1);
+        engine.handle_message(ConsensusMessage::Proposal(proposal(&engine, 1, locked, None, Vec::new())));
+        for index in [1, 2] {
+            engine.handle_message(ConsensusMessage::Vote(vote(index, VoteType::Prevote, 1, 1, Some(locked_hash))));
+        }
+        assert_eq!(engine.round_state.locked_round, Some(1));
+
+        // Prevotes from round 0 predate the lock and do not release it.
+        engine.start_round(2);
+        let stale_pol = (1..=3).map(|index| vote(index, VoteType::Prevote, 1, 0, Some(other_hash))).collect();
+        engine.handle_message(ConsensusMessage::Proposal(proposal(&engine, 2, other.clone(), Some(0), stale_pol)));
+        assert_eq!(prevote_of(&engine, 0, 2), Some(None));
+
+        // Prevotes from round 2, after the lock, do.
+        engine.start_round(3);
+        let pol = (1..=3).map(|index| vote(index, VoteType::Prevote, 1, 2, Some(other_hash))).collect();
+        engine.handle_message(ConsensusMessage::Proposal(proposal(&engine, 3, other, Some(2), pol)));
+        assert_eq!(prevote_of(&engine, 0, 3), Some(Some(other_hash)));
+    }
+
+    #[test]
+    fn test_timeouts_vote_nil_and_move_to_the_next_round() {
+        // `a` proposes in none of the first rounds, so it waits for a proposal that never comes.
+        let mut engine = engine(&[1, 10, 10, 10], 0);
+        engine.start_round(0);
+        let propose = Timeout { height: 1, round: 0, step: Step::Propose };
+        assert!(engine.scheduled_timeouts.iter().any(|(_, timeout)| *timeout == propose));
+
+        engine.handle_timeout(propose);
+        assert_eq!(engine.round_state.step, Step::Prevote);
+        assert_eq!(prevote_of(&engine, 0, 0), Some(None));
+
+        engine.handle_timeout(Timeout { height: 1, round: 0, step: Step::Prevote });
+        assert_eq!(engine.round_state.step, Step::Precommit);
+        let precommits = &engine.round_state.votes[&(0, VoteType::Precommit)];
+        assert_eq!(precommits.get("a").map(|vote| vote.block_hash), Some(None));
+
+        engine.handle_timeout(Timeout { height: 1, round: 0, step: Step::Precommit });
+        assert_eq!((engine.round_state.round, engine.round_state.step), (1, Step::Propose));
+        assert!(engine
+            .scheduled_timeouts
+            .iter()
+            .any(|(_, timeout)| *timeout == Timeout { height: 1, round: 1, step: Step::Propose }));
+
+        // A timeout of an abandoned round has no effect.
+        engine.handle_timeout(propose);
+        assert_eq!(engine.round_state.step, Step::Propose);
+        assert_eq!(prevote_of(&engine, 0, 1), None);
+
+        // Each failed round waits longer.
+        let config = TimeoutConfig::default();
+        assert!(config.timeout(Step::Propose, 1) > config.timeout(Step::Propose, 0));
+    }
+
+    #[test]
+    fn test_votes_of_over_a_third_in_a_later_round_skip_to_it() {
+        let mut engine = engine(&[1, 10, 10, 10], 0);
+        engine.start_round(0);
+        engine.handle_message(ConsensusMessage::Vote(vote(1, VoteType::Prevote, 1, 3, None)));
+        assert_eq!(engine.round_state.round, 0);
+
+        engine.handle_message(ConsensusMessage::Vote(vote(2, VoteType::Precommit, 1, 3, None)));
+        assert_eq!((engine.round_state.round, engine.round_state.step), (3, Step::Propose));
+    }
+
+    #[test]
+    fn test_epoch_end_rotates_the_validator_set() {
+        let mut engine = engine(&[10], 0);
+        engine.consensus_state.set_epoch_length(1);
+        let public_key = keypair(1).public.to_bytes().to_vec();
+        engine.validator_set.add_validator("b".to_string(), 5, public_key, Commission::default()).unwrap();
+        let seed = engine.validator_set.epoch_seed();
+
+        engine.start_round(0);
+        while engine.apply_round_rules() {}
+
+        // `b` joins at the epoch boundary only, so `a` finalized height 1 alone.
+        assert_eq!(engine.consensus_state.height(), 1);
+        assert_eq!(engine.consensus_state.current_epoch(), 1);
+        assert_eq!(engine.consensus_state.epoch_start_height(), 2);
+        let update = engine.consensus_state.validator_set_updates().last().unwrap();
+        assert_eq!(update.added, vec![("b".to_string(), 5)]);
+        assert_eq!(engine.validator_set.voting_power("b"), 5);
+        assert_ne!(engine.validator_set.epoch_seed(), seed);
+        assert_eq!(engine.round_state.height, 2);
+    }
+
+    #[test]
+    fn test_evidence_is_included_once_and_rejected_after_it_is_committed() {
+        let mut engine = engine(&[1, 10, 10, 10], 0);
+        engine.handle_message(ConsensusMessage::Vote(vote(3, VoteType::Prevote, 1, 5, None)));
+        engine.handle_message(ConsensusMessage::Vote(vote(3, VoteType::Prevote, 1, 5, Some([1; 32]))));
+        let evidence = engine.consensus_state.pending_evidence()[0].clone();
+
+        // A block proposed by another validator carrying the evidence is valid.
+        engine.start_round(0);
+        let block = engine.propose_block(None);
+        assert_eq!(block.evidence.iter().map(Evidence::hash).collect::<Vec<_>>(), vec![evidence.hash()]);
+        let block_hash = crypto_utils::hash_block(&block);
+        engine.handle_message(ConsensusMessage::Proposal(proposal(&engine, 0, block, None, Vec::new())));
+        assert_eq!(prevote_of(&engine, 0, 0), Some(Some(block_hash)));
+        for vote_type in [VoteType::Prevote, VoteType::Precommit] {
+            for index in [1, 2] {
+                engine.handle_message(ConsensusMessage::Vote(vote(index, vote_type, 1, 0, Some(block_hash))));
+            }
+        }
+        assert_eq!(engine.consensus_state.height(), 1);
+        assert!(engine.consensus_state.pending_evidence().is_empty());
+
+        // Committed evidence cannot be included again.
+        let mut replay = engine.propose_block(None);
+        assert!(replay.evidence.is_empty());
+        assert!(engine.validate_block(&replay));
+        replay.add_evidence(evidence);
+        replay.set_hash(crypto_utils::hash_block(&replay));
+        assert!(!engine.validate_block(&replay));
+    }
+
+    #[test]
+    fn test_badly_signed_proposal_is_ignored() {
+        let mut engine = engine(&[1, 10, 10, 10], 0);
+        let (block, _) = conflicting_blocks(&engine);
+        let block_hash = crypto_utils::hash_block(&block);
+        engine.start_round(0);
+
+        // Sent under the round's proposer ID but signed by `a`.
+        let mut forged = proposal(&engine, 0, block.clone(), None, Vec::new());
+        forged.signature = crypto_utils::sign_message(&keypair(0), &forged.sign_bytes());
+        engine.handle_message(ConsensusMessage::Proposal(forged));
+        assert!(engine.round_proposal(0).is_none());
+        assert_eq!(prevote_of(&engine, 0, 0), None);
+
+        engine.handle_message(ConsensusMessage::Proposal(proposal(&engine, 0, block, None, Vec::new())));
+        assert_eq!(prevote_of(&engine, 0, 0), Some(Some(block_hash)));
+        assert!(engine.consensus_state.pending_evidence().is_empty());
+    }
+
+    #[test]
+    fn test_block_time_must_follow_the_parent_without_running_ahead() {
+        let mut engine = engine(&[10], 0);
+        engine.start_round(0);
+        while engine.apply_round_rules() {}
+        let parent_timestamp = engine.consensus_state.get_last_committed_block().unwrap().header.timestamp;
+
+        let block = engine.propose_block(None);
+        assert!(engine.validate_block(&block));
+        let retimed = |timestamp: u64| {
+            let mut block = block.clone();
+            block.header.timestamp = timestamp;
+            block.set_hash(crypto_utils::hash_block(&block));
+            block
+        };
+        assert!(!engine.validate_block(&retimed(parent_timestamp)));
+        let far_ahead = time_utils::current_timestamp() + consensus_engine::MAX_BLOCK_TIME_DRIFT + 60;
+        assert!(!engine.validate_block(&retimed(far_ahead)));
+    }
+
+    #[test]
+    fn test_an_equivocation_is_punished_once_and_tombstones_the_offender() {
+        let mut engine = engine(&[700, 100, 100, 100], 0);
+        let votes: Vec<Vote> = [None, Some([1; 32]), Some([2; 32])]
+            .into_iter()
+            .map(|block_hash| vote(1, VoteType::Prevote, 1, 0, block_hash))
+            .collect();
+        for vote in &votes {
+            engine.handle_message(ConsensusMessage::Vote(vote.clone()));
+        }
+        // The pairs (a, b), (a, c) and (b, c) are all evidence of one offence.
+        let later_pair = Evidence::DuplicateVote(DuplicateVoteEvidence {
+            vote_a: votes[1].clone(),
+            vote_b: votes[2].clone(),
+        });
+        engine.consensus_state.submit_evidence(later_pair.clone(), &engine.validator_set).unwrap();
+        assert_eq!(engine.consensus_state.pending_evidence().len(), 1);
+
+        engine.start_round(0);
+        while engine.apply_round_rules() {}
+
+        assert_eq!(engine.consensus_state.height(), 1);
+        assert_eq!(engine.validator_set.stake_manager().get_stake(&"b".to_string()), Some(&BigDecimal::from(95)));
+        assert!(engine.validator_set.get_validator("b").unwrap().jailed);
+        assert_eq!(engine.validator_set.voting_power("b"), 0);
+        assert_eq!(
+            engine.consensus_state.submit_evidence(later_pair, &engine.validator_set),
+            Err(EvidenceError::AlreadyProcessed)
+        );
+        let other_round = Evidence::DuplicateVote(DuplicateVoteEvidence {
+            vote_a: vote(1, VoteType::Prevote, 1, 1, None),
+            vote_b: vote(1, VoteType::Prevote, 1, 1, Some([1; 32])),
+        });
+        assert_eq!(
+            engine.consensus_state.submit_evidence(other_round, &engine.validator_set),
+            Err(EvidenceError::Tombstoned)
+        );
+
+        let mut unjail = UnjailTransaction { validator_id: "b".to_string(), jailed_until: 0, signature: Vec::new() };
+        unjail.signature = crypto_utils::sign_message(&keypair(1), &unjail.sign_bytes());
+        assert_eq!(engine.validator_set.unjail(&unjail, 1_000_000), Err(ValidatorSetError::Tombstoned));
+    }
+}
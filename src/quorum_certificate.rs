@@ -0,0 +1,221 @@
+// quorum_certificate.rs
+// Compact certificate that a block was precommitted by more than two thirds of the voting power:
+// one aggregated BLS signature and a bitmap of the signers, instead of one signature per signer.
+// It is the commit both engines finalize blocks with, see `pos_algorithm::Commit`.
+
+use serde::{Serialize, Deserialize};
+use crate::pos_algorithm::{Height, Round, Vote, VoteType};
+use crate::utilities::crypto_utils::{self, Hash};
+use crate::validator_set::Validator;
+
+/// Precommits of a block by validators holding more than two thirds of the voting power.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QuorumCertificate {
+    pub height: Height,
+    pub round: Round,
+    pub block_hash: Hash,
+    /// Bit `i` is set if the `i`-th validator of the set, ordered by ID, signed.
+    /// Bits are numbered from the least significant bit of the first byte.
+    pub signers: Vec<u8>,
+    /// Aggregate of the signers' BLS signatures of the precommit.
+    pub signature: Vec<u8>,
+}
+
+/// Reasons a certificate cannot be built or does not verify.
+#[derive(Debug, PartialEq, Eq)]
+pub enum QcError {
+    /// A vote is not a precommit for the certified block, or a validator voted twice.
+    InvalidVote,
+    /// A signer is not in the validator set or has not registered a BLS key.
+    UnknownSigner,
+    /// The bitmap does not match the size of the validator set.
+    InvalidBitmap,
+    /// The signers hold two thirds of the voting power or less.
+    InsufficientPower,
+    InvalidSignature,
+}
+
+impl QuorumCertificate {
+    /// Aggregates the BLS signatures of precommits for `block_hash` at `height` and `round`.
+    /// `validators` is the active set at that height, see `ConsensusState::validator_set_at`.
+    pub fn from_votes(height: Height, round: Round, block_hash: Hash, precommits: &[Vote], validators: &[Validator]) -> Result<Self, QcError> {
+        let ordered = canonical_order(validators);
+        let mut signers = vec![0; bitmap_len(ordered.len())];
+        let mut signatures = Vec::new();
+        for vote in precommits {
+            if vote.vote_type != VoteType::Precommit
+                || vote.height != height
+                || vote.round != round
+                || vote.block_hash != Some(block_hash)
+            {
+                return Err(QcError::InvalidVote);
+            }
+            let index = ordered
+                .iter()
+                .position(|validator| validator.id == vote.validator_id)
+                .ok_or(QcError::UnknownSigner)?;
+            if bit(&signers, index) {
+                return Err(QcError::InvalidVote);
+            }
+            signers[index / 8] |= 1 << (index % 8);
+            signatures.push(vote.bls_signature.as_slice());
+        }
+        let signature = crypto_utils::bls_aggregate(&signatures).ok_or(QcError::InvalidSignature)?;
+        let certificate = QuorumCertificate {
+            height,
+            round,
+            block_hash,
+            signers,
+            signature,
+        };
+        certificate.verify(validators)?;
+        Ok(certificate)
+    }
+
+    /// Returns a certificate for the block consensus starts from when no commit of it is known,
+    /// such as genesis. It has no signers and never verifies.
+    pub fn unsigned(height: Height, round: Round, block_hash: Hash) -> Self {
+        QuorumCertificate {
+            height,
+            round,
+            block_hash,
+            signers: Vec::new(),
+            signature: Vec::new(),
+        }
+    }
+
+    /// Returns the bytes every signer signed: its precommit for the block.
+    pub fn sign_bytes(&self) -> Vec<u8> {
+        Vote::sign_bytes_for(VoteType::Precommit, self.height, self.round, Some(self.block_hash))
+    }
+
+    /// Verifies the certificate against `validators`, the active set at its height.
+    /// The signers must hold more than two thirds of the set's voting power and the aggregate
+    /// signature must verify against their BLS keys.
+    pub fn verify(&self, validators: &[Validator]) -> Result<(), QcError> {
+        let ordered = canonical_order(validators);
+        let unused_bits_set = (ordered.len()..self.signers.len() * 8).any(|index| bit(&self.signers, index));
+        if self.signers.len() != bitmap_len(ordered.len()) || unused_bits_set {
+            return Err(QcError::InvalidBitmap);
+        }
+
+        let total: u64 = ordered.iter().map(|validator| validator.stake).sum();
+        let mut power: u64 = 0;
+        let mut keys = Vec::new();
+        for (index, validator) in ordered.iter().enumerate() {
+            if !bit(&self.signers, index) {
+                continue;
+            }
+            if validator.bls_public_key.is_empty() {
+                return Err(QcError::UnknownSigner);
+            }
+            power += validator.stake;
+            keys.push(validator.bls_public_key.as_slice());
+        }
+        if power as u128 * 3 <= total as u128 * 2 {
+            return Err(QcError::InsufficientPower);
+        }
+        if !crypto_utils::bls_verify_aggregate(&keys, &self.sign_bytes(), &self.signature) {
+            return Err(QcError::InvalidSignature);
+        }
+        Ok(())
+    }
+
+    /// Returns the IDs of the validators that signed, given the set the certificate was built over.
+    pub fn signer_ids<'a>(&self, validators: &'a [Validator]) -> Vec<&'a str> {
+        canonical_order(validators)
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| bit(&self.signers, *index))
+            .map(|(_, validator)| validator.id.as_str())
+            .collect()
+    }
+}
+
+/// Orders a validator set by ID, the order bitmap positions refer to.
+fn canonical_order(validators: &[Validator]) -> Vec<&Validator> {
+    let mut ordered: Vec<&Validator> = validators.iter().collect();
+    ordered.sort_by(|a, b| a.id.cmp(&b.id));
+    ordered
+}
+
+fn bitmap_len(validator_count: usize) -> usize {
+    (validator_count + 7) / 8
+}
+
+fn bit(bitmap: &[u8], index: usize) -> bool {
+    bitmap.get(index / 8).map_or(false, |byte| (byte >> (index % 8)) & 1 == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utilities::crypto_utils::BlsSecretKey;
+    use crate::validator_set::{Commission, ValidatorStatus};
+
+    fn key(seed: u8) -> BlsSecretKey {
+        BlsSecretKey::key_gen(&[seed; 32], &[]).unwrap()
+    }
+
+    fn validator(id: &str, stake: u64, seed: u8) -> Validator {
+        Validator {
+            id: id.to_string(),
+            stake,
+            is_active: true,
+            last_active_epoch: 0,
+            public_key: Vec::new(),
+            bls_public_key: crypto_utils::bls_public_key(&key(seed)),
+            jailed: false,
+            commission: Commission::default(),
+            status: ValidatorStatus::Bonded,
+        }
+    }
+
+    fn certificate(signers: &[(&str, u8)], validators: &[Validator]) -> Result<QuorumCertificate, QcError> {
+        let block_hash = [7; 32];
+        let precommits: Vec<Vote> = signers
+            .iter()
+            .map(|(id, seed)| Vote {
+                vote_type: VoteType::Precommit,
+                height: 5,
+                round: 1,
+                block_hash: Some(block_hash),
+                validator_id: id.to_string(),
+                signature: Vec::new(),
+                bls_signature: crypto_utils::bls_sign(
+                    &key(*seed),
+                    &Vote::sign_bytes_for(VoteType::Precommit, 5, 1, Some(block_hash)),
+                ),
+            })
+            .collect();
+        QuorumCertificate::from_votes(5, 1, block_hash, &precommits, validators)
+    }
+
+    #[test]
+    fn test_certificate_aggregates_and_verifies() {
+        let validators = vec![validator("c", 10, 3), validator("a", 10, 1), validator("b", 15, 2)];
+        let certificate = certificate(&[("b", 2), ("c", 3)], &validators).unwrap();
+
+        assert_eq!(certificate.signers, vec![0b110]);
+        assert_eq!(certificate.signer_ids(&validators), vec!["b", "c"]);
+        assert_eq!(certificate.verify(&validators), Ok(()));
+
+        // Claiming a signer that did not sign breaks the aggregate signature.
+        let mut forged = certificate.clone();
+        forged.signers = vec![0b111];
+        assert_eq!(forged.verify(&validators), Err(QcError::InvalidSignature));
+    }
+
+    #[test]
+    fn test_certificate_needs_more_than_two_thirds() {
+        let validators = vec![validator("a", 10, 1), validator("b", 10, 2), validator("c", 10, 3)];
+        assert_eq!(
+            certificate(&[("a", 1), ("b", 2)], &validators),
+            Err(QcError::InsufficientPower)
+        );
+        assert_eq!(
+            certificate(&[("a", 1), ("d", 4)], &validators),
+            Err(QcError::UnknownSigner)
+        );
+    }
+}
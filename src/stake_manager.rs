@@ -1,80 +1,974 @@
 // stake_manager.rs
 // Manages staking operations, including stake calculation and rewards.
 
-use std::collections::HashMap;
-use big_decimal::BigDecimal;
+use std::collections::{BTreeMap, HashMap};
+use big_decimal::{BigDecimal, RoundingMode};
 use serde::{Serialize, Deserialize};
+use crate::utilities::serde_utils;
+
+/// Default unbonding period: 21 days.
+const DEFAULT_UNBONDING_SECONDS: u64 = 21 * 24 * 60 * 60;
+
+/// Number of decimal places kept for delegation shares and the token amounts derived from them.
+const SHARE_SCALE: i64 = 18;
+
+/// Maximum number of redelegations in progress per (delegator, source, destination) unless configured otherwise.
+const DEFAULT_MAX_REDELEGATION_ENTRIES: usize = 7;
+
+/// Number of blocks of stake history kept for `StakeManager::snapshot_at` unless configured otherwise.
+const DEFAULT_SNAPSHOT_RETENTION: u64 = 100_000;
+
+/// Number of decimal places kept for cumulative rewards per share.
+const REWARD_RATIO_SCALE: i64 = 36;
 
 /// Represents a staker in the VENIA blockchain.
+/// A staker is a validator holding the tokens bonded to it. Its delegators, the validator
+/// itself included, own shares of those tokens, so a slash or reward applied to `tokens`
+/// changes every delegator's balance proportionally without touching the delegations.
 #[derive(Serialize, Deserialize, Debug)]
 struct Staker {
     address: String,
+    /// Tokens bonded to the validator, including its self-delegation.
+    tokens: BigDecimal,
+    /// Total shares issued to the validator's delegators.
+    delegator_shares: BigDecimal,
+    /// Reward period in progress. A period ends whenever `tokens` changes or rewards are withdrawn.
+    current_period: u64,
+    /// Rewards for the validator's delegators accrued during the current period.
+    current_rewards: BigDecimal,
+}
+
+impl Staker {
+    fn new(address: &str) -> Self {
+        Staker {
+            address: address.to_string(),
+            tokens: BigDecimal::from(0),
+            delegator_shares: BigDecimal::from(0),
+            current_period: 1,
+            current_rewards: BigDecimal::from(0),
+        }
+    }
+
+    /// Converts a token amount to shares at the current exchange rate.
+    /// Returns `None` if the validator was slashed to zero tokens, as its shares are then worthless.
+    fn shares_for_tokens(&self, amount: &BigDecimal, rounding: RoundingMode) -> Option<BigDecimal> {
+        if self.delegator_shares == BigDecimal::from(0) {
+            return Some(amount.clone());
+        }
+        if self.tokens == BigDecimal::from(0) {
+            return None;
+        }
+        Some((amount * &self.delegator_shares / &self.tokens).with_scale_round(SHARE_SCALE, rounding))
+    }
+
+    /// Converts shares to the tokens they are worth, rounding down.
+    fn tokens_for_shares(&self, shares: &BigDecimal) -> BigDecimal {
+        if self.delegator_shares == BigDecimal::from(0) {
+            return BigDecimal::from(0);
+        }
+        (shares * &self.tokens / &self.delegator_shares).with_scale_round(SHARE_SCALE, RoundingMode::Down)
+    }
+}
+
+/// Cumulative rewards per token of a validator at the end of a reward period.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct HistoricalRewards {
+    cumulative_reward_ratio: BigDecimal,
+    /// Number of delegations, slash events and current validator periods that refer to this entry.
+    /// The entry is pruned when it drops to zero.
+    reference_count: u32,
+}
+
+/// Where a delegation's reward accounting starts.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct DelegationStart {
+    /// Last validator period that ended before the delegation was created or last modified.
+    previous_period: u64,
+    /// Tokens the delegation was worth at that point.
     stake: BigDecimal,
-    delegation: BigDecimal,
-    rewards: BigDecimal,
+}
+
+/// How long unbonding stake stays locked before it is released.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnbondingPeriod {
+    /// Released after this many blocks.
+    Blocks(u64),
+    /// Released once block time has advanced by this many seconds.
+    Seconds(u64),
+}
+
+/// Stake that is being unbonded.
+/// Until it is released it can still be slashed for infractions committed while it was bonded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnbondingEntry {
+    pub delegator: String,
+    pub validator: String,
+    pub creation_height: u64,
+    /// Height and block time from which the entry can be released.
+    pub completion_height: u64,
+    pub completion_time: u64,
+    /// Amount unbonded; slashes are computed on this amount.
+    pub initial_balance: BigDecimal,
+    /// Amount left after slashes, released to the delegator.
+    pub balance: BigDecimal,
+}
+
+impl UnbondingEntry {
+    /// Returns true if the entry can be released at the given height and block time.
+    pub fn is_mature(&self, height: u64, time: u64) -> bool {
+        height >= self.completion_height && time >= self.completion_time
+    }
+}
+
+/// Stake moved from one validator to another.
+/// Until the unbonding period passes, the source validator can still slash it for infractions
+/// committed while the stake was bonded to it; the penalty is taken from the destination delegation.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RedelegationEntry {
+    pub delegator: String,
+    pub src_validator: String,
+    pub dst_validator: String,
+    pub creation_height: u64,
+    /// Height and block time from which the source validator can no longer slash the entry.
+    pub completion_height: u64,
+    pub completion_time: u64,
+    /// Amount redelegated; slashes are computed on this amount.
+    pub initial_balance: BigDecimal,
+}
+
+impl RedelegationEntry {
+    /// Returns true if the entry is no longer slashable at the given height and block time.
+    pub fn is_mature(&self, height: u64, time: u64) -> bool {
+        height >= self.completion_height && time >= self.completion_time
+    }
+}
+
+/// Stake bonded to each validator as of the end of a block.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StakeSnapshot {
+    height: u64,
+    stakes: BTreeMap<String, BigDecimal>,
+    total_stake: BigDecimal,
+}
+
+impl StakeSnapshot {
+    /// Creates a snapshot of the given stakes taken at `height`.
+    pub fn new(height: u64, stakes: BTreeMap<String, BigDecimal>) -> Self {
+        let total_stake = stakes.values().fold(BigDecimal::from(0), |total, stake| total + stake);
+        StakeSnapshot {
+            height,
+            stakes,
+            total_stake,
+        }
+    }
+
+    /// Returns the height at which the snapshot was taken.
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Returns the tokens bonded to a validator, delegations included.
+    pub fn get_stake(&self, validator: &str) -> Option<&BigDecimal> {
+        self.stakes.get(validator)
+    }
+
+    /// Returns the tokens bonded to all validators.
+    pub fn get_total_stake(&self) -> &BigDecimal {
+        &self.total_stake
+    }
+
+    /// Returns the stake of every validator, ordered by ID.
+    pub fn stakes(&self) -> &BTreeMap<String, BigDecimal> {
+        &self.stakes
+    }
 }
 
 /// The StakeManager responsible for handling staking operations.
+#[derive(Serialize, Deserialize)]
 pub struct StakeManager {
     stakers: HashMap<String, Staker>,
+    /// Shares held by each (delegator, validator) pair.
+    #[serde(with = "serde_utils::map_as_pairs")]
+    delegations: HashMap<(String, String), BigDecimal>,
+    /// Reward accounting start of each (delegator, validator) pair.
+    #[serde(with = "serde_utils::map_as_pairs")]
+    delegation_starts: HashMap<(String, String), DelegationStart>,
+    /// Cumulative rewards per token, keyed by validator and ended period.
+    #[serde(with = "serde_utils::map_as_pairs")]
+    historical_rewards: HashMap<(String, u64), HistoricalRewards>,
+    /// Slashes of each validator as (ended period, fraction), oldest first.
+    slash_events: HashMap<String, Vec<(u64, BigDecimal)>>,
+    /// Rewards settled for each delegator but not yet withdrawn.
+    unclaimed_rewards: HashMap<String, BigDecimal>,
     total_staked: BigDecimal,
-    reward_rate: BigDecimal,
+    unbonding_period: UnbondingPeriod,
+    unbonding_queue: Vec<UnbondingEntry>,
+    redelegations: Vec<RedelegationEntry>,
+    max_redelegation_entries: usize,
+    /// Snapshots taken by `end_block`, keyed by height. A snapshot is only stored when stake changed,
+    /// so the stake at a height is that of the latest snapshot at or below it.
+    snapshots: BTreeMap<u64, StakeSnapshot>,
+    snapshot_retention: u64,
+    /// Height and block time of the last block seen by `end_block`.
+    current_height: u64,
+    current_time: u64,
 }
 
 impl StakeManager {
     /// Initializes a new StakeManager.
-    pub fn new(reward_rate: BigDecimal) -> StakeManager {
+    pub fn new() -> StakeManager {
         StakeManager {
             stakers: HashMap::new(),
+            delegations: HashMap::new(),
+            delegation_starts: HashMap::new(),
+            historical_rewards: HashMap::new(),
+            slash_events: HashMap::new(),
+            unclaimed_rewards: HashMap::new(),
             total_staked: BigDecimal::from(0),
-            reward_rate,
+            unbonding_period: UnbondingPeriod::Seconds(DEFAULT_UNBONDING_SECONDS),
+            unbonding_queue: Vec::new(),
+            redelegations: Vec::new(),
+            max_redelegation_entries: DEFAULT_MAX_REDELEGATION_ENTRIES,
+            snapshots: BTreeMap::new(),
+            snapshot_retention: DEFAULT_SNAPSHOT_RETENTION,
+            current_height: 0,
+            current_time: 0,
         }
     }
 
-    /// Adds or updates a staker's stake.
-    pub fn set_stake(&mut self, address: String, stake: BigDecimal) {
-        // Implementation details...
+    /// Bonds additional self-stake for a validator, registering the validator as a staker if needed.
+    /// Self-stake is held as a delegation from the validator to itself.
+    pub fn add_stake(&mut self, address: &str, amount: BigDecimal) -> Result<(), StakeError> {
+        if !self.stakers.contains_key(address) {
+            self.stakers.insert(address.to_string(), Staker::new(address));
+            self.historical_rewards.insert(
+                (address.to_string(), 0),
+                HistoricalRewards {
+                    cumulative_reward_ratio: BigDecimal::from(0),
+                    reference_count: 1,
+                },
+            );
+        }
+        self.delegate_stake(address.to_string(), address.to_string(), amount)?;
+        Ok(())
     }
 
-    /// Calculates the total stake in the network.
-    fn calculate_total_stake(&self) {
-        // Implementation details...
+    /// Unbonds all of a validator's self-stake.
+    /// The stake is queued for release after the unbonding period instead of disappearing;
+    /// stake delegated to the validator stays until its delegators undelegate it.
+    pub fn remove_stake(&mut self, address: &str) {
+        let balance = self.get_delegation_balance(address, address);
+        if balance > BigDecimal::from(0) {
+            let _ = self.undelegate_stake(address.to_string(), address.to_string(), balance);
+        }
     }
 
-    /// Distributes rewards to stakers based on their stake.
-    fn distribute_rewards(&mut self) {
-        // Implementation details...
+    /// Distributes rewards to a validator's delegators based on their stake.
+    /// Rewards are added to the validator's current period in O(1); each delegator's share is only
+    /// computed when it withdraws or modifies its delegation.
+    pub fn distribute_rewards(&mut self, validator: &str, amount: BigDecimal) -> Result<(), StakeError> {
+        let staker = self.stakers.get_mut(validator).ok_or(StakeError::UnknownStaker)?;
+        staker.current_rewards += amount;
+        Ok(())
     }
 
-    /// Calculates rewards for a single staker.
-    fn calculate_reward(&self, staker: &Staker) -> BigDecimal {
-        // Implementation details...
+    /// Withdraws the rewards a delegator has accrued with a validator, together with rewards
+    /// settled earlier when its delegations changed. Returns the amount to credit to the delegator.
+    pub fn withdraw_rewards(&mut self, delegator: &str, validator: &str) -> Result<BigDecimal, StakeError> {
+        let key = (delegator.to_string(), validator.to_string());
+        if !self.delegation_starts.contains_key(&key) && !self.unclaimed_rewards.contains_key(delegator) {
+            return Err(StakeError::NoDelegation);
+        }
+        if self.delegation_starts.contains_key(&key) {
+            self.before_delegation_modified(delegator, validator);
+            self.initialize_delegation(delegator, validator);
+        }
+        Ok(self
+            .unclaimed_rewards
+            .remove(delegator)
+            .unwrap_or_else(|| BigDecimal::from(0)))
+    }
+
+    /// Calculates the rewards a delegation accrued from its start up to `ending_period`.
+    /// Slashes split the range into segments, each earning on the stake left after the previous slash.
+    fn calculate_reward(&self, validator: &str, start: &DelegationStart, ending_period: u64) -> BigDecimal {
+        let mut stake = start.stake.clone();
+        let mut starting_period = start.previous_period;
+        let mut rewards = BigDecimal::from(0);
+
+        if let Some(events) = self.slash_events.get(validator) {
+            for (period, fraction) in events
+                .iter()
+                .filter(|(period, _)| *period > start.previous_period && *period <= ending_period)
+            {
+                rewards += self.rewards_between(validator, starting_period, *period, &stake);
+                stake = (&stake * (BigDecimal::from(1) - fraction)).with_scale_round(SHARE_SCALE, RoundingMode::Down);
+                starting_period = *period;
+            }
+        }
+        rewards += self.rewards_between(validator, starting_period, ending_period, &stake);
+        rewards.with_scale_round(SHARE_SCALE, RoundingMode::Down)
+    }
+
+    /// Returns the rewards earned by `stake` between two ended periods of a validator.
+    fn rewards_between(&self, validator: &str, starting_period: u64, ending_period: u64, stake: &BigDecimal) -> BigDecimal {
+        let ratio_at = |period: u64| {
+            self.historical_rewards
+                .get(&(validator.to_string(), period))
+                .map(|entry| entry.cumulative_reward_ratio.clone())
+                .unwrap_or_else(|| BigDecimal::from(0))
+        };
+        stake * (ratio_at(ending_period) - ratio_at(starting_period))
+    }
+
+    /// Ends the validator's current reward period, recording the cumulative rewards per token.
+    /// Returns the period that ended.
+    fn increment_validator_period(&mut self, validator: &str) -> u64 {
+        let staker = match self.stakers.get_mut(validator) {
+            Some(staker) => staker,
+            None => return 0,
+        };
+        // Rewards accrued while nothing is bonded cannot be attributed to anyone and are dropped.
+        let ratio = if staker.tokens == BigDecimal::from(0) {
+            BigDecimal::from(0)
+        } else {
+            (&staker.current_rewards / &staker.tokens).with_scale_round(REWARD_RATIO_SCALE, RoundingMode::Down)
+        };
+        let ended = staker.current_period;
+        staker.current_rewards = BigDecimal::from(0);
+        staker.current_period += 1;
+
+        let previous = self
+            .historical_rewards
+            .get(&(validator.to_string(), ended - 1))
+            .map(|entry| entry.cumulative_reward_ratio.clone())
+            .unwrap_or_else(|| BigDecimal::from(0));
+        self.historical_rewards.insert(
+            (validator.to_string(), ended),
+            HistoricalRewards {
+                cumulative_reward_ratio: previous + ratio,
+                reference_count: 1,
+            },
+        );
+        // The validator's current period now refers to the entry just created.
+        self.release_historical_rewards(validator, ended - 1);
+        ended
+    }
+
+    /// Ends the validator's period and settles a delegation's accrued rewards before its stake changes.
+    fn before_delegation_modified(&mut self, delegator: &str, validator: &str) {
+        let ended = self.increment_validator_period(validator);
+        let key = (delegator.to_string(), validator.to_string());
+        if let Some(start) = self.delegation_starts.remove(&key) {
+            let rewards = self.calculate_reward(validator, &start, ended);
+            self.release_historical_rewards(validator, start.previous_period);
+            *self
+                .unclaimed_rewards
+                .entry(delegator.to_string())
+                .or_insert_with(|| BigDecimal::from(0)) += rewards;
+        }
+        // The delegation restarts after every existing slash, so older slashes may now be unused.
+        if self.slash_events.contains_key(validator) {
+            self.prune_slash_events(validator);
+        }
+    }
+
+    /// Drops the validator's slash events that no delegation's reward accounting starts before,
+    /// releasing the historical rewards they refer to.
+    fn prune_slash_events(&mut self, validator: &str) {
+        let oldest_start = self
+            .delegation_starts
+            .iter()
+            .filter(|((_, delegatee), _)| delegatee == validator)
+            .map(|(_, start)| start.previous_period)
+            .min();
+        let events = match self.slash_events.get_mut(validator) {
+            Some(events) => events,
+            None => return,
+        };
+        // `calculate_reward` only applies events after a delegation's start.
+        let (kept, pruned): (Vec<_>, Vec<_>) = events
+            .drain(..)
+            .partition(|(period, _)| oldest_start.map_or(false, |oldest| *period > oldest));
+        if kept.is_empty() {
+            self.slash_events.remove(validator);
+        } else {
+            *events = kept;
+        }
+        for (period, _) in pruned {
+            self.release_historical_rewards(validator, period);
+        }
+    }
+
+    /// Starts reward accounting for a delegation at the validator's last ended period.
+    fn initialize_delegation(&mut self, delegator: &str, validator: &str) {
+        let previous_period = match self.stakers.get(validator) {
+            Some(staker) => staker.current_period - 1,
+            None => return,
+        };
+        let stake = self.get_delegation_balance(delegator, validator);
+        self.retain_historical_rewards(validator, previous_period);
+        self.delegation_starts.insert(
+            (delegator.to_string(), validator.to_string()),
+            DelegationStart { previous_period, stake },
+        );
+    }
+
+    fn retain_historical_rewards(&mut self, validator: &str, period: u64) {
+        if let Some(entry) = self.historical_rewards.get_mut(&(validator.to_string(), period)) {
+            entry.reference_count += 1;
+        }
+    }
+
+    fn release_historical_rewards(&mut self, validator: &str, period: u64) {
+        let key = (validator.to_string(), period);
+        if let Some(entry) = self.historical_rewards.get_mut(&key) {
+            entry.reference_count -= 1;
+            if entry.reference_count == 0 {
+                self.historical_rewards.remove(&key);
+            }
+        }
     }
 
     /// Delegates stake to another staker.
-    pub fn delegate_stake(&mut self, delegator: String, delegatee: String, amount: BigDecimal) {
-        // Implementation details...
+    /// The delegator receives shares of the delegatee's tokens at the current exchange rate,
+    /// rounded down. Returns the shares issued.
+    pub fn delegate_stake(&mut self, delegator: String, delegatee: String, amount: BigDecimal) -> Result<BigDecimal, StakeError> {
+        if amount <= BigDecimal::from(0) {
+            return Err(StakeError::InvalidAmount);
+        }
+        let shares = self
+            .stakers
+            .get(&delegatee)
+            .ok_or(StakeError::UnknownStaker)?
+            .shares_for_tokens(&amount, RoundingMode::Down)
+            .ok_or(StakeError::ValidatorFullySlashed)?;
+
+        self.before_delegation_modified(&delegator, &delegatee);
+        let staker = self.stakers.get_mut(&delegatee).ok_or(StakeError::UnknownStaker)?;
+        staker.tokens += &amount;
+        staker.delegator_shares += &shares;
+        self.total_staked += amount;
+        *self
+            .delegations
+            .entry((delegator.clone(), delegatee.clone()))
+            .or_insert_with(|| BigDecimal::from(0)) += &shares;
+        self.initialize_delegation(&delegator, &delegatee);
+        Ok(shares)
     }
 
     /// Handles undelegation of stake.
-    pub fn undelegate_stake(&mut self, delegator: String, delegatee: String, amount: BigDecimal) {
-        // Implementation details...
+    /// The shares worth `amount` are burned, rounding up, and the amount stops counting towards the
+    /// delegatee's stake immediately. It is released to the delegator after the unbonding period;
+    /// until then it can still be slashed.
+    pub fn undelegate_stake(&mut self, delegator: String, delegatee: String, amount: BigDecimal) -> Result<(), StakeError> {
+        self.unbond(&delegator, &delegatee, &amount)?;
+        self.queue_unbonding(delegator, delegatee, amount);
+        Ok(())
+    }
+
+    /// Moves bonded stake from one validator to another without waiting for the unbonding period.
+    /// The source validator can still slash the moved stake until the unbonding period passes.
+    /// Stake that is itself being redelegated into `src_validator` cannot be moved on until then.
+    /// Both legs are checked before either is applied, so a failed redelegation changes nothing.
+    /// Returns the shares issued by the destination validator.
+    pub fn redelegate_stake(
+        &mut self,
+        delegator: String,
+        src_validator: String,
+        dst_validator: String,
+        amount: BigDecimal,
+    ) -> Result<BigDecimal, StakeError> {
+        if src_validator == dst_validator {
+            return Err(StakeError::SelfRedelegation);
+        }
+        self.unbond_shares(&delegator, &src_validator, &amount)?;
+        self.stakers
+            .get(&dst_validator)
+            .ok_or(StakeError::UnknownStaker)?
+            .shares_for_tokens(&amount, RoundingMode::Down)
+            .ok_or(StakeError::ValidatorFullySlashed)?;
+        if self
+            .redelegations
+            .iter()
+            .any(|entry| entry.delegator == delegator && entry.dst_validator == src_validator)
+        {
+            return Err(StakeError::TransitiveRedelegation);
+        }
+        let entries = self
+            .redelegations
+            .iter()
+            .filter(|entry| {
+                entry.delegator == delegator && entry.src_validator == src_validator && entry.dst_validator == dst_validator
+            })
+            .count();
+        if entries >= self.max_redelegation_entries {
+            return Err(StakeError::TooManyRedelegations);
+        }
+
+        self.unbond(&delegator, &src_validator, &amount)?;
+        let shares = self.delegate_stake(delegator.clone(), dst_validator.clone(), amount.clone())?;
+        let (completion_height, completion_time) = self.unbonding_completion();
+        self.redelegations.push(RedelegationEntry {
+            delegator,
+            src_validator,
+            dst_validator,
+            creation_height: self.current_height,
+            completion_height,
+            completion_time,
+            initial_balance: amount,
+        });
+        Ok(shares)
+    }
+
+    /// Sets how many redelegations may be in progress per (delegator, source, destination).
+    pub fn update_max_redelegation_entries(&mut self, max_entries: usize) {
+        self.max_redelegation_entries = max_entries;
+    }
+
+    /// Returns the redelegations still slashable by their source validator.
+    pub fn get_redelegations(&self) -> &[RedelegationEntry] {
+        &self.redelegations
+    }
+
+    /// Burns the delegator's shares worth `amount`, rounding up, and removes the amount from the validator's tokens.
+    fn unbond(&mut self, delegator: &str, delegatee: &str, amount: &BigDecimal) -> Result<(), StakeError> {
+        let shares = self.unbond_shares(delegator, delegatee, amount)?;
+        let key = (delegator.to_string(), delegatee.to_string());
+        let remaining = &self.delegations[&key] - &shares;
+
+        self.before_delegation_modified(delegator, delegatee);
+        let staker = self.stakers.get_mut(delegatee).ok_or(StakeError::UnknownStaker)?;
+        staker.tokens -= amount;
+        staker.delegator_shares -= &shares;
+        self.total_staked -= amount;
+
+        if remaining == BigDecimal::from(0) {
+            self.delegations.remove(&key);
+        } else {
+            self.delegations.insert(key, remaining);
+            self.initialize_delegation(delegator, delegatee);
+        }
+        Ok(())
+    }
+
+    /// Returns the shares `unbond` would burn to take `amount` out of the delegation, rounding
+    /// up, without changing anything.
+    fn unbond_shares(&self, delegator: &str, delegatee: &str, amount: &BigDecimal) -> Result<BigDecimal, StakeError> {
+        if *amount <= BigDecimal::from(0) {
+            return Err(StakeError::InvalidAmount);
+        }
+        let staker = self.stakers.get(delegatee).ok_or(StakeError::UnknownStaker)?;
+        let held = self
+            .delegations
+            .get(&(delegator.to_string(), delegatee.to_string()))
+            .ok_or(StakeError::NoDelegation)?;
+        if *amount > staker.tokens_for_shares(held) {
+            return Err(StakeError::InsufficientStake);
+        }
+        Ok(staker
+            .shares_for_tokens(amount, RoundingMode::Up)
+            .ok_or(StakeError::ValidatorFullySlashed)?
+            .min(held.clone()))
+    }
+
+    /// Returns the shares a delegator holds in a validator.
+    pub fn get_delegation_shares(&self, delegator: &str, validator: &str) -> Option<&BigDecimal> {
+        self.delegations.get(&(delegator.to_string(), validator.to_string()))
+    }
+
+    /// Returns the shares held by every (delegator, validator) pair, in no particular order.
+    pub fn delegations(&self) -> impl Iterator<Item = (&(String, String), &BigDecimal)> {
+        self.delegations.iter()
+    }
+
+    /// Returns the outstanding reward accounting as state tree entries, keyed as listed in
+    /// `ConsensusState::state_tree`: each validator's period in progress with its shares and
+    /// rewards, the cumulative reward ratios of ended periods, where each delegation's accounting
+    /// starts, slash events, and rewards settled but not withdrawn.
+    pub fn reward_records(&self) -> Vec<(String, Vec<u8>)> {
+        let mut records = Vec::new();
+        for staker in self.stakers.values() {
+            records.push((
+                format!("rewards/current/{}", staker.address),
+                format!("{}:{}:{}", staker.current_period, staker.delegator_shares, staker.current_rewards).into_bytes(),
+            ));
+        }
+        for ((validator, period), historical) in &self.historical_rewards {
+            records.push((
+                format!("rewards/historical/{}/{}", validator, period),
+                format!("{}:{}", historical.cumulative_reward_ratio, historical.reference_count).into_bytes(),
+            ));
+        }
+        for ((delegator, validator), start) in &self.delegation_starts {
+            records.push((
+                format!("rewards/start/{}/{}", delegator, validator),
+                format!("{}:{}", start.previous_period, start.stake).into_bytes(),
+            ));
+        }
+        for (validator, events) in &self.slash_events {
+            let events = serde_json::to_vec(events).expect("slash events are serializable");
+            records.push((format!("rewards/slashes/{}", validator), events));
+        }
+        for (delegator, amount) in &self.unclaimed_rewards {
+            records.push((format!("rewards/unclaimed/{}", delegator), amount.to_string().into_bytes()));
+        }
+        records
+    }
+
+    /// Returns the tokens a delegator's shares in a validator are currently worth.
+    pub fn get_delegation_balance(&self, delegator: &str, validator: &str) -> BigDecimal {
+        match (self.get_delegation_shares(delegator, validator), self.stakers.get(validator)) {
+            (Some(shares), Some(staker)) => staker.tokens_for_shares(shares),
+            _ => BigDecimal::from(0),
+        }
+    }
+
+    /// Updates how long unbonding stake stays locked. Entries already queued keep their completion time.
+    pub fn update_unbonding_period(&mut self, unbonding_period: UnbondingPeriod) {
+        self.unbonding_period = unbonding_period;
+    }
+
+    /// Returns the entries still unbonding.
+    pub fn get_unbonding_entries(&self) -> &[UnbondingEntry] {
+        &self.unbonding_queue
+    }
+
+    /// End-block hook. Records the height and block time and a snapshot of the block's final stake,
+    /// then releases every matured unbonding entry and stops tracking matured redelegations.
+    /// Returns the released entries so their balances can be credited to the delegators.
+    pub fn end_block(&mut self, height: u64, time: u64) -> Vec<UnbondingEntry> {
+        self.current_height = height;
+        self.current_time = time;
+        self.record_snapshot(height);
+        self.redelegations.retain(|entry| !entry.is_mature(height, time));
+        let (matured, pending): (Vec<_>, Vec<_>) = self
+            .unbonding_queue
+            .drain(..)
+            .partition(|entry| entry.is_mature(height, time));
+        self.unbonding_queue = pending;
+        matured
+    }
+
+    /// Queues an unbonding entry that completes one unbonding period after the current block.
+    fn queue_unbonding(&mut self, delegator: String, validator: String, amount: BigDecimal) {
+        let (completion_height, completion_time) = self.unbonding_completion();
+        self.unbonding_queue.push(UnbondingEntry {
+            delegator,
+            validator,
+            creation_height: self.current_height,
+            completion_height,
+            completion_time,
+            initial_balance: amount.clone(),
+            balance: amount,
+        });
+    }
+
+    /// Returns the stake bonded to each validator right now.
+    pub fn get_current_stake_snapshot(&self) -> StakeSnapshot {
+        let stakes = self
+            .stakers
+            .iter()
+            .map(|(address, staker)| (address.clone(), staker.tokens.clone()))
+            .collect();
+        StakeSnapshot::new(self.current_height, stakes)
+    }
+
+    /// Returns the stake as of the end of block `height`, or `None` if that height is not yet
+    /// finalized or its history has been pruned.
+    /// This is a query for light clients and RPC; consensus uses the current stake.
+    pub fn snapshot_at(&self, height: u64) -> Option<&StakeSnapshot> {
+        if height > self.current_height {
+            return None;
+        }
+        self.snapshots.range(..=height).next_back().map(|(_, snapshot)| snapshot)
+    }
+
+    /// Sets how many blocks of stake history are kept.
+    pub fn update_snapshot_retention(&mut self, retention: u64) {
+        self.snapshot_retention = retention;
+    }
+
+    /// Stores the stake at the end of block `height` if it changed since the last snapshot,
+    /// and prunes history older than the retention window.
+    fn record_snapshot(&mut self, height: u64) {
+        let snapshot = self.get_current_stake_snapshot();
+        let changed = self
+            .snapshots
+            .values()
+            .next_back()
+            .map_or(true, |last| last.stakes != snapshot.stakes);
+        if changed {
+            self.snapshots.insert(height, snapshot);
+        }
+
+        let oldest = height.saturating_sub(self.snapshot_retention);
+        // The latest snapshot at or below the oldest retained height still describes it, so it is kept.
+        if let Some(&anchor) = self.snapshots.range(..=oldest).next_back().map(|(h, _)| h) {
+            self.snapshots = self.snapshots.split_off(&anchor);
+        }
+    }
+
+    /// Returns the height and block time one unbonding period after the current block.
+    pub fn unbonding_completion(&self) -> (u64, u64) {
+        match self.unbonding_period {
+            UnbondingPeriod::Blocks(blocks) => (self.current_height + blocks, 0),
+            UnbondingPeriod::Seconds(seconds) => (0, self.current_time + seconds),
+        }
+    }
+
+    /// Slashes a fraction of a validator's own stake and of the stake delegated to it.
+    /// Only the validator's tokens are reduced, which lowers the exchange rate of every delegator's shares.
+    /// Stake that started unbonding or was redelegated away from the validator at or after
+    /// `infraction_height` was bonded when the infraction happened, so it is slashed as well;
+    /// redelegated stake is taken from the destination delegation.
+    /// Returns the total amount slashed.
+    pub fn slash(&mut self, address: &str, fraction: &BigDecimal, infraction_height: u64) -> BigDecimal {
+        let mut slashed = BigDecimal::from(0);
+
+        let redelegated: Vec<(String, String, BigDecimal)> = self
+            .redelegations
+            .iter()
+            .filter(|entry| entry.src_validator == address && entry.creation_height >= infraction_height)
+            .map(|entry| (entry.delegator.clone(), entry.dst_validator.clone(), &entry.initial_balance * fraction))
+            .collect();
+        for (delegator, dst_validator, penalty) in redelegated {
+            let penalty = penalty
+                .with_scale_round(SHARE_SCALE, RoundingMode::Up)
+                .min(self.get_delegation_balance(&delegator, &dst_validator));
+            // A destination delegation that was withdrawn in the meantime has nothing left to slash.
+            if penalty > BigDecimal::from(0) && self.unbond(&delegator, &dst_validator, &penalty).is_ok() {
+                slashed += penalty;
+            }
+        }
+
+        for entry in self
+            .unbonding_queue
+            .iter_mut()
+            .filter(|entry| entry.validator == address && entry.creation_height >= infraction_height)
+        {
+            let penalty = (&entry.initial_balance * fraction).min(entry.balance.clone());
+            entry.balance -= &penalty;
+            slashed += penalty;
+        }
+
+        if self.stakers.contains_key(address) {
+            let ended = self.increment_validator_period(address);
+            self.retain_historical_rewards(address, ended);
+            self.slash_events
+                .entry(address.to_string())
+                .or_default()
+                .push((ended, fraction.clone()));
+        }
+        if let Some(staker) = self.stakers.get_mut(address) {
+            let bonded_penalty = (&staker.tokens * fraction).with_scale_round(SHARE_SCALE, RoundingMode::Up);
+            staker.tokens -= &bonded_penalty;
+            self.total_staked -= &bonded_penalty;
+            slashed += bonded_penalty;
+        }
+        slashed
     }
 
-    /// Retrieves the stake for a given address.
+    /// Retrieves the stake for a given address: all tokens bonded to it, delegations included.
     pub fn get_stake(&self, address: &String) -> Option<&BigDecimal> {
-        self.stakers.get(address).map(|s| &s.stake)
+        self.stakers.get(address).map(|s| &s.tokens)
     }
 
     /// Retrieves the total staked amount in the network.
     pub fn get_total_staked(&self) -> &BigDecimal {
         &self.total_staked
     }
+}
 
-    /// Updates the reward rate.
-    pub fn update_reward_rate(&mut self, new_rate: BigDecimal) {
-        self.reward_rate = new_rate;
+impl Default for StakeManager {
+    fn default() -> Self {
+        StakeManager::new()
     }
 }
 
+/// Errors returned by staking operations.
+#[derive(Debug, PartialEq, Eq)]
+pub enum StakeError {
+    UnknownStaker,
+    NoDelegation,
+    InsufficientStake,
+    InvalidAmount,
+    ValidatorFullySlashed,
+    /// The source and destination of a redelegation are the same validator.
+    SelfRedelegation,
+    /// The stake is still being redelegated into the source validator.
+    TransitiveRedelegation,
+    /// The delegator has too many redelegations in progress between the two validators.
+    TooManyRedelegations,
+}
+
 // Additional utility functions and types may be added here.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn dec(value: &str) -> BigDecimal {
+        BigDecimal::from_str(value).unwrap()
+    }
+
+    #[test]
+    fn test_slash_reduces_all_delegations_proportionally() {
+        let mut manager = StakeManager::new();
+        manager.add_stake("val", dec("100")).unwrap();
+        manager.delegate_stake("alice".to_string(), "val".to_string(), dec("300")).unwrap();
+
+        manager.slash("val", &dec("0.1"), 0);
+
+        assert_eq!(manager.get_delegation_balance("val", "val"), dec("90"));
+        assert_eq!(manager.get_delegation_balance("alice", "val"), dec("270"));
+        assert_eq!(manager.get_total_staked(), &dec("360"));
+    }
+
+    #[test]
+    fn test_delegation_after_slash_uses_exchange_rate() {
+        let mut manager = StakeManager::new();
+        manager.add_stake("val", dec("100")).unwrap();
+        manager.slash("val", &dec("0.5"), 0);
+
+        let shares = manager.delegate_stake("bob".to_string(), "val".to_string(), dec("50")).unwrap();
+
+        assert_eq!(shares, dec("100"));
+        assert_eq!(manager.get_delegation_balance("bob", "val"), dec("50"));
+        assert_eq!(manager.get_delegation_balance("val", "val"), dec("50"));
+    }
+
+    #[test]
+    fn test_undelegated_stake_is_released_after_unbonding_period() {
+        let mut manager = StakeManager::new();
+        manager.update_unbonding_period(UnbondingPeriod::Blocks(10));
+        manager.add_stake("val", dec("100")).unwrap();
+        manager.delegate_stake("alice".to_string(), "val".to_string(), dec("50")).unwrap();
+
+        manager.undelegate_stake("alice".to_string(), "val".to_string(), dec("20")).unwrap();
+
+        assert_eq!(manager.get_delegation_balance("alice", "val"), dec("30"));
+        assert!(manager.end_block(9, 0).is_empty());
+        let released = manager.end_block(10, 0);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].balance, dec("20"));
+    }
+
+    #[test]
+    fn test_rewards_are_shared_by_stake_across_slashes() {
+        let mut manager = StakeManager::new();
+        manager.add_stake("val", dec("100")).unwrap();
+        manager.delegate_stake("alice".to_string(), "val".to_string(), dec("300")).unwrap();
+
+        manager.distribute_rewards("val", dec("40")).unwrap();
+        assert_eq!(manager.withdraw_rewards("alice", "val").unwrap(), dec("30"));
+        assert_eq!(manager.withdraw_rewards("val", "val").unwrap(), dec("10"));
+
+        manager.slash("val", &dec("0.5"), 0);
+        manager.distribute_rewards("val", dec("20")).unwrap();
+        assert_eq!(manager.withdraw_rewards("alice", "val").unwrap(), dec("15"));
+    }
+
+    #[test]
+    fn test_slash_events_are_pruned_once_no_delegation_starts_before_them() {
+        let mut manager = StakeManager::new();
+        manager.add_stake("val", dec("100")).unwrap();
+        manager.delegate_stake("alice".to_string(), "val".to_string(), dec("100")).unwrap();
+        manager.slash("val", &dec("0.1"), 0);
+        let slash_period = manager.slash_events["val"][0].0;
+
+        manager.withdraw_rewards("val", "val").unwrap();
+        assert_eq!(manager.slash_events["val"].len(), 1, "alice's delegation still starts before the slash");
+
+        manager.distribute_rewards("val", dec("18")).unwrap();
+        assert_eq!(manager.withdraw_rewards("alice", "val").unwrap(), dec("9"));
+        assert!(manager.slash_events.get("val").is_none());
+        assert!(!manager.historical_rewards.contains_key(&("val".to_string(), slash_period)));
+    }
+
+    #[test]
+    fn test_rewards_are_settled_when_delegation_changes() {
+        let mut manager = StakeManager::new();
+        manager.add_stake("val", dec("100")).unwrap();
+        manager.delegate_stake("alice".to_string(), "val".to_string(), dec("100")).unwrap();
+        manager.distribute_rewards("val", dec("20")).unwrap();
+
+        manager.delegate_stake("alice".to_string(), "val".to_string(), dec("200")).unwrap();
+        manager.distribute_rewards("val", dec("40")).unwrap();
+
+        assert_eq!(manager.withdraw_rewards("alice", "val").unwrap(), dec("40"));
+    }
+
+    #[test]
+    fn test_redelegated_stake_stays_slashable_by_source() {
+        let mut manager = StakeManager::new();
+        manager.update_unbonding_period(UnbondingPeriod::Blocks(10));
+        for validator in ["a", "b", "c"] {
+            manager.add_stake(validator, dec("100")).unwrap();
+        }
+        manager.delegate_stake("alice".to_string(), "a".to_string(), dec("100")).unwrap();
+        manager.end_block(5, 0);
+
+        manager.redelegate_stake("alice".to_string(), "a".to_string(), "b".to_string(), dec("50")).unwrap();
+        assert_eq!(manager.get_delegation_balance("alice", "b"), dec("50"));
+        assert_eq!(
+            manager.redelegate_stake("alice".to_string(), "b".to_string(), "c".to_string(), dec("10")),
+            Err(StakeError::TransitiveRedelegation)
+        );
+
+        manager.slash("a", &dec("0.1"), 3);
+        assert_eq!(manager.get_delegation_balance("alice", "b"), dec("45"));
+
+        manager.end_block(15, 0);
+        assert!(manager.get_redelegations().is_empty());
+        manager.redelegate_stake("alice".to_string(), "b".to_string(), "c".to_string(), dec("10")).unwrap();
+    }
+
+    #[test]
+    fn test_failed_redelegation_leaves_the_source_delegation_untouched() {
+        let mut manager = StakeManager::new();
+        manager.add_stake("a", dec("100")).unwrap();
+        manager.add_stake("b", dec("100")).unwrap();
+        manager.delegate_stake("alice".to_string(), "a".to_string(), dec("100")).unwrap();
+        let redelegate = |manager: &mut StakeManager, dst: &str, amount: &str| {
+            manager.redelegate_stake("alice".to_string(), "a".to_string(), dst.to_string(), dec(amount))
+        };
+
+        assert_eq!(redelegate(&mut manager, "missing", "50"), Err(StakeError::UnknownStaker));
+        assert_eq!(redelegate(&mut manager, "b", "0"), Err(StakeError::InvalidAmount));
+        assert_eq!(redelegate(&mut manager, "b", "101"), Err(StakeError::InsufficientStake));
+        assert_eq!(manager.get_delegation_balance("alice", "a"), dec("100"));
+        assert_eq!(manager.get_total_staked(), &dec("300"));
+        assert!(manager.get_redelegations().is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_at_returns_stake_as_of_height() {
+        let mut manager = StakeManager::new();
+        manager.update_snapshot_retention(10);
+        manager.add_stake("val", dec("100")).unwrap();
+        manager.end_block(1, 0);
+        manager.end_block(2, 0);
+        manager.delegate_stake("alice".to_string(), "val".to_string(), dec("50")).unwrap();
+        manager.end_block(3, 0);
+
+        assert_eq!(manager.snapshot_at(2).unwrap().get_stake("val"), Some(&dec("100")));
+        assert_eq!(manager.snapshot_at(3).unwrap().get_total_stake(), &dec("150"));
+        assert!(manager.snapshot_at(4).is_none());
+
+        manager.end_block(20, 0);
+        assert!(manager.snapshot_at(2).is_none());
+        assert_eq!(manager.snapshot_at(10).unwrap().get_stake("val"), Some(&dec("150")));
+    }
+
+    #[test]
+    fn test_undelegate_more_than_balance_fails() {
+        let mut manager = StakeManager::new();
+        manager.add_stake("val", dec("100")).unwrap();
+
+        let result = manager.undelegate_stake("val".to_string(), "val".to_string(), dec("101"));
+
+        assert_eq!(result, Err(StakeError::InsufficientStake));
+    }
+}
@@ -0,0 +1,107 @@
+// staking_transaction.rs
+// Staking operations submitted as transactions and executed when their block is finalized.
+
+use big_decimal::BigDecimal;
+use serde::{Serialize, Deserialize};
+use crate::validator_set::{BlsKeyRegistration, UnjailTransaction, ValidatorSet, ValidatorSetError};
+
+/// A staking operation, carried JSON-encoded in a transaction's payload.
+/// The sender's account is the delegator. This crate keeps no balances: the account layer that
+/// verified the transaction debits delegated amounts, and credits the payouts returned by `execute`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum StakingTransaction {
+    Delegate { validator: String, amount: BigDecimal },
+    /// Starts unbonding; the amount is paid out once the unbonding period has passed.
+    Undelegate { validator: String, amount: BigDecimal },
+    Redelegate { src_validator: String, dst_validator: String, amount: BigDecimal },
+    /// Withdraws the rewards the sender accrued with a validator.
+    WithdrawRewards { validator: String },
+    /// Signed by the validator's own key, so the sender is not checked.
+    Unjail(UnjailTransaction),
+    /// Registers the BLS key the validator signs votes with. Signed by the validator's own key.
+    RegisterBlsKey(BlsKeyRegistration),
+}
+
+/// Tokens owed to an account by finalized staking operations, to be credited by the account layer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Payout {
+    pub account: String,
+    pub amount: BigDecimal,
+}
+
+impl StakingTransaction {
+    /// Decodes the staking operation in a transaction payload, or returns `None` if the payload
+    /// carries none.
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        serde_json::from_slice(payload).ok()
+    }
+
+    /// Executes the operation for the account `sender` in a block at `height`.
+    /// Returns the payout it produced, if any.
+    pub fn execute(&self, sender: &str, validator_set: &mut ValidatorSet, height: u64) -> Result<Option<Payout>, ValidatorSetError> {
+        match self {
+            StakingTransaction::Delegate { validator, amount } => {
+                validator_set
+                    .stake_manager_mut()
+                    .delegate_stake(sender.to_string(), validator.clone(), amount.clone())
+                    .map_err(ValidatorSetError::Stake)?;
+            }
+            StakingTransaction::Undelegate { validator, amount } => {
+                validator_set
+                    .stake_manager_mut()
+                    .undelegate_stake(sender.to_string(), validator.clone(), amount.clone())
+                    .map_err(ValidatorSetError::Stake)?;
+            }
+            StakingTransaction::Redelegate { src_validator, dst_validator, amount } => {
+                validator_set
+                    .stake_manager_mut()
+                    .redelegate_stake(sender.to_string(), src_validator.clone(), dst_validator.clone(), amount.clone())
+                    .map_err(ValidatorSetError::Stake)?;
+            }
+            StakingTransaction::WithdrawRewards { validator } => {
+                let amount = validator_set
+                    .stake_manager_mut()
+                    .withdraw_rewards(sender, validator)
+                    .map_err(ValidatorSetError::Stake)?;
+                return Ok(Some(Payout { account: sender.to_string(), amount }));
+            }
+            StakingTransaction::Unjail(transaction) => validator_set.unjail(transaction, height)?,
+            StakingTransaction::RegisterBlsKey(registration) => validator_set.register_bls_key(registration)?,
+        }
+        Ok(None)
+    }
+}
+
+/// Returns the account ID of a transaction sender: the lowercase hex encoding of its public key.
+pub fn account_id(sender: &[u8]) -> String {
+    sender.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stake_manager::StakeManager;
+    use crate::validator_set::Commission;
+
+    #[test]
+    fn test_decoded_operations_change_the_validator_set_stake() {
+        let mut validator_set = ValidatorSet::new(StakeManager::new());
+        validator_set.add_validator("a".to_string(), 10, vec![1; 32], Commission::default()).unwrap();
+        let delegate = StakingTransaction::Delegate { validator: "a".to_string(), amount: BigDecimal::from(5) };
+        let payload = serde_json::to_vec(&delegate).unwrap();
+        let sender = account_id(&[0xab, 0x01]);
+        assert_eq!(sender, "ab01");
+
+        let operation = StakingTransaction::decode(&payload).unwrap();
+        assert_eq!(operation.execute(&sender, &mut validator_set, 1), Ok(None));
+        assert_eq!(validator_set.stake_manager().get_stake(&"a".to_string()), Some(&BigDecimal::from(15)));
+
+        let withdraw = StakingTransaction::WithdrawRewards { validator: "a".to_string() };
+        let payout = withdraw.execute(&sender, &mut validator_set, 1).unwrap().unwrap();
+        assert_eq!(payout.account, sender);
+
+        let unknown = StakingTransaction::Delegate { validator: "b".to_string(), amount: BigDecimal::from(5) };
+        assert!(unknown.execute(&sender, &mut validator_set, 1).is_err());
+        assert!(StakingTransaction::decode(b"transfer").is_none());
+    }
+}
@@ -0,0 +1,215 @@
+// storage.rs
+// Storage backends for blocks, commits, validator sets, stake history and consensus metadata.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use serde::{de::DeserializeOwned, Serialize};
+use blockchain_types::Block;
+use crate::pos_algorithm::{Commit, Height};
+use crate::stake_manager::StakeSnapshot;
+use crate::validator_set::Validator;
+
+/// Errors returned by storage backends.
+#[derive(Debug)]
+pub enum StorageError {
+    Io(io::Error),
+    /// A stored value could not be encoded or decoded.
+    Serialization(String),
+}
+
+impl From<io::Error> for StorageError {
+    fn from(err: io::Error) -> Self {
+        StorageError::Io(err)
+    }
+}
+
+/// Key-value store that consensus data is persisted to.
+/// Backends implement the raw accessors; the typed accessors encode values as JSON
+/// under keys that sort by height.
+pub trait ConsensusStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError>;
+    /// Stores a value. When this returns, the value survives a crash.
+    fn put(&mut self, key: &str, value: Vec<u8>) -> Result<(), StorageError>;
+    fn delete(&mut self, key: &str) -> Result<(), StorageError>;
+
+    fn put_block(&mut self, height: Height, block: &Block) -> Result<(), StorageError> {
+        put_json(self, &height_key("block", height), block)
+    }
+
+    fn get_block(&self, height: Height) -> Result<Option<Block>, StorageError> {
+        get_json(self, &height_key("block", height))
+    }
+
+    fn put_commit(&mut self, height: Height, commit: &Commit) -> Result<(), StorageError> {
+        put_json(self, &height_key("commit", height), commit)
+    }
+
+    fn get_commit(&self, height: Height) -> Result<Option<Commit>, StorageError> {
+        get_json(self, &height_key("commit", height))
+    }
+
+    /// Stores the active validator set that starts validating at `height`.
+    fn put_validator_set(&mut self, height: Height, validators: &[Validator]) -> Result<(), StorageError> {
+        put_json(self, &height_key("validator_set", height), &validators)
+    }
+
+    fn get_validator_set(&self, height: Height) -> Result<Option<Vec<Validator>>, StorageError> {
+        get_json(self, &height_key("validator_set", height))
+    }
+
+    fn put_stake_snapshot(&mut self, snapshot: &StakeSnapshot) -> Result<(), StorageError> {
+        put_json(self, &height_key("stake_snapshot", snapshot.height()), snapshot)
+    }
+
+    fn get_stake_snapshot(&self, height: Height) -> Result<Option<StakeSnapshot>, StorageError> {
+        get_json(self, &height_key("stake_snapshot", height))
+    }
+}
+
+/// Encodes `value` as JSON and stores it under `key`.
+pub fn put_json<S, T>(store: &mut S, key: &str, value: &T) -> Result<(), StorageError>
+where
+    S: ConsensusStore + ?Sized,
+    T: Serialize + ?Sized,
+{
+    let bytes = serde_json::to_vec(value).map_err(|err| StorageError::Serialization(err.to_string()))?;
+    store.put(key, bytes)
+}
+
+/// Loads the JSON value stored under `key`, if any.
+pub fn get_json<S, T>(store: &S, key: &str) -> Result<Option<T>, StorageError>
+where
+    S: ConsensusStore + ?Sized,
+    T: DeserializeOwned,
+{
+    match store.get(key)? {
+        Some(bytes) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|err| StorageError::Serialization(err.to_string())),
+        None => Ok(None),
+    }
+}
+
+/// Builds the key of a per-height record. Heights are zero-padded so keys sort by height.
+fn height_key(kind: &str, height: Height) -> String {
+    format!("{}/{:020}", kind, height)
+}
+
+/// Store keeping everything in memory. Nothing survives a restart; intended for tests and tools.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    entries: BTreeMap<String, Vec<u8>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+impl ConsensusStore for MemoryStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.entries.get(key).cloned())
+    }
+
+    fn put(&mut self, key: &str, value: Vec<u8>) -> Result<(), StorageError> {
+        self.entries.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StorageError> {
+        self.entries.remove(key);
+        Ok(())
+    }
+}
+
+/// Store keeping one file per key under a root directory.
+/// Values are written to a temporary file, synced and renamed into place, so a crash
+/// leaves either the old or the new value.
+#[derive(Debug)]
+pub struct FileStore {
+    root: PathBuf,
+}
+
+impl FileStore {
+    /// Opens the store rooted at `root`, creating the directory if needed.
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self, StorageError> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(FileStore { root })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+impl ConsensusStore for FileStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        match fs::read(self.path(key)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn put(&mut self, key: &str, value: Vec<u8>) -> Result<(), StorageError> {
+        let path = self.path(key);
+        let dir = path.parent().unwrap_or(&self.root).to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&value)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        // Make the rename itself durable.
+        File::open(&dir)?.sync_all()?;
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StorageError> {
+        match fs::remove_file(self.path(key)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use big_decimal::BigDecimal;
+
+    fn snapshot(height: Height) -> StakeSnapshot {
+        let mut stakes = BTreeMap::new();
+        stakes.insert("a".to_string(), BigDecimal::from(10));
+        StakeSnapshot::new(height, stakes)
+    }
+
+    fn round_trip(store: &mut dyn ConsensusStore) {
+        assert!(store.get_stake_snapshot(5).unwrap().is_none());
+        store.put_stake_snapshot(&snapshot(5)).unwrap();
+        assert_eq!(store.get_stake_snapshot(5).unwrap(), Some(snapshot(5)));
+
+        store.delete(&height_key("stake_snapshot", 5)).unwrap();
+        assert!(store.get_stake_snapshot(5).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_memory_store_round_trip() {
+        round_trip(&mut MemoryStore::new());
+    }
+
+    #[test]
+    fn test_file_store_round_trip_and_reopen() {
+        let root = std::env::temp_dir().join(format!("venia-store-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        round_trip(&mut FileStore::open(&root).unwrap());
+
+        FileStore::open(&root).unwrap().put_stake_snapshot(&snapshot(7)).unwrap();
+        assert_eq!(FileStore::open(&root).unwrap().get_stake_snapshot(7).unwrap(), Some(snapshot(7)));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
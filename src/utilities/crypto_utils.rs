@@ -1 +1,166 @@
 // Cryptographic utilities used in the consensus process.
+
+use blockchain_types::Block;
+use blst::min_pk as bls;
+use blst::BLST_ERROR;
+use merlin::Transcript;
+use schnorrkel::vrf::{VRFPreOut, VRFProof};
+use schnorrkel::{signing_context, Keypair, PublicKey, Signature};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+/// 32-byte digest used to identify blocks and consensus messages.
+pub type Hash = [u8; 32];
+
+/// Signing context shared by all consensus messages.
+const CONSENSUS_SIGNING_CONTEXT: &[u8] = b"venia-consensus";
+
+/// Signing context of the leader-election VRF.
+const VRF_SIGNING_CONTEXT: &[u8] = b"venia-leader-vrf";
+
+/// Context used to derive randomness from a VRF output.
+const VRF_RANDOMNESS_CONTEXT: &[u8] = b"venia-leader-randomness";
+
+/// Domain separation tag of BLS vote signatures (proof-of-possession scheme).
+const BLS_SIGNATURE_DST: &[u8] = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_";
+
+/// Domain separation tag of BLS proofs of possession.
+const BLS_POP_DST: &[u8] = b"BLS_POP_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_";
+
+/// Secret key validators sign votes with so that their signatures can be aggregated.
+pub type BlsSecretKey = bls::SecretKey;
+
+/// Output and proof of a leader-election VRF evaluation, as carried in block headers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct VrfProof {
+    /// VRF pre-output; the randomness is derived from it once the proof is verified.
+    pub output: [u8; 32],
+    pub proof: Vec<u8>,
+}
+
+/// Computes the SHA-256 digest of the given bytes.
+pub fn hash_bytes(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+/// Computes the hash identifying a block.
+/// The whole block is hashed, transactions and evidence included, so votes for the hash commit to
+/// its contents. The hash stored in the block is cleared first, so the result does not depend on it.
+pub fn hash_block(block: &Block) -> Hash {
+    let mut unhashed = block.clone();
+    unhashed.set_hash([0; 32]);
+    hash_bytes(&serde_json::to_vec(&unhashed).expect("block is serializable"))
+}
+
+/// Signs a consensus message with the local validator key.
+pub fn sign_message(keypair: &Keypair, message: &[u8]) -> Vec<u8> {
+    keypair
+        .sign(signing_context(CONSENSUS_SIGNING_CONTEXT).bytes(message))
+        .to_bytes()
+        .to_vec()
+}
+
+/// Verifies a consensus message signature against the signer's public key.
+pub fn verify_signature(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let (public_key, signature) = match (PublicKey::from_bytes(public_key), Signature::from_bytes(signature)) {
+        (Ok(public_key), Ok(signature)) => (public_key, signature),
+        _ => return false,
+    };
+    public_key
+        .verify(signing_context(CONSENSUS_SIGNING_CONTEXT).bytes(message), &signature)
+        .is_ok()
+}
+
+/// Evaluates the leader-election VRF for the slot at `height` and `round` under the epoch seed.
+/// Returns the proof to publish and the randomness it yields.
+pub fn vrf_evaluate(keypair: &Keypair, seed: &Hash, height: u64, round: u32) -> (VrfProof, Hash) {
+    let (inout, proof, _) = keypair.vrf_sign(vrf_transcript(seed, height, round));
+    let vrf_proof = VrfProof {
+        output: inout.to_preout().to_bytes(),
+        proof: proof.to_bytes().to_vec(),
+    };
+    (vrf_proof, inout.make_bytes(VRF_RANDOMNESS_CONTEXT))
+}
+
+/// Verifies a leader-election VRF proof against the evaluator's public key.
+/// Returns the randomness the proof yields, or `None` if the key or proof is invalid.
+pub fn verify_vrf(public_key: &[u8], seed: &Hash, height: u64, round: u32, vrf_proof: &VrfProof) -> Option<Hash> {
+    let public_key = PublicKey::from_bytes(public_key).ok()?;
+    let output = VRFPreOut::from_bytes(&vrf_proof.output).ok()?;
+    let proof = VRFProof::from_bytes(&vrf_proof.proof).ok()?;
+    let (inout, _) = public_key
+        .vrf_verify(vrf_transcript(seed, height, round), &output, &proof)
+        .ok()?;
+    Some(inout.make_bytes(VRF_RANDOMNESS_CONTEXT))
+}
+
+/// Builds the VRF input for a slot.
+fn vrf_transcript(seed: &Hash, height: u64, round: u32) -> Transcript {
+    let mut message = seed.to_vec();
+    message.extend_from_slice(&height.to_be_bytes());
+    message.extend_from_slice(&round.to_be_bytes());
+    signing_context(VRF_SIGNING_CONTEXT).bytes(&message)
+}
+
+/// Signs a consensus message with a BLS key.
+pub fn bls_sign(secret_key: &BlsSecretKey, message: &[u8]) -> Vec<u8> {
+    secret_key.sign(message, BLS_SIGNATURE_DST, &[]).to_bytes().to_vec()
+}
+
+/// Returns the public key of a BLS secret key.
+pub fn bls_public_key(secret_key: &BlsSecretKey) -> Vec<u8> {
+    secret_key.sk_to_pk().to_bytes().to_vec()
+}
+
+/// Signs the key's own public key, proving knowledge of the secret key.
+/// Aggregate verification is only sound for keys registered with such a proof, since otherwise a
+/// validator could pick a key that cancels out the keys of others.
+pub fn bls_proof_of_possession(secret_key: &BlsSecretKey) -> Vec<u8> {
+    let public_key = bls_public_key(secret_key);
+    secret_key.sign(&public_key, BLS_POP_DST, &[]).to_bytes().to_vec()
+}
+
+/// Verifies a proof of possession for a BLS public key.
+pub fn verify_bls_proof_of_possession(public_key: &[u8], proof: &[u8]) -> bool {
+    let (key, proof) = match (bls::PublicKey::key_validate(public_key), bls::Signature::from_bytes(proof)) {
+        (Ok(key), Ok(proof)) => (key, proof),
+        _ => return false,
+    };
+    proof.verify(true, public_key, BLS_POP_DST, &[], &key, false) == BLST_ERROR::BLST_SUCCESS
+}
+
+/// Verifies a BLS signature of `message` by `public_key`, which must have been registered with a
+/// proof of possession.
+pub fn bls_verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    bls_verify_aggregate(&[public_key], message, signature)
+}
+
+/// Aggregates BLS signatures into one. Returns `None` if any signature is malformed or none is given.
+pub fn bls_aggregate(signatures: &[&[u8]]) -> Option<Vec<u8>> {
+    let signatures = signatures
+        .iter()
+        .map(|signature| bls::Signature::from_bytes(signature).ok())
+        .collect::<Option<Vec<_>>>()?;
+    let refs: Vec<&bls::Signature> = signatures.iter().collect();
+    let aggregate = bls::AggregateSignature::aggregate(&refs, true).ok()?;
+    Some(aggregate.to_signature().to_bytes().to_vec())
+}
+
+/// Verifies an aggregate BLS signature of `message` by all of `public_keys`.
+/// The keys must have been registered with a proof of possession.
+pub fn bls_verify_aggregate(public_keys: &[&[u8]], message: &[u8], signature: &[u8]) -> bool {
+    let keys = match public_keys
+        .iter()
+        .map(|key| bls::PublicKey::key_validate(key).ok())
+        .collect::<Option<Vec<_>>>()
+    {
+        Some(keys) if !keys.is_empty() => keys,
+        _ => return false,
+    };
+    let signature = match bls::Signature::from_bytes(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    let refs: Vec<&bls::PublicKey> = keys.iter().collect();
+    signature.fast_aggregate_verify(true, message, BLS_SIGNATURE_DST, &refs) == BLST_ERROR::BLST_SUCCESS
+}
@@ -0,0 +1,191 @@
+// Sparse Merkle tree used to commit to consensus state.
+
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+use crate::utilities::crypto_utils::{hash_bytes, Hash};
+
+/// Root of an empty subtree.
+pub const EMPTY_ROOT: Hash = [0; 32];
+
+/// Domain separators keeping leaf and inner node hashes apart.
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// Sparse Merkle tree over 256-bit key hashes.
+/// A leaf sits at the path given by the bits of its key hash. A subtree holding a single leaf
+/// is represented by that leaf, so the tree is only as deep as needed to separate its keys,
+/// and the root does not depend on the order in which keys were inserted.
+#[derive(Debug, Clone, Default)]
+pub struct SparseMerkleTree {
+    /// Value hashes by key hash.
+    leaves: BTreeMap<Hash, Hash>,
+}
+
+/// Path from the root towards a key, proving either that the key is present with a given value
+/// or that it is absent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    /// Roots of the sibling subtrees along the path, from the root downwards.
+    pub siblings: Vec<Hash>,
+    /// Key hash and value hash of the leaf the path ends at, or `None` if it ends at an empty subtree.
+    pub leaf: Option<(Hash, Hash)>,
+}
+
+impl SparseMerkleTree {
+    pub fn new() -> Self {
+        SparseMerkleTree::default()
+    }
+
+    /// Sets the value stored under `key`.
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) {
+        self.leaves.insert(hash_bytes(key), hash_bytes(value));
+    }
+
+    /// Removes `key` from the tree.
+    pub fn remove(&mut self, key: &[u8]) {
+        self.leaves.remove(&hash_bytes(key));
+    }
+
+    /// Returns the root hash committing to every key and value in the tree.
+    pub fn root(&self) -> Hash {
+        let leaves: Vec<(Hash, Hash)> = self.leaves.iter().map(|(k, v)| (*k, *v)).collect();
+        subtree_root(&leaves, 0)
+    }
+
+    /// Returns a proof of the presence or absence of `key`.
+    pub fn prove(&self, key: &[u8]) -> MerkleProof {
+        let key_hash = hash_bytes(key);
+        let leaves: Vec<(Hash, Hash)> = self.leaves.iter().map(|(k, v)| (*k, *v)).collect();
+        let mut path = &leaves[..];
+        let mut siblings = Vec::new();
+        let mut depth = 0;
+        while path.len() > 1 {
+            let (left, right) = path.split_at(split_index(path, depth));
+            if bit(&key_hash, depth) {
+                siblings.push(subtree_root(left, depth + 1));
+                path = right;
+            } else {
+                siblings.push(subtree_root(right, depth + 1));
+                path = left;
+            }
+            depth += 1;
+        }
+        MerkleProof {
+            siblings,
+            leaf: path.first().copied(),
+        }
+    }
+}
+
+impl MerkleProof {
+    /// Returns true if the proof shows that `key` holds `value` in the tree with the given root.
+    pub fn verify_inclusion(&self, root: &Hash, key: &[u8], value: &[u8]) -> bool {
+        let key_hash = hash_bytes(key);
+        self.leaf == Some((key_hash, hash_bytes(value))) && self.computed_root(&key_hash) == *root
+    }
+
+    /// Returns true if the proof shows that `key` is absent from the tree with the given root.
+    /// The path must end at an empty subtree or at a different key sharing the path to it.
+    pub fn verify_exclusion(&self, root: &Hash, key: &[u8]) -> bool {
+        let key_hash = hash_bytes(key);
+        let ends_elsewhere = match &self.leaf {
+            None => true,
+            Some((leaf_key, _)) => {
+                *leaf_key != key_hash && (0..self.siblings.len()).all(|depth| bit(leaf_key, depth) == bit(&key_hash, depth))
+            }
+        };
+        ends_elsewhere && self.computed_root(&key_hash) == *root
+    }
+
+    /// Folds the siblings from the bottom of the path up to the root.
+    fn computed_root(&self, key_hash: &Hash) -> Hash {
+        let mut hash = match &self.leaf {
+            Some((leaf_key, value_hash)) => leaf_hash(leaf_key, value_hash),
+            None => EMPTY_ROOT,
+        };
+        for (depth, sibling) in self.siblings.iter().enumerate().rev() {
+            hash = if bit(key_hash, depth) {
+                node_hash(sibling, &hash)
+            } else {
+                node_hash(&hash, sibling)
+            };
+        }
+        hash
+    }
+}
+
+/// Returns the root of the subtree at `depth` holding `leaves`, which are sorted by key hash.
+fn subtree_root(leaves: &[(Hash, Hash)], depth: usize) -> Hash {
+    match leaves {
+        [] => EMPTY_ROOT,
+        [(key_hash, value_hash)] => leaf_hash(key_hash, value_hash),
+        _ => {
+            let (left, right) = leaves.split_at(split_index(leaves, depth));
+            node_hash(&subtree_root(left, depth + 1), &subtree_root(right, depth + 1))
+        }
+    }
+}
+
+/// Returns the index of the first leaf whose key hash has bit `depth` set.
+fn split_index(leaves: &[(Hash, Hash)], depth: usize) -> usize {
+    leaves.partition_point(|(key_hash, _)| !bit(key_hash, depth))
+}
+
+/// Returns bit `depth` of a key hash, most significant bit first.
+fn bit(key_hash: &Hash, depth: usize) -> bool {
+    (key_hash[depth / 8] >> (7 - depth % 8)) & 1 == 1
+}
+
+fn leaf_hash(key_hash: &Hash, value_hash: &Hash) -> Hash {
+    hash_bytes(&[&[LEAF_PREFIX][..], key_hash, value_hash].concat())
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    hash_bytes(&[&[NODE_PREFIX][..], left, right].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(entries: &[(&str, &str)]) -> SparseMerkleTree {
+        let mut tree = SparseMerkleTree::new();
+        for (key, value) in entries {
+            tree.insert(key.as_bytes(), value.as_bytes());
+        }
+        tree
+    }
+
+    #[test]
+    fn test_root_is_independent_of_insertion_order() {
+        let entries = [("a", "1"), ("b", "2"), ("c", "3"), ("d", "4")];
+        let reversed: Vec<_> = entries.iter().rev().copied().collect();
+
+        assert_eq!(tree(&entries).root(), tree(&reversed).root());
+        assert_ne!(tree(&entries).root(), tree(&entries[..3]).root());
+        assert_eq!(SparseMerkleTree::new().root(), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn test_inclusion_proofs_verify() {
+        let tree = tree(&[("a", "1"), ("b", "2"), ("c", "3"), ("d", "4"), ("e", "5")]);
+        let root = tree.root();
+        for (key, value) in [("a", "1"), ("c", "3"), ("e", "5")] {
+            let proof = tree.prove(key.as_bytes());
+            assert!(proof.verify_inclusion(&root, key.as_bytes(), value.as_bytes()));
+            assert!(!proof.verify_inclusion(&root, key.as_bytes(), b"other"));
+            assert!(!proof.verify_exclusion(&root, key.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn test_exclusion_proofs_verify() {
+        let tree = tree(&[("a", "1"), ("b", "2"), ("c", "3")]);
+        let root = tree.root();
+        for key in ["x", "y", "z"] {
+            let proof = tree.prove(key.as_bytes());
+            assert!(proof.verify_exclusion(&root, key.as_bytes()));
+            assert!(!proof.verify_exclusion(&[1; 32], key.as_bytes()));
+        }
+    }
+}
@@ -0,0 +1,6 @@
+// Shared utilities for the consensus modules.
+
+pub mod crypto_utils;
+pub mod merkle;
+pub mod serde_utils;
+pub mod time_utils;
@@ -0,0 +1,27 @@
+// Serde helpers for types JSON cannot encode directly.
+
+/// Serializes a map as a sequence of key-value pairs, so that maps with non-string keys
+/// such as tuples can be stored as JSON. Use with `#[serde(with = "serde_utils::map_as_pairs")]`.
+pub mod map_as_pairs {
+    use std::collections::HashMap;
+    use std::hash::Hash;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Eq + Hash,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Vec::<(K, V)>::deserialize(deserializer).map(|pairs| pairs.into_iter().collect())
+    }
+}
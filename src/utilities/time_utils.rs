@@ -1 +1,13 @@
 // Utility functions for time-based calculations.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Returns the current Unix time in seconds, the unit of block timestamps.
+pub fn current_timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs()
+}
+
+/// Returns the time remaining until `deadline`, or zero if it has already passed.
+pub fn time_until(deadline: Instant) -> Duration {
+    deadline.saturating_duration_since(Instant::now())
+}
@@ -1,23 +1,284 @@
 // validator_set.rs
 // Manages the set of validators, including selection and rotation.
 
-use std::collections::HashMap;
-use crate::stake_manager::StakeManager;
-use crate::utilities::crypto_utils;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::str::FromStr;
+use big_decimal::{BigDecimal, RoundingMode, ToPrimitive};
+use schnorrkel::Keypair;
+use serde::{Serialize, Deserialize};
+use crate::stake_manager::{StakeError, StakeManager, UnbondingEntry};
+use crate::utilities::crypto_utils::{self, BlsSecretKey, Hash, VrfProof};
+
+/// Number of decimal places kept when splitting block rewards between validators.
+const REWARD_SCALE: i64 = 18;
+
+/// Expected number of validators winning the VRF leader lottery of a round. Several winners are
+/// ranked by ticket; more than one expected winner keeps rounds without a proposer rare.
+const LEADER_LOTTERY_EXPECTED_WINNERS: u128 = 5;
 
 // Struct representing a validator.
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Validator {
     pub id: String,
     pub stake: u64,
     pub is_active: bool,
     pub last_active_epoch: u64,
+    /// sr25519 public key used to verify the validator's signatures and VRF proofs.
+    pub public_key: Vec<u8>,
+    /// BLS public key used to verify aggregated vote signatures; empty until registered.
+    #[serde(default)]
+    pub bls_public_key: Vec<u8>,
+    /// Jailed validators are left out of the active set from the next epoch until they unjail.
+    pub jailed: bool,
+    pub commission: Commission,
+    pub status: ValidatorStatus,
+}
+
+/// Bonding state of a validator.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidatorStatus {
+    /// Not in the active set and no longer slashable for past infractions.
+    Unbonded,
+    /// In the active set.
+    Bonded,
+    /// Left the active set; infractions committed while bonded can still be slashed until
+    /// the given height and block time.
+    Unbonding { completion_height: u64, completion_time: u64 },
+}
+
+/// Limits on who can validate.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StakingParams {
+    /// Smallest self-delegation a validator needs to be created and to stay eligible.
+    pub min_self_delegation: u64,
+    /// Largest number of active validators; the ones with the most bonded stake are chosen.
+    pub max_validators: usize,
+}
+
+impl Default for StakingParams {
+    fn default() -> Self {
+        StakingParams {
+            min_self_delegation: 1,
+            max_validators: 100,
+        }
+    }
+}
+
+/// Minimum number of seconds between two commission rate changes.
+const COMMISSION_UPDATE_INTERVAL: u64 = 24 * 60 * 60;
+
+/// Share of a validator's rewards kept by its operator before delegators are paid.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Commission {
+    pub rate: BigDecimal,
+    /// Upper bound of `rate`, fixed when the validator is created.
+    pub max_rate: BigDecimal,
+    /// Largest change of `rate` allowed per update, fixed when the validator is created.
+    pub max_change_rate: BigDecimal,
+    /// Block time of the last rate change, in seconds.
+    pub update_time: u64,
+}
+
+impl Commission {
+    /// Creates a commission, checking that `0 <= rate <= max_rate <= 1` and `0 <= max_change_rate <= max_rate`.
+    pub fn new(rate: BigDecimal, max_rate: BigDecimal, max_change_rate: BigDecimal) -> Result<Self, ValidatorSetError> {
+        let zero = BigDecimal::from(0);
+        if rate < zero || rate > max_rate || max_rate > BigDecimal::from(1) {
+            return Err(ValidatorSetError::InvalidCommission);
+        }
+        if max_change_rate < zero || max_change_rate > max_rate {
+            return Err(ValidatorSetError::InvalidCommission);
+        }
+        Ok(Commission {
+            rate,
+            max_rate,
+            max_change_rate,
+            update_time: 0,
+        })
+    }
+}
+
+impl Default for Commission {
+    /// No commission, and no room to introduce one.
+    fn default() -> Self {
+        Commission {
+            rate: BigDecimal::from(0),
+            max_rate: BigDecimal::from(0),
+            max_change_rate: BigDecimal::from(0),
+            update_time: 0,
+        }
+    }
+}
+
+/// Parameters of downtime tracking.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LivenessParams {
+    /// Number of most recent blocks over which signatures are counted.
+    pub signed_blocks_window: u64,
+    /// Minimum fraction of the window a validator must sign to avoid jailing.
+    pub min_signed_per_window: BigDecimal,
+    /// Number of blocks a jailed validator must wait before it can unjail.
+    pub downtime_jail_duration: u64,
+    /// Fraction of stake slashed when a validator is jailed for downtime.
+    pub slash_fraction_downtime: BigDecimal,
+}
+
+impl Default for LivenessParams {
+    fn default() -> Self {
+        LivenessParams {
+            signed_blocks_window: 10_000,
+            min_signed_per_window: BigDecimal::from_str("0.5").expect("valid decimal"),
+            downtime_jail_duration: 10_000,
+            slash_fraction_downtime: BigDecimal::from_str("0.0001").expect("valid decimal"),
+        }
+    }
+}
+
+/// Signatures of an active validator over the most recent blocks.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct SigningInfo {
+    /// One entry per block in the window, oldest first; `true` marks a missed block.
+    missed_blocks: VecDeque<bool>,
+    missed_count: u64,
+    /// First height at which the validator may unjail.
+    jailed_until: u64,
+    /// Set once the validator was punished for double-signing; it stays jailed for good.
+    #[serde(default)]
+    tombstoned: bool,
+}
+
+/// A request, signed by the validator's key, to rejoin the active set after a downtime jail.
+/// It names the jail it ends by the jail's release height, so it cannot be replayed to end a later one.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnjailTransaction {
+    pub validator_id: String,
+    /// First height at which the validator may unjail, see `ValidatorSet::jailed_until`.
+    pub jailed_until: u64,
+    pub signature: Vec<u8>,
+}
+
+impl UnjailTransaction {
+    /// Returns the bytes covered by the transaction signature.
+    pub fn sign_bytes(&self) -> Vec<u8> {
+        let mut bytes = b"unjail:".to_vec();
+        bytes.extend_from_slice(self.validator_id.as_bytes());
+        bytes.extend_from_slice(&self.jailed_until.to_be_bytes());
+        bytes
+    }
+}
+
+/// A request, signed by the validator's key, to register the BLS key it signs votes with.
+/// It names the key it replaces, so it cannot be replayed once the key has changed again.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlsKeyRegistration {
+    pub validator_id: String,
+    /// The validator's BLS key before the registration; empty for its first key.
+    pub previous_key: Vec<u8>,
+    pub public_key: Vec<u8>,
+    /// The new key's signature of itself, see `crypto_utils::bls_proof_of_possession`.
+    pub proof_of_possession: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl BlsKeyRegistration {
+    /// Creates a registration of `bls_key` replacing `previous_key`, signed with the validator's key.
+    pub fn sign(validator_id: &str, previous_key: Vec<u8>, bls_key: &BlsSecretKey, keypair: &Keypair) -> Self {
+        let mut registration = BlsKeyRegistration {
+            validator_id: validator_id.to_string(),
+            previous_key,
+            public_key: crypto_utils::bls_public_key(bls_key),
+            proof_of_possession: crypto_utils::bls_proof_of_possession(bls_key),
+            signature: Vec::new(),
+        };
+        registration.signature = crypto_utils::sign_message(keypair, &registration.sign_bytes());
+        registration
+    }
+
+    /// Returns the bytes covered by the registration signature.
+    pub fn sign_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&("register-bls-key", &self.validator_id, &self.previous_key, &self.public_key))
+            .expect("registration fields are serializable")
+    }
+}
+
+/// Errors returned by validator set operations.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ValidatorSetError {
+    Stake(StakeError),
+    UnknownValidator,
+    InvalidSignature,
+    NotJailed,
+    StillJailed,
+    /// The unjail transaction is for an earlier jail of the validator.
+    StaleUnjail,
+    /// The validator was jailed for double-signing and can never unjail.
+    Tombstoned,
+    /// The commission rate is negative or above its maximum.
+    InvalidCommission,
+    /// The validator's self-delegation is below the minimum.
+    InsufficientSelfBond,
+    /// The commission rate changed by more than its maximum change rate.
+    CommissionChangeTooLarge,
+    /// The commission rate already changed within the last day.
+    CommissionUpdateTooSoon,
+    /// The BLS public key is malformed or its proof of possession does not verify.
+    InvalidBlsKey,
+    /// The BLS key registration replaces a key the validator no longer has.
+    StaleBlsKeyRegistration,
+}
+
+/// Errors returned when distributing a block reward.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RewardError {
+    NegativeReward,
+    /// No active validator has voting power to be rewarded for.
+    NoVotingPower,
+    /// The combined voting power does not fit in a `u64`.
+    Overflow,
+}
+
+/// Changes to the active validator set that take effect at the start of an epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ValidatorSetUpdate {
+    pub epoch: u64,
+    /// First height validated by the new set.
+    pub start_height: u64,
+    /// Validators that joined the active set, with their voting power.
+    pub added: Vec<(String, u64)>,
+    /// Validators that left the active set.
+    pub removed: Vec<String>,
+    /// Validators that stayed active with a different voting power.
+    pub power_changes: Vec<(String, u64)>,
+}
+
+/// How the proposer of a height and round is chosen.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProposerSelection {
+    /// Deterministic stake-weighted round-robin, see `ValidatorSet::proposer_for`.
+    RoundRobin,
+    /// Private stake-weighted VRF lottery over the epoch seed, see `ValidatorSet::verify_leader_proof`.
+    VrfLottery,
 }
 
 // ValidatorSet manages the current set of validators.
+#[derive(Serialize, Deserialize)]
 pub struct ValidatorSet {
     validators: HashMap<String, Validator>,
     stake_manager: StakeManager,
+    /// Proposer priorities as of `proposer_anchor_height`, keyed by validator ID.
+    /// Active validators missing from the map start at zero.
+    proposer_priorities: BTreeMap<String, i64>,
+    proposer_anchor_height: u64,
+    proposer_selection: ProposerSelection,
+    /// Seed of the current epoch that VRF lottery tickets are drawn against.
+    epoch_seed: Hash,
+    /// Validators that leave the set at the end of the current epoch.
+    pending_exits: HashSet<String>,
+    liveness_params: LivenessParams,
+    signing_info: HashMap<String, SigningInfo>,
+    /// Commission earned by each validator and not yet withdrawn.
+    accumulated_commission: HashMap<String, BigDecimal>,
+    staking_params: StakingParams,
 }
 
 impl ValidatorSet {
@@ -26,23 +287,106 @@ impl ValidatorSet {
         ValidatorSet {
             validators: HashMap::new(),
             stake_manager,
+            proposer_priorities: BTreeMap::new(),
+            proposer_anchor_height: 0,
+            proposer_selection: ProposerSelection::RoundRobin,
+            epoch_seed: [0; 32],
+            pending_exits: HashSet::new(),
+            liveness_params: LivenessParams::default(),
+            signing_info: HashMap::new(),
+            accumulated_commission: HashMap::new(),
+            staking_params: StakingParams::default(),
         }
     }
 
     /// Adds a new validator to the set.
+    /// The validator joins the active set at the next epoch boundary if its stake ranks among
+    /// the top `max_validators`. `stake` is its self-delegation and must be at least the minimum.
     /// 
     /// # Arguments
     /// * `validator_id` - A unique identifier for the validator.
     /// * `stake` - The amount of stake the validator is putting up.
-    pub fn add_validator(&mut self, validator_id: String, stake: u64) {
+    /// * `public_key` - The validator's sr25519 public key.
+    pub fn add_validator(
+        &mut self,
+        validator_id: String,
+        stake: u64,
+        public_key: Vec<u8>,
+        commission: Commission,
+    ) -> Result<(), ValidatorSetError> {
+        if stake < self.staking_params.min_self_delegation {
+            return Err(ValidatorSetError::InsufficientSelfBond);
+        }
         let validator = Validator {
             id: validator_id.clone(),
             stake,
             is_active: false,
             last_active_epoch: 0,
+            public_key,
+            bls_public_key: Vec::new(),
+            jailed: false,
+            commission,
+            status: ValidatorStatus::Unbonded,
         };
-        self.stake_manager.add_stake(&validator_id, stake);
+        self.stake_manager
+            .add_stake(&validator_id, BigDecimal::from(stake))
+            .map_err(ValidatorSetError::Stake)?;
         self.validators.insert(validator_id, validator);
+        Ok(())
+    }
+
+    /// Registers the BLS key a validator signs votes with, replacing any earlier one.
+    /// Votes of a validator without a registered key are not counted.
+    pub fn register_bls_key(&mut self, registration: &BlsKeyRegistration) -> Result<(), ValidatorSetError> {
+        let validator = self
+            .validators
+            .get_mut(&registration.validator_id)
+            .ok_or(ValidatorSetError::UnknownValidator)?;
+        if !crypto_utils::verify_signature(&validator.public_key, &registration.sign_bytes(), &registration.signature) {
+            return Err(ValidatorSetError::InvalidSignature);
+        }
+        if registration.previous_key != validator.bls_public_key {
+            return Err(ValidatorSetError::StaleBlsKeyRegistration);
+        }
+        if !crypto_utils::verify_bls_proof_of_possession(&registration.public_key, &registration.proof_of_possession) {
+            return Err(ValidatorSetError::InvalidBlsKey);
+        }
+        validator.bls_public_key = registration.public_key.clone();
+        Ok(())
+    }
+
+    /// Changes a validator's commission rate at block time `now`.
+    /// The rate must stay within `max_rate`, move by at most `max_change_rate`,
+    /// and change at most once per day.
+    pub fn update_commission(&mut self, validator_id: &str, rate: BigDecimal, now: u64) -> Result<(), ValidatorSetError> {
+        let validator = self
+            .validators
+            .get_mut(validator_id)
+            .ok_or(ValidatorSetError::UnknownValidator)?;
+        let commission = &mut validator.commission;
+        if rate < BigDecimal::from(0) || rate > commission.max_rate {
+            return Err(ValidatorSetError::InvalidCommission);
+        }
+        if (&rate - &commission.rate).abs() > commission.max_change_rate {
+            return Err(ValidatorSetError::CommissionChangeTooLarge);
+        }
+        if commission.update_time != 0 && now < commission.update_time + COMMISSION_UPDATE_INTERVAL {
+            return Err(ValidatorSetError::CommissionUpdateTooSoon);
+        }
+        commission.rate = rate;
+        commission.update_time = now;
+        Ok(())
+    }
+
+    /// Withdraws the commission a validator has earned. Returns the amount to credit to the operator.
+    pub fn withdraw_commission(&mut self, validator_id: &str) -> Result<BigDecimal, ValidatorSetError> {
+        if !self.validators.contains_key(validator_id) {
+            return Err(ValidatorSetError::UnknownValidator);
+        }
+        Ok(self
+            .accumulated_commission
+            .remove(validator_id)
+            .unwrap_or_else(|| BigDecimal::from(0)))
     }
 
     /// Updates the status of a validator.
@@ -53,40 +397,645 @@ impl ValidatorSet {
     pub fn update_validator_status(&mut self, validator_id: &str, is_active: bool) {
         if let Some(validator) = self.validators.get_mut(validator_id) {
             validator.is_active = is_active;
+            validator.status = if is_active {
+                ValidatorStatus::Bonded
+            } else {
+                ValidatorStatus::Unbonded
+            };
+        }
+    }
+
+    /// Updates the minimum self-delegation and maximum active set size.
+    /// Both take effect at the next epoch boundary.
+    pub fn update_staking_params(&mut self, staking_params: StakingParams) {
+        self.staking_params = staking_params;
+    }
+
+    /// End-block hook. Runs the staking end-block hook and marks validators whose unbonding
+    /// period has passed as unbonded. Returns the unbonding entries released by the StakeManager.
+    pub fn end_block(&mut self, height: u64, time: u64) -> Vec<UnbondingEntry> {
+        for validator in self.validators.values_mut() {
+            if let ValidatorStatus::Unbonding { completion_height, completion_time } = validator.status {
+                if height >= completion_height && time >= completion_time {
+                    validator.status = ValidatorStatus::Unbonded;
+                }
+            }
         }
+        self.stake_manager.end_block(height, time)
     }
 
     /// Selects validators for the next epoch based on their stake and other criteria.
+    /// Validators that are not jailed or leaving, have bonded stake in the StakeManager and keep
+    /// the minimum self-delegation are eligible. The `max_validators` eligible validators with the
+    /// most stake are selected, ties broken by ID, with their voting power set to that stake.
+    /// Results are sorted by ID.
     pub fn select_validators_for_next_epoch(&self) -> Vec<Validator> {
-        // Implement selection logic, possibly involving randomness and stake amount.
-        // For example, validators with higher stakes and consistent participation
-        // might have a higher chance of being selected.
+        let min_self_delegation = BigDecimal::from(self.staking_params.min_self_delegation);
+        let mut selected: Vec<Validator> = self
+            .validators
+            .values()
+            .filter(|v| !v.jailed && !self.pending_exits.contains(&v.id))
+            .filter(|v| self.stake_manager.get_delegation_balance(&v.id, &v.id) >= min_self_delegation)
+            .filter_map(|v| {
+                let stake = self.bonded_stake(&v.id);
+                if stake == 0 {
+                    return None;
+                }
+                let mut next = v.clone();
+                next.stake = stake;
+                next.is_active = true;
+                next.status = ValidatorStatus::Bonded;
+                Some(next)
+            })
+            .collect();
+        selected.sort_by(|a, b| b.stake.cmp(&a.stake).then_with(|| a.id.cmp(&b.id)));
+        selected.truncate(self.staking_params.max_validators);
+        selected.sort_by(|a, b| a.id.cmp(&b.id));
+        selected
     }
 
     /// Rotates validators based on the selection for the new epoch.
-    pub fn rotate_validators(&mut self) {
+    /// Applies the joins, exits and stake changes queued during the epoch, resets proposer
+    /// priorities and returns the resulting changes to the active set.
+    ///
+    /// # Arguments
+    /// * `epoch` - The epoch that is starting.
+    /// * `start_height` - The first height of the new epoch.
+    pub fn rotate_validators(&mut self, epoch: u64, start_height: u64) -> ValidatorSetUpdate {
         let selected_validators = self.select_validators_for_next_epoch();
-        // Update the validators set based on the selected validators for the new epoch.
+
+        let mut update = ValidatorSetUpdate {
+            epoch,
+            start_height,
+            added: Vec::new(),
+            removed: Vec::new(),
+            power_changes: Vec::new(),
+        };
+        let selected_ids: HashSet<&str> = selected_validators.iter().map(|v| v.id.as_str()).collect();
+        for next in &selected_validators {
+            match self.validators.get(&next.id) {
+                Some(current) if current.is_active => {
+                    if current.stake != next.stake {
+                        update.power_changes.push((next.id.clone(), next.stake));
+                    }
+                }
+                _ => update.added.push((next.id.clone(), next.stake)),
+            }
+        }
+        update.removed = self
+            .validators
+            .values()
+            .filter(|v| v.is_active && !selected_ids.contains(v.id.as_str()))
+            .map(|v| v.id.clone())
+            .collect();
+        update.removed.sort();
+
+        let (completion_height, completion_time) = self.stake_manager.unbonding_completion();
+        for validator in self.validators.values_mut() {
+            if validator.is_active {
+                validator.status = ValidatorStatus::Unbonding { completion_height, completion_time };
+            }
+            validator.is_active = false;
+        }
+        for validator_id in self.pending_exits.drain() {
+            self.validators.remove(&validator_id);
+            self.stake_manager.remove_stake(&validator_id);
+        }
+        for mut validator in selected_validators {
+            validator.last_active_epoch = epoch;
+            self.validators.insert(validator.id.clone(), validator);
+        }
+        self.reset_proposer_priorities(start_height);
+
+        update
+    }
+
+    /// Updates the downtime parameters.
+    pub fn update_liveness_params(&mut self, liveness_params: LivenessParams) {
+        self.liveness_params = liveness_params;
+    }
+
+    /// Records which of the validators that finalized the block at `height` signed its commit,
+    /// and jails those that signed less than the minimum over the sliding window.
+    /// Jailed validators are slashed for downtime and lose their voting power at once; they stay
+    /// bonded until the next epoch boundary. Returns the IDs of the validators jailed at this height.
+    ///
+    /// # Arguments
+    /// * `height` - The height of the committed block.
+    /// * `validators` - The active set that finalized the block, as it was at `height`.
+    /// * `signers` - IDs of the validators that signed the block's commit.
+    pub fn record_block_signatures(&mut self, height: u64, validators: &[Validator], signers: &HashSet<String>) -> Vec<String> {
+        let window = self.liveness_params.signed_blocks_window as usize;
+        let min_signed = (BigDecimal::from(self.liveness_params.signed_blocks_window)
+            * &self.liveness_params.min_signed_per_window)
+            .to_u64()
+            .unwrap_or(0);
+
+        let mut jailed = Vec::new();
+        for validator in validators {
+            // Validators that left since, or were jailed in between, are not tracked.
+            if !self.validators.get(&validator.id).map_or(false, |current| !current.jailed) {
+                continue;
+            }
+            let info = self.signing_info.entry(validator.id.clone()).or_default();
+            let missed = !signers.contains(&validator.id);
+            info.missed_blocks.push_back(missed);
+            if missed {
+                info.missed_count += 1;
+            }
+            if info.missed_blocks.len() > window && info.missed_blocks.pop_front() == Some(true) {
+                info.missed_count -= 1;
+            }
+
+            let signed = info.missed_blocks.len() as u64 - info.missed_count;
+            if info.missed_blocks.len() == window && signed < min_signed {
+                *info = SigningInfo {
+                    jailed_until: height + self.liveness_params.downtime_jail_duration,
+                    ..SigningInfo::default()
+                };
+                jailed.push(validator.id.clone());
+            }
+        }
+
+        jailed.sort();
+        for validator_id in &jailed {
+            if let Some(validator) = self.validators.get_mut(validator_id) {
+                validator.jailed = true;
+            }
+            let fraction = self.liveness_params.slash_fraction_downtime.clone();
+            self.slash_validator(validator_id, &fraction, height);
+        }
+        jailed
+    }
+
+    /// Handles an unjail transaction. Once the jail period is over, the validator regains its
+    /// voting power at once if it was jailed during the current epoch, and otherwise rejoins the
+    /// active set at the next epoch boundary.
+    ///
+    /// # Arguments
+    /// * `transaction` - The unjail transaction, signed by the validator.
+    /// * `height` - The height at which the transaction is executed.
+    pub fn unjail(&mut self, transaction: &UnjailTransaction, height: u64) -> Result<(), ValidatorSetError> {
+        let validator = self
+            .validators
+            .get(&transaction.validator_id)
+            .ok_or(ValidatorSetError::UnknownValidator)?;
+        if !crypto_utils::verify_signature(&validator.public_key, &transaction.sign_bytes(), &transaction.signature) {
+            return Err(ValidatorSetError::InvalidSignature);
+        }
+        if !validator.jailed {
+            return Err(ValidatorSetError::NotJailed);
+        }
+        if self.is_tombstoned(&transaction.validator_id) {
+            return Err(ValidatorSetError::Tombstoned);
+        }
+        let jailed_until = self.jailed_until(&transaction.validator_id).unwrap_or(0);
+        if transaction.jailed_until != jailed_until {
+            return Err(ValidatorSetError::StaleUnjail);
+        }
+        if height < jailed_until {
+            return Err(ValidatorSetError::StillJailed);
+        }
+
+        if let Some(validator) = self.validators.get_mut(&transaction.validator_id) {
+            validator.jailed = false;
+        }
+        Ok(())
     }
 
-    /// Returns the current set of active validators.
+    /// Jails a validator for good once evidence of its double-signing is committed. Unlike a
+    /// downtime jail, it can never be ended by an unjail transaction.
+    pub fn tombstone(&mut self, validator_id: &str) {
+        let validator = match self.validators.get_mut(validator_id) {
+            Some(validator) => validator,
+            None => return,
+        };
+        validator.jailed = true;
+        self.signing_info.entry(validator_id.to_string()).or_default().tombstoned = true;
+    }
+
+    /// Returns true if the validator was tombstoned for double-signing.
+    pub fn is_tombstoned(&self, validator_id: &str) -> bool {
+        self.signing_info.get(validator_id).map_or(false, |info| info.tombstoned)
+    }
+
+    /// Returns the commission each validator has earned and not yet withdrawn.
+    pub fn accumulated_commissions(&self) -> impl Iterator<Item = (&String, &BigDecimal)> {
+        self.accumulated_commission.iter()
+    }
+
+    /// Returns each validator's signing window and jail record as JSON, for the state tree.
+    pub fn signing_info_records(&self) -> impl Iterator<Item = (&String, Vec<u8>)> + '_ {
+        self.signing_info
+            .iter()
+            .map(|(validator_id, info)| (validator_id, serde_json::to_vec(info).expect("signing info is serializable")))
+    }
+
+    /// Returns the release height of the validator's latest downtime jail, which its unjail
+    /// transaction names, or `None` if it was never jailed for downtime.
+    pub fn jailed_until(&self, validator_id: &str) -> Option<u64> {
+        self.signing_info
+            .get(validator_id)
+            .map(|info| info.jailed_until)
+            .filter(|jailed_until| *jailed_until > 0)
+    }
+
+    /// Returns the validator's bonded stake in the StakeManager as voting power.
+    fn bonded_stake(&self, validator_id: &str) -> u64 {
+        self.stake_manager
+            .get_stake(&validator_id.to_string())
+            .and_then(|stake| stake.to_u64())
+            .unwrap_or(0)
+    }
+
+    /// Returns the validator with the given ID, active or not.
+    pub fn get_validator(&self, validator_id: &str) -> Option<&Validator> {
+        self.validators.get(validator_id)
+    }
+
+    /// Slashes a fraction of the validator's stake and of its delegators' stake, including stake
+    /// unbonding since `infraction_height`.
+    /// The validator's voting power follows at the next epoch boundary. Returns the amount slashed.
+    pub fn slash_validator(&mut self, validator_id: &str, fraction: &BigDecimal, infraction_height: u64) -> BigDecimal {
+        self.stake_manager.slash(validator_id, fraction, infraction_height)
+    }
+
+    /// Returns the StakeManager backing the validators' voting power.
+    pub fn stake_manager(&self) -> &StakeManager {
+        &self.stake_manager
+    }
+
+    /// Returns the StakeManager backing the validators' voting power, for staking operations.
+    pub fn stake_manager_mut(&mut self) -> &mut StakeManager {
+        &mut self.stake_manager
+    }
+
+    /// Returns every validator, active or not, sorted by ID.
+    pub fn get_validators(&self) -> Vec<&Validator> {
+        let mut validators: Vec<&Validator> = self.validators.values().collect();
+        validators.sort_by(|a, b| a.id.cmp(&b.id));
+        validators
+    }
+
+    /// Returns the current set of active validators. A jailed validator leaves it as soon as it is
+    /// jailed, though it stays bonded until the next epoch boundary.
     pub fn get_active_validators(&self) -> Vec<&Validator> {
-        self.validators.values().filter(|v| v.is_active).collect()
+        self.validators.values().filter(|v| v.is_active && !v.jailed).collect()
+    }
+
+    /// Returns the voting power of a validator, which is its stake while active and not jailed,
+    /// and zero otherwise.
+    pub fn voting_power(&self, validator_id: &str) -> u64 {
+        self.validators
+            .get(validator_id)
+            .filter(|v| v.is_active && !v.jailed)
+            .map_or(0, |v| v.stake)
     }
 
-    /// Calculates and distributes rewards to validators based on their participation.
-    pub fn distribute_rewards(&self) {
-        // Implement reward calculation and distribution logic.
-        // Rewards might depend on factors like the amount of stake,
-        // the number of blocks proposed/validated, etc.
+    /// Returns the combined voting power of all active validators.
+    pub fn total_voting_power(&self) -> u64 {
+        self.get_active_validators().iter().map(|v| v.stake).sum()
+    }
+
+    /// Returns how proposers are chosen.
+    pub fn proposer_selection(&self) -> ProposerSelection {
+        self.proposer_selection
+    }
+
+    /// Switches between round-robin and VRF lottery proposer selection.
+    /// All nodes must switch at the same height.
+    pub fn set_proposer_selection(&mut self, proposer_selection: ProposerSelection) {
+        self.proposer_selection = proposer_selection;
+    }
+
+    /// Returns the seed VRF lottery tickets are currently drawn against.
+    pub fn epoch_seed(&self) -> Hash {
+        self.epoch_seed
+    }
+
+    /// Sets the seed VRF lottery tickets are drawn against for the new epoch.
+    pub fn set_epoch_seed(&mut self, epoch_seed: Hash) {
+        self.epoch_seed = epoch_seed;
+    }
+
+    /// Draws the local validator's VRF lottery ticket for a height and round.
+    /// Returns the proof to place in the block header, or in the proposal of a re-proposed block, if
+    /// the ticket wins, and `None` otherwise.
+    ///
+    /// # Arguments
+    /// * `validator_id` - The ID of the local validator.
+    /// * `keypair` - The local validator's key, matching its registered public key.
+    /// * `height` - The block height.
+    /// * `round` - The round within the height.
+    pub fn evaluate_leader_lottery(&self, validator_id: &str, keypair: &Keypair, height: u64, round: u32) -> Option<VrfProof> {
+        let (proof, randomness) = crypto_utils::vrf_evaluate(keypair, &self.epoch_seed, height, round);
+        if self.wins_leader_lottery(validator_id, &randomness) {
+            Some(proof)
+        } else {
+            None
+        }
+    }
+
+    /// Verifies that `proof` is a winning VRF lottery ticket of `validator_id` for a height and round.
+    /// Returns the ticket's randomness, which ranks competing winners (lowest first).
+    pub fn verify_leader_proof(&self, validator_id: &str, height: u64, round: u32, proof: &VrfProof) -> Option<Hash> {
+        let validator = self.validators.get(validator_id)?;
+        let randomness = crypto_utils::verify_vrf(&validator.public_key, &self.epoch_seed, height, round, proof)?;
+        if self.wins_leader_lottery(validator_id, &randomness) {
+            Some(randomness)
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the ticket randomness falls below `LEADER_LOTTERY_EXPECTED_WINNERS` times
+    /// the validator's share of the voting power, so a validator wins in proportion to its stake.
+    /// A slot has no winner with probability about e^-LEADER_LOTTERY_EXPECTED_WINNERS; such
+    /// rounds end at the propose timeout.
+    fn wins_leader_lottery(&self, validator_id: &str, randomness: &Hash) -> bool {
+        let total = self.total_voting_power() as u128;
+        if total == 0 {
+            return false;
+        }
+        let mut prefix = [0u8; 8];
+        prefix.copy_from_slice(&randomness[..8]);
+        let ticket = u64::from_be_bytes(prefix) as u128;
+        // ticket / 2^64 < expected * power / total, without overflowing.
+        (ticket * total) >> 64 < self.voting_power(validator_id) as u128 * LEADER_LOTTERY_EXPECTED_WINNERS
+    }
+
+    /// Returns the validator that proposes at the given height and round
+    /// when proposers are selected by round-robin.
+    ///
+    /// Proposers rotate by weighted round-robin: at every step each active validator's priority
+    /// grows by its voting power, the validator with the highest priority proposes (ties go to the
+    /// lowest ID) and its priority is then decreased by the total voting power. Round `round` at
+    /// `height` takes the step `round` places after that of round 0, and the next height starts one
+    /// step after the current one whatever the number of rounds it took, so round `r` at `h` has
+    /// the proposer of round 0 at `h + r`. Every node derives the same proposer from the same set.
+    ///
+    /// # Arguments
+    /// * `height` - The block height, at or above the last reset or advance of the priorities.
+    /// * `round` - The round within the height.
+    pub fn proposer_for(&self, height: u64, round: u32) -> Option<String> {
+        let mut candidates = self.proposer_candidates();
+        if candidates.is_empty() {
+            return None;
+        }
+        let steps = height.saturating_sub(self.proposer_anchor_height) + round as u64;
+        let mut proposer = 0;
+        for _ in 0..=steps {
+            proposer = rotate_proposer(&mut candidates);
+        }
+        Some(candidates.swap_remove(proposer).0)
+    }
+
+    /// Applies the priority steps of every height below `height`, so later calls to
+    /// `proposer_for` only replay the steps of the current height.
+    pub fn advance_proposer_priorities(&mut self, height: u64) {
+        if height <= self.proposer_anchor_height {
+            return;
+        }
+        let mut candidates = self.proposer_candidates();
+        if !candidates.is_empty() {
+            for _ in self.proposer_anchor_height..height {
+                rotate_proposer(&mut candidates);
+            }
+        }
+        self.proposer_priorities = candidates.into_iter().map(|(id, _, priority)| (id, priority)).collect();
+        self.proposer_anchor_height = height;
+    }
+
+    /// Resets all proposer priorities to zero starting at `height`.
+    /// Must be called at the same height on every node whenever the active set changes.
+    pub fn reset_proposer_priorities(&mut self, height: u64) {
+        self.proposer_priorities.clear();
+        self.proposer_anchor_height = height;
+    }
+
+    /// Returns `(id, voting power, priority)` for every active validator, sorted by ID.
+    fn proposer_candidates(&self) -> Vec<(String, u64, i64)> {
+        let mut candidates: Vec<(String, u64, i64)> = self
+            .get_active_validators()
+            .into_iter()
+            .map(|v| {
+                let priority = self.proposer_priorities.get(&v.id).copied().unwrap_or(0);
+                (v.id.clone(), v.stake, priority)
+            })
+            .collect();
+        candidates.sort_by(|a, b| a.0.cmp(&b.0));
+        candidates
+    }
+
+    /// Splits `reward` among active validators by voting power and returns the part left
+    /// undistributed by rounding, which the caller must account for.
+    ///
+    /// A validator's share is `reward * power / total power`, rounded down to `REWARD_SCALE`
+    /// decimal places, so the shares never exceed `reward` and the remainder is below
+    /// 10^-`REWARD_SCALE` per validator. Its commission is the share times its rate, rounded down
+    /// the same way, and is set aside for its operator; the rest of the share is credited to its
+    /// delegators, who collect it with `StakeManager::withdraw_rewards`. A validator unknown to
+    /// the stake manager gets nothing and its share is left in the remainder.
+    /// Nothing is distributed if an error is returned.
+    pub fn distribute_rewards(&mut self, reward: &BigDecimal) -> Result<BigDecimal, RewardError> {
+        if *reward < BigDecimal::from(0) {
+            return Err(RewardError::NegativeReward);
+        }
+        let active = self.get_active_validators();
+        let total_power = active
+            .iter()
+            .try_fold(0u64, |total, v| total.checked_add(v.stake))
+            .ok_or(RewardError::Overflow)?;
+        if total_power == 0 {
+            return Err(RewardError::NoVotingPower);
+        }
+        let shares: Vec<(String, BigDecimal, BigDecimal)> = active
+            .iter()
+            .map(|v| {
+                let share = (reward * BigDecimal::from(v.stake) / BigDecimal::from(total_power))
+                    .with_scale_round(REWARD_SCALE, RoundingMode::Down);
+                let commission = (&share * &v.commission.rate).with_scale_round(REWARD_SCALE, RoundingMode::Down);
+                (v.id.clone(), share, commission)
+            })
+            .collect();
+        let mut remainder = reward.clone();
+        for (validator_id, share, commission) in shares {
+            if self.stake_manager.distribute_rewards(&validator_id, &share - &commission).is_err() {
+                continue;
+            }
+            *self
+                .accumulated_commission
+                .entry(validator_id)
+                .or_insert_with(|| BigDecimal::from(0)) += commission;
+            remainder -= share;
+        }
+        Ok(remainder)
     }
 
     /// Removes a validator from the set.
+    /// The validator keeps validating until the end of the current epoch.
     /// 
     /// # Arguments
     /// * `validator_id` - The ID of the validator to be removed.
     pub fn remove_validator(&mut self, validator_id: &str) {
-        self.validators.remove(validator_id);
-        self.stake_manager.remove_stake(validator_id);
+        if self.validators.contains_key(validator_id) {
+            self.pending_exits.insert(validator_id.to_string());
+        }
+    }
+}
+
+/// Performs one weighted round-robin step over `(id, voting power, priority)` candidates
+/// and returns the index of the selected proposer.
+fn rotate_proposer(candidates: &mut [(String, u64, i64)]) -> usize {
+    let total: i64 = candidates.iter().map(|(_, power, _)| *power as i64).sum();
+    for (_, power, priority) in candidates.iter_mut() {
+        *priority += *power as i64;
+    }
+    let proposer = (0..candidates.len())
+        .max_by(|&a, &b| {
+            candidates[a]
+                .2
+                .cmp(&candidates[b].2)
+                .then_with(|| candidates[b].0.cmp(&candidates[a].0))
+        })
+        .expect("candidates are not empty");
+    candidates[proposer].2 -= total;
+    proposer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validator_set(stakes: &[(&str, u64)]) -> ValidatorSet {
+        let mut set = ValidatorSet::new(StakeManager::new());
+        for (id, stake) in stakes {
+            set.add_validator(id.to_string(), *stake, Vec::new(), Commission::default()).unwrap();
+            set.update_validator_status(id, true);
+        }
+        set
+    }
+
+    #[test]
+    fn test_proposer_rotation_is_weighted_by_stake() {
+        let set = validator_set(&[("a", 30), ("b", 10)]);
+        let proposers: Vec<String> = (0..4).map(|h| set.proposer_for(h, 0).unwrap()).collect();
+
+        assert_eq!(proposers.iter().filter(|p| *p == "a").count(), 3);
+        assert_eq!(proposers.iter().filter(|p| *p == "b").count(), 1);
+    }
+
+    #[test]
+    fn test_commission_changes_are_bounded() {
+        let mut set = validator_set(&[]);
+        let rate = |r: &str| BigDecimal::from_str(r).unwrap();
+        let commission = Commission::new(rate("0.05"), rate("0.2"), rate("0.01")).unwrap();
+        set.add_validator("a".to_string(), 10, Vec::new(), commission).unwrap();
+
+        assert_eq!(set.update_commission("a", rate("0.07"), 100), Err(ValidatorSetError::CommissionChangeTooLarge));
+        assert_eq!(set.update_commission("a", rate("0.06"), 100), Ok(()));
+        assert_eq!(set.update_commission("a", rate("0.07"), 200), Err(ValidatorSetError::CommissionUpdateTooSoon));
+        assert_eq!(set.update_commission("a", rate("0.07"), 100 + COMMISSION_UPDATE_INTERVAL), Ok(()));
+        assert!(Commission::new(rate("0.3"), rate("0.2"), rate("0.01")).is_err());
+    }
+
+    #[test]
+    fn test_commission_is_set_aside_and_delegators_receive_the_rest() {
+        let mut set = validator_set(&[("b", 100)]);
+        let rate = |r: &str| BigDecimal::from_str(r).unwrap();
+        let commission = Commission::new(rate("0.1"), rate("0.2"), rate("0.01")).unwrap();
+        set.add_validator("a".to_string(), 100, Vec::new(), commission).unwrap();
+        set.update_validator_status("a", true);
+
+        assert_eq!(set.distribute_rewards(&rate("20")), Ok(rate("0")));
+        assert_eq!(set.withdraw_commission("a").unwrap(), rate("1"));
+        assert_eq!(set.stake_manager_mut().withdraw_rewards("a", "a").unwrap(), rate("9"));
+        assert_eq!(set.withdraw_commission("b").unwrap(), rate("0"));
+        assert_eq!(set.stake_manager_mut().withdraw_rewards("b", "b").unwrap(), rate("10"));
+    }
+
+    #[test]
+    fn test_reward_shares_round_down_and_the_remainder_is_returned() {
+        let mut set = validator_set(&[("a", 1), ("b", 2)]);
+        let amount = |r: &str| BigDecimal::from_str(r).unwrap();
+
+        // Thirds of one token are cut at the 18th decimal place.
+        assert_eq!(set.distribute_rewards(&amount("1")), Ok(amount("0.000000000000000001")));
+        assert_eq!(set.stake_manager_mut().withdraw_rewards("a", "a").unwrap(), amount("0.333333333333333333"));
+        assert_eq!(set.stake_manager_mut().withdraw_rewards("b", "b").unwrap(), amount("0.666666666666666666"));
+
+        assert_eq!(set.distribute_rewards(&amount("-1")), Err(RewardError::NegativeReward));
+        let mut empty = validator_set(&[]);
+        assert_eq!(empty.distribute_rewards(&amount("1")), Err(RewardError::NoVotingPower));
+    }
+
+    #[test]
+    fn test_liveness_is_checked_against_the_signing_set_and_jails_take_effect_at_once() {
+        let mut set = validator_set(&[("a", 10), ("b", 10), ("c", 10)]);
+        set.update_liveness_params(LivenessParams {
+            signed_blocks_window: 2,
+            min_signed_per_window: BigDecimal::from(1),
+            ..LivenessParams::default()
+        });
+        // `c` joined after the blocks were signed, so it is not held to them.
+        let signing_set: Vec<Validator> = ["a", "b"].iter().map(|id| set.get_validator(id).unwrap().clone()).collect();
+        let signers: HashSet<String> = ["a".to_string()].into_iter().collect();
+
+        assert!(set.record_block_signatures(1, &signing_set, &signers).is_empty());
+        assert_eq!(set.record_block_signatures(2, &signing_set, &signers), vec!["b".to_string()]);
+        assert_eq!(set.voting_power("b"), 0);
+        assert_eq!(set.total_voting_power(), 20);
+        assert!(set.get_active_validators().iter().all(|v| v.id != "b"));
+        assert!(!set.get_validator("c").unwrap().jailed);
+    }
+
+    #[test]
+    fn test_only_top_validators_by_stake_stay_active() {
+        let mut set = validator_set(&[("a", 30), ("b", 10), ("c", 20)]);
+        set.update_staking_params(StakingParams {
+            min_self_delegation: 5,
+            max_validators: 2,
+        });
+        assert_eq!(
+            set.add_validator("d".to_string(), 4, Vec::new(), Commission::default()),
+            Err(ValidatorSetError::InsufficientSelfBond)
+        );
+
+        let update = set.rotate_validators(1, 10);
+        assert_eq!(update.removed, vec!["b".to_string()]);
+        assert_eq!(set.get_validator("a").unwrap().status, ValidatorStatus::Bonded);
+        assert!(matches!(set.get_validator("b").unwrap().status, ValidatorStatus::Unbonding { .. }));
+    }
+
+    #[test]
+    fn test_advancing_priorities_preserves_schedule() {
+        let mut set = validator_set(&[("a", 5), ("b", 3), ("c", 2)]);
+        let expected: Vec<Option<String>> = (0..20).map(|h| set.proposer_for(h, 1)).collect();
+
+        set.advance_proposer_priorities(7);
+        let actual: Vec<Option<String>> = (7..20).map(|h| set.proposer_for(h, 1)).collect();
+
+        assert_eq!(actual, expected[7..].to_vec());
+    }
+
+    #[test]
+    fn test_later_rounds_take_the_proposers_of_later_heights() {
+        let mut set = validator_set(&[("a", 5), ("b", 3), ("c", 2)]);
+        for height in 1..10 {
+            for round in 1..4 {
+                assert_eq!(set.proposer_for(height, round), set.proposer_for(height + round as u64, 0));
+            }
+        }
+
+        // Advancing past a height that took several rounds does not skip ahead.
+        let expected = set.proposer_for(4, 0);
+        set.advance_proposer_priorities(4);
+        assert_eq!(set.proposer_for(4, 0), expected);
+    }
+
+    #[test]
+    fn test_no_proposer_without_active_validators() {
+        let set = validator_set(&[]);
+        assert_eq!(set.proposer_for(1, 0), None);
     }
 }
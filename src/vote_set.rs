@@ -0,0 +1,235 @@
+// vote_set.rs
+// Collects the votes of one type cast in a round and tallies the voting power behind each value.
+
+use std::collections::HashMap;
+use crate::evidence::DuplicateVoteEvidence;
+use crate::pos_algorithm::{Height, Round, Vote, VoteType};
+use crate::utilities::crypto_utils::{self, Hash};
+use crate::validator_set::ValidatorSet;
+
+/// Reasons a vote is not added to a vote set.
+#[derive(Debug, PartialEq, Eq)]
+pub enum VoteSetError {
+    /// The vote is for another height, round or vote type than the set collects.
+    UnexpectedVote,
+    /// The voter has no voting power.
+    UnknownValidator,
+    InvalidSignature,
+    /// The voter already voted for another value; the first vote is kept.
+    Conflicting(Box<DuplicateVoteEvidence>),
+}
+
+/// Votes of one type for a height and round, at most one per validator.
+/// The total voting power is taken from the validator set when the set is created, as the
+/// validator set only changes between heights.
+#[derive(Debug, Clone)]
+pub struct VoteSet {
+    height: Height,
+    round: Round,
+    vote_type: VoteType,
+    total_power: u64,
+    /// Votes by validator ID, with the voting power each was counted with.
+    votes: HashMap<String, (Vote, u64)>,
+    /// Voting power behind each value; `None` is nil.
+    power_by_value: HashMap<Option<Hash>, u64>,
+    /// Combined voting power of every vote, whatever its value.
+    voted_power: u64,
+}
+
+impl VoteSet {
+    /// Creates an empty set for votes of `vote_type` at `height` and `round`.
+    pub fn new(height: Height, round: Round, vote_type: VoteType, validator_set: &ValidatorSet) -> Self {
+        VoteSet {
+            height,
+            round,
+            vote_type,
+            total_power: validator_set.total_voting_power(),
+            votes: HashMap::new(),
+            power_by_value: HashMap::new(),
+            voted_power: 0,
+        }
+    }
+
+    /// Verifies a vote's signatures and its voter's membership in `validator_set`, and counts it.
+    /// The BLS signature must verify against the voter's registered BLS key, so that the counted
+    /// votes for a value always aggregate into a quorum certificate.
+    /// Returns false if the same vote was already counted.
+    pub fn add_vote(&mut self, vote: Vote, validator_set: &ValidatorSet) -> Result<bool, VoteSetError> {
+        if vote.height != self.height || vote.round != self.round || vote.vote_type != self.vote_type {
+            return Err(VoteSetError::UnexpectedVote);
+        }
+        if let Some((existing, _)) = self.votes.get(&vote.validator_id) {
+            if existing.block_hash == vote.block_hash {
+                return Ok(false);
+            }
+        }
+
+        let power = validator_set.voting_power(&vote.validator_id);
+        let validator = validator_set
+            .get_validator(&vote.validator_id)
+            .filter(|_| power > 0)
+            .ok_or(VoteSetError::UnknownValidator)?;
+        if !crypto_utils::verify_signature(&validator.public_key, &vote.sign_bytes(), &vote.signature)
+            || !crypto_utils::bls_verify(&validator.bls_public_key, &vote.sign_bytes(), &vote.bls_signature)
+        {
+            return Err(VoteSetError::InvalidSignature);
+        }
+        // Checked after the signature so that only signed votes become evidence.
+        if let Some((existing, _)) = self.votes.get(&vote.validator_id) {
+            return Err(VoteSetError::Conflicting(Box::new(DuplicateVoteEvidence {
+                vote_a: existing.clone(),
+                vote_b: vote,
+            })));
+        }
+
+        *self.power_by_value.entry(vote.block_hash).or_insert(0) += power;
+        self.voted_power += power;
+        self.votes.insert(vote.validator_id.clone(), (vote, power));
+        Ok(true)
+    }
+
+    /// Returns the voting power of the validators that voted for `value`.
+    pub fn power_for(&self, value: Option<Hash>) -> u64 {
+        self.power_by_value.get(&value).copied().unwrap_or(0)
+    }
+
+    /// Returns the combined voting power of the validators that voted, whatever for.
+    pub fn voted_power(&self) -> u64 {
+        self.voted_power
+    }
+
+    /// Returns true if more than two thirds of the voting power voted for `value`.
+    pub fn has_two_thirds_for(&self, value: Option<Hash>) -> bool {
+        self.is_quorum(self.power_for(value))
+    }
+
+    /// Returns the value more than two thirds of the voting power voted for, if any.
+    /// At most one value can have such a majority.
+    pub fn two_thirds_majority(&self) -> Option<Option<Hash>> {
+        self.power_by_value
+            .iter()
+            .find(|(_, power)| self.is_quorum(**power))
+            .map(|(value, _)| *value)
+    }
+
+    /// Returns true if more than two thirds of the voting power voted, for any values.
+    pub fn has_two_thirds_any(&self) -> bool {
+        self.is_quorum(self.voted_power)
+    }
+
+    /// Returns the vote counted for a validator, if any.
+    pub fn get(&self, validator_id: &str) -> Option<&Vote> {
+        self.votes.get(validator_id).map(|(vote, _)| vote)
+    }
+
+    /// Returns the IDs of the validators that voted.
+    pub fn voters(&self) -> impl Iterator<Item = &str> {
+        self.votes.keys().map(String::as_str)
+    }
+
+    /// Returns the votes for `value`, for example the precommits of a commit.
+    pub fn votes_for(&self, value: Option<Hash>) -> Vec<Vote> {
+        self.votes
+            .values()
+            .filter(|(vote, _)| vote.block_hash == value)
+            .map(|(vote, _)| vote.clone())
+            .collect()
+    }
+
+    /// Returns true if `power` is strictly more than two thirds of the total voting power.
+    fn is_quorum(&self, power: u64) -> bool {
+        power as u128 * 3 > self.total_power as u128 * 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use schnorrkel::{ExpansionMode, Keypair, MiniSecretKey};
+    use crate::stake_manager::StakeManager;
+    use crate::utilities::crypto_utils::BlsSecretKey;
+    use crate::validator_set::{BlsKeyRegistration, Commission};
+
+    fn keypair(seed: u8) -> Keypair {
+        MiniSecretKey::from_bytes(&[seed; 32]).unwrap().expand_to_keypair(ExpansionMode::Ed25519)
+    }
+
+    fn bls_key(seed: u8) -> BlsSecretKey {
+        BlsSecretKey::key_gen(&[seed; 32], &[]).unwrap()
+    }
+
+    fn validator_set(stakes: &[(&str, u64, u8)]) -> ValidatorSet {
+        let mut set = ValidatorSet::new(StakeManager::new());
+        for (id, stake, seed) in stakes {
+            let public_key = keypair(*seed).public.to_bytes().to_vec();
+            set.add_validator(id.to_string(), *stake, public_key, Commission::default()).unwrap();
+            set.update_validator_status(id, true);
+            set.register_bls_key(&BlsKeyRegistration::sign(id, Vec::new(), &bls_key(*seed), &keypair(*seed)))
+                .unwrap();
+        }
+        set
+    }
+
+    fn prevote(validator_id: &str, seed: u8, block_hash: Option<Hash>) -> Vote {
+        let mut vote = Vote {
+            vote_type: VoteType::Prevote,
+            height: 3,
+            round: 0,
+            block_hash,
+            validator_id: validator_id.to_string(),
+            signature: Vec::new(),
+            bls_signature: Vec::new(),
+        };
+        vote.signature = crypto_utils::sign_message(&keypair(seed), &vote.sign_bytes());
+        vote.bls_signature = crypto_utils::bls_sign(&bls_key(seed), &vote.sign_bytes());
+        vote
+    }
+
+    #[test]
+    fn test_tally_reports_majority_and_any() {
+        let validators = validator_set(&[("a", 40, 1), ("b", 30, 2), ("c", 30, 3)]);
+        let mut votes = VoteSet::new(3, 0, VoteType::Prevote, &validators);
+        let block = Some([1; 32]);
+
+        assert_eq!(votes.add_vote(prevote("a", 1, block), &validators), Ok(true));
+        assert_eq!(votes.add_vote(prevote("a", 1, block), &validators), Ok(false));
+        assert_eq!(votes.add_vote(prevote("b", 2, None), &validators), Ok(true));
+        assert_eq!(votes.power_for(block), 40);
+        assert!(!votes.has_two_thirds_any());
+
+        votes.add_vote(prevote("c", 3, block), &validators).unwrap();
+        assert!(votes.has_two_thirds_any());
+        assert!(!votes.has_two_thirds_for(block));
+        assert_eq!(votes.two_thirds_majority(), None);
+
+        let mut votes = VoteSet::new(3, 0, VoteType::Prevote, &validators);
+        votes.add_vote(prevote("a", 1, block), &validators).unwrap();
+        votes.add_vote(prevote("b", 2, block), &validators).unwrap();
+        assert_eq!(votes.two_thirds_majority(), Some(block));
+        assert_eq!(votes.votes_for(block).len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_and_conflicting_votes_are_rejected() {
+        let validators = validator_set(&[("a", 10, 1), ("b", 10, 2)]);
+        let mut votes = VoteSet::new(3, 0, VoteType::Prevote, &validators);
+
+        assert_eq!(votes.add_vote(prevote("a", 2, None), &validators), Err(VoteSetError::InvalidSignature));
+        assert_eq!(votes.add_vote(prevote("d", 4, None), &validators), Err(VoteSetError::UnknownValidator));
+        // A vote whose BLS signature does not verify could not be aggregated into a certificate.
+        let mut unaggregatable = prevote("a", 1, None);
+        unaggregatable.bls_signature = crypto_utils::bls_sign(&bls_key(2), &unaggregatable.sign_bytes());
+        assert_eq!(votes.add_vote(unaggregatable, &validators), Err(VoteSetError::InvalidSignature));
+        let mut precommit = prevote("a", 1, None);
+        precommit.vote_type = VoteType::Precommit;
+        assert_eq!(votes.add_vote(precommit, &validators), Err(VoteSetError::UnexpectedVote));
+
+        votes.add_vote(prevote("a", 1, None), &validators).unwrap();
+        match votes.add_vote(prevote("a", 1, Some([1; 32])), &validators) {
+            Err(VoteSetError::Conflicting(evidence)) => assert_eq!(evidence.verify(&validators), Ok(())),
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+        assert_eq!(votes.get("a").unwrap().block_hash, None);
+        assert_eq!(votes.voted_power(), 10);
+    }
+}
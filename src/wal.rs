@@ -0,0 +1,131 @@
+// wal.rs
+// Write-ahead log of the consensus messages and timeouts acted on at the current height.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use crate::pos_algorithm::{ConsensusMessage, Height, Timeout};
+
+/// An input of the round state machine, or the end of a height.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum WalEntry {
+    /// A proposal or vote, either received or signed by this node.
+    Message(ConsensusMessage),
+    /// A timeout that fired.
+    Timeout(Timeout),
+    /// The height was finalized; entries before this one are no longer needed.
+    EndHeight(Height),
+}
+
+/// Append-only log holding one JSON-encoded entry per line.
+/// Every entry is synced to disk before the node acts on it, so after a crash the round state
+/// can be rebuilt by replaying the log and the node never signs a conflicting message.
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Wal {
+    /// Opens the log at `path`, creating it if it does not exist.
+    /// A partially written last line is cut off so that new entries start on a line of their own.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let contents = fs::read(&path)?;
+        if contents.last().map_or(false, |byte| *byte != b'\n') {
+            let complete = contents.iter().rposition(|byte| *byte == b'\n').map_or(0, |index| index + 1);
+            file.set_len(complete as u64)?;
+        }
+        Ok(Wal { path, file })
+    }
+
+    /// Appends an entry and waits until it is on disk.
+    pub fn write(&mut self, entry: &WalEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()
+    }
+
+    /// Records that `height` was finalized and discards the entries written for it.
+    pub fn end_height(&mut self, height: Height) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.write(&WalEntry::EndHeight(height))
+    }
+
+    /// Reads the entries written since the last finalized height.
+    /// A partially written last line, left by a crash in the middle of a write, is ignored.
+    pub fn entries(&self) -> io::Result<Vec<WalEntry>> {
+        let mut entries = Vec::new();
+        for line in BufReader::new(File::open(&self.path)?).lines() {
+            match serde_json::from_str::<WalEntry>(&line?) {
+                Ok(WalEntry::EndHeight(_)) => entries.clear(),
+                Ok(entry) => entries.push(entry),
+                Err(_) => break,
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pos_algorithm::Step;
+
+    fn wal_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("venia-wal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn timeout(round: u32) -> WalEntry {
+        WalEntry::Timeout(Timeout {
+            height: 1,
+            round,
+            step: Step::Propose,
+        })
+    }
+
+    fn rounds(entries: &[WalEntry]) -> Vec<u32> {
+        entries
+            .iter()
+            .filter_map(|entry| match entry {
+                WalEntry::Timeout(timeout) => Some(timeout.round),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_entries_survive_reopening() {
+        let path = wal_path("reopen");
+        let mut wal = Wal::open(&path).unwrap();
+        wal.write(&timeout(0)).unwrap();
+        wal.write(&timeout(1)).unwrap();
+        drop(wal);
+
+        let wal = Wal::open(&path).unwrap();
+        assert_eq!(rounds(&wal.entries().unwrap()), vec![0, 1]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_end_height_discards_entries_and_torn_tail_is_dropped() {
+        let path = wal_path("end-height");
+        let mut wal = Wal::open(&path).unwrap();
+        wal.write(&timeout(0)).unwrap();
+        wal.end_height(1).unwrap();
+        wal.write(&timeout(2)).unwrap();
+        wal.file.write_all(b"{\"Timeout\":{\"hei").unwrap();
+
+        assert_eq!(rounds(&wal.entries().unwrap()), vec![2]);
+
+        drop(wal);
+        let mut wal = Wal::open(&path).unwrap();
+        wal.write(&timeout(3)).unwrap();
+        assert_eq!(rounds(&wal.entries().unwrap()), vec![2, 3]);
+        fs::remove_file(&path).unwrap();
+    }
+}